@@ -0,0 +1,302 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{AccessLogConfig, Backend};
+use crate::eth_rpc::routing::glob_match;
+
+/// Number of entries that can wait for the writer before new entries are dropped
+const QUEUE_SIZE: usize = 16_384;
+
+/// One line of the access log.
+///
+/// A line has the `id`, `method` and `params` of the call so it can be replayed as a json-rpc
+/// request. Calls that came in the same http request share `httpRequest`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccessLogEntry {
+    /// Unix time in milliseconds
+    pub timestamp_ms: u64,
+    pub http_request: u64,
+    #[serde(default)]
+    pub batch: bool,
+    pub client: Option<IpAddr>,
+    pub id: i64,
+    pub method: String,
+    #[serde(default)]
+    pub params: serde_json::Value,
+    /// Params were left out by the redaction options, the call can't be replayed faithfully
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub redacted: bool,
+    /// None if the call was rejected before reaching a backend
+    pub backend: Option<Backend>,
+    /// Time spent on all calls of this method in the http request, in microseconds
+    pub latency_us: u64,
+    pub status: CallStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<i64>,
+    pub response_bytes: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CallStatus {
+    Ok,
+    Error,
+}
+
+/// Writes access log entries from a background thread so requests never wait for the disk
+pub struct AccessLog {
+    log_params: bool,
+    redact_methods: Vec<String>,
+    next_http_request: AtomicU64,
+    entry_tx: mpsc::SyncSender<AccessLogEntry>,
+}
+
+impl AccessLog {
+    pub fn new(cfg: AccessLogConfig) -> Result<Self> {
+        let writer = RotatingWriter::open(
+            cfg.path,
+            cfg.max_file_size_mb.saturating_mul(1_000_000),
+            cfg.max_files,
+        )
+        .context("open access log")?;
+
+        let (entry_tx, entry_rx) = mpsc::sync_channel(QUEUE_SIZE);
+
+        std::thread::Builder::new()
+            .name("access-log".to_owned())
+            .spawn(move || writer.run(entry_rx))
+            .context("spawn access log writer")?;
+
+        Ok(Self {
+            log_params: cfg.log_params,
+            redact_methods: cfg.redact_methods,
+            next_http_request: AtomicU64::new(0),
+            entry_tx,
+        })
+    }
+
+    /// Sequence number that groups the calls of an http request
+    pub fn next_http_request(&self) -> u64 {
+        self.next_http_request.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Params as they should be written for the method, and whether they were redacted
+    pub fn redact(&self, method: &str, params: &serde_json::Value) -> (serde_json::Value, bool) {
+        let redacted = !self.log_params
+            || self
+                .redact_methods
+                .iter()
+                .any(|pattern| glob_match(pattern, method));
+
+        if redacted {
+            (serde_json::Value::Null, true)
+        } else {
+            (params.clone(), false)
+        }
+    }
+
+    pub fn write(&self, entry: AccessLogEntry) {
+        if let Err(mpsc::TrySendError::Full(_)) = self.entry_tx.try_send(entry) {
+            log::warn!("access log writer is falling behind, dropping entry");
+        }
+    }
+}
+
+/// Unix time in milliseconds
+pub fn timestamp_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis().try_into().unwrap_or(u64::MAX))
+        .unwrap_or_default()
+}
+
+struct RotatingWriter {
+    path: PathBuf,
+    max_file_size: u64,
+    max_files: usize,
+    file: BufWriter<File>,
+    file_size: u64,
+}
+
+impl RotatingWriter {
+    fn open(path: PathBuf, max_file_size: u64, max_files: usize) -> Result<Self> {
+        let (file, file_size) = open_append(&path)?;
+
+        Ok(Self {
+            path,
+            max_file_size,
+            max_files,
+            file,
+            file_size,
+        })
+    }
+
+    fn run(mut self, entry_rx: mpsc::Receiver<AccessLogEntry>) {
+        while let Ok(entry) = entry_rx.recv() {
+            let mut res = self.write(&entry);
+            // write everything that is queued before flushing
+            while let Ok(entry) = entry_rx.try_recv() {
+                res = res.and_then(|_| self.write(&entry));
+            }
+            res = res.and_then(|_| self.file.flush().context("flush access log"));
+
+            if let Err(e) = res {
+                log::error!("failed to write access log: {:?}", e);
+            }
+        }
+    }
+
+    fn write(&mut self, entry: &AccessLogEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry).context("serialize access log entry")?;
+        line.push(b'\n');
+
+        if self.file_size > 0 && self.file_size + line.len() as u64 > self.max_file_size {
+            self.rotate().context("rotate access log")?;
+        }
+
+        self.file.write_all(&line).context("write access log")?;
+        self.file_size += line.len() as u64;
+
+        Ok(())
+    }
+
+    /// Moves `<path>.n` to `<path>.n+1`, and the current file to `<path>.1`
+    fn rotate(&mut self) -> Result<()> {
+        self.file.flush().context("flush")?;
+
+        if self.max_files == 0 {
+            fs::remove_file(&self.path).context("remove file")?;
+        } else {
+            let rotated = |n: usize| {
+                let mut path = self.path.clone().into_os_string();
+                path.push(format!(".{}", n));
+                PathBuf::from(path)
+            };
+
+            let oldest = rotated(self.max_files);
+            if oldest.exists() {
+                fs::remove_file(&oldest).context("remove oldest file")?;
+            }
+            for n in (1..self.max_files).rev() {
+                let from = rotated(n);
+                if from.exists() {
+                    fs::rename(&from, rotated(n + 1)).context("rename rotated file")?;
+                }
+            }
+            fs::rename(&self.path, rotated(1)).context("rename file")?;
+        }
+
+        (self.file, self.file_size) = open_append(&self.path)?;
+
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> Result<(BufWriter<File>, u64)> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("open {}", path.display()))?;
+    let size = file.metadata().context("read file metadata")?.len();
+
+    Ok((BufWriter::new(file), size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: i64) -> AccessLogEntry {
+        AccessLogEntry {
+            timestamp_ms: 1_700_000_000_000,
+            http_request: 7,
+            batch: true,
+            client: Some("127.0.0.1".parse().unwrap()),
+            id,
+            method: "eth_getBlockByNumber".to_owned(),
+            params: serde_json::json!(["0x10", false]),
+            redacted: false,
+            backend: Some(Backend::HyperSync),
+            latency_us: 1500,
+            status: CallStatus::Ok,
+            error_code: None,
+            response_bytes: 1024,
+        }
+    }
+
+    #[test]
+    fn test_entry_format() {
+        let line = serde_json::to_string(&entry(1)).unwrap();
+        assert_eq!(
+            line,
+            r#"{"timestampMs":1700000000000,"httpRequest":7,"batch":true,"client":"127.0.0.1","id":1,"method":"eth_getBlockByNumber","params":["0x10",false],"backend":"hypersync","latencyUs":1500,"status":"ok","responseBytes":1024}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<AccessLogEntry>(&line).unwrap(),
+            entry(1)
+        );
+    }
+
+    #[test]
+    fn test_redact() {
+        let access_log = AccessLog {
+            log_params: true,
+            redact_methods: vec!["eth_sendRawTransaction".to_owned(), "personal_*".to_owned()],
+            next_http_request: AtomicU64::new(0),
+            entry_tx: mpsc::sync_channel(1).0,
+        };
+        let params = serde_json::json!(["0x02f8"]);
+
+        assert_eq!(
+            access_log.redact("eth_sendRawTransaction", &params),
+            (serde_json::Value::Null, true)
+        );
+        assert_eq!(
+            access_log.redact("personal_sign", &params),
+            (serde_json::Value::Null, true)
+        );
+        assert_eq!(
+            access_log.redact("eth_call", &params),
+            (params.clone(), false)
+        );
+    }
+
+    #[test]
+    fn test_rotate() {
+        let dir = std::env::temp_dir().join(format!("access-log-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.jsonl");
+
+        let line_len = serde_json::to_vec(&entry(1)).unwrap().len() as u64 + 1;
+        let mut writer = RotatingWriter::open(path.clone(), line_len * 2, 2).unwrap();
+        for id in 0..7 {
+            writer.write(&entry(id)).unwrap();
+        }
+        writer.file.flush().unwrap();
+
+        let ids = |path: PathBuf| {
+            fs::read_to_string(path)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str::<AccessLogEntry>(line).unwrap().id)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(ids(path.clone()), vec![6]);
+        assert_eq!(ids(dir.join("access.jsonl.1")), vec![4, 5]);
+        assert_eq!(ids(dir.join("access.jsonl.2")), vec![2, 3]);
+        assert!(!dir.join("access.jsonl.3").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,9 +1,69 @@
-use clap::Parser;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use url::Url;
 
 #[derive(Parser, Debug, Clone)]
 pub struct Args {
     #[clap(long, default_value_t = default_config_path())]
     pub config_path: String,
+    /// Runs the proxy if no command is given
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Replays a JSONL request log against rpc endpoints and reports latency per method
+    Replay(ReplayArgs),
+    /// Sends the requests of a JSONL request log to two endpoints and reports the fields whose
+    /// values differ
+    Diff(DiffArgs),
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct ReplayArgs {
+    /// File with a json-rpc request, a batch or an access log entry on each line
+    pub log_path: PathBuf,
+    /// Url the requests are sent to, can be given multiple times to compare endpoints
+    #[clap(long = "endpoint", required = true)]
+    pub endpoints: Vec<Url>,
+    /// Maximum number of http requests in flight
+    #[clap(long, default_value_t = 10)]
+    pub concurrency: usize,
+    /// Maximum number of http requests sent per second
+    #[clap(long)]
+    pub rate: Option<f64>,
+    /// Only replays the first n http requests of the log
+    #[clap(long)]
+    pub limit: Option<usize>,
+    /// Timeout of each http request
+    #[clap(long, default_value_t = 30)]
+    pub timeout_secs: u64,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct DiffArgs {
+    /// File with a json-rpc request, a batch or an access log entry on each line
+    pub log_path: PathBuf,
+    pub left: Url,
+    pub right: Url,
+    /// Glob pattern of fields that aren't compared, e.g. `result.transactions[].yParity`.
+    /// Can be given multiple times.
+    #[clap(long = "ignore-field")]
+    pub ignore_fields: Vec<String>,
+    /// Maximum number of http requests in flight
+    #[clap(long, default_value_t = 10)]
+    pub concurrency: usize,
+    /// Only sends the first n http requests of the log
+    #[clap(long)]
+    pub limit: Option<usize>,
+    /// Timeout of each http request
+    #[clap(long, default_value_t = 30)]
+    pub timeout_secs: u64,
+    /// Prints the report as json
+    #[clap(long)]
+    pub json: bool,
 }
 
 impl Args {
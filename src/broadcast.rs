@@ -0,0 +1,308 @@
+use std::sync::Arc;
+
+use serde::Deserialize;
+use skar_format::{Data, Hash, Hex};
+use tokio::sync::mpsc;
+use tracing::Instrument;
+
+use crate::rpc_client::{
+    self, RawResponse, RpcClient, RpcClientConfig, RpcRequest, RpcRequestImpl, RpcResponse,
+    RpcResponseImpl,
+};
+use crate::verify::{self, rlp};
+
+/// Sends raw transactions to a set of endpoints in parallel.
+///
+/// Every endpoint gets every transaction so it reaches the public mempool and private relays at the
+/// same time. The first endpoint that accepts the transaction answers the request, the others keep
+/// going in the background and only their outcome is logged.
+pub struct Broadcaster {
+    endpoints: Vec<Arc<BroadcastEndpoint>>,
+}
+
+struct BroadcastEndpoint {
+    label: String,
+    client: RpcClient,
+}
+
+enum Outcome {
+    /// Endpoint returned the expected transaction hash
+    Accepted(RawResponse),
+    /// Endpoint answered with a json-rpc error object
+    Rejected(RawResponse),
+    Failed(rpc_client::Error),
+}
+
+#[derive(Deserialize)]
+struct SendRawTransactionResponse {
+    #[serde(default)]
+    result: Option<Hash>,
+    #[serde(default)]
+    error: Option<serde_json::Value>,
+}
+
+impl Broadcaster {
+    pub fn new(config: RpcClientConfig) -> Self {
+        let endpoints = config
+            .endpoints
+            .into_iter()
+            .map(|endpoint| {
+                // urls can contain api keys so only the host is used if there is no label
+                let label = endpoint
+                    .label
+                    .clone()
+                    .or_else(|| endpoint.url.host_str().map(str::to_owned))
+                    .unwrap_or_default();
+
+                Arc::new(BroadcastEndpoint {
+                    label,
+                    client: RpcClient::from_config(RpcClientConfig {
+                        http_req_timeout_millis: config.http_req_timeout_millis,
+                        endpoints: vec![endpoint],
+                    }),
+                })
+            })
+            .collect();
+
+        Self { endpoints }
+    }
+
+    /// Queries eth_chainId from each broadcast endpoint, results are labeled with the endpoint label
+    pub async fn get_chain_ids(&self) -> Vec<(String, rpc_client::Result<u64>)> {
+        let mut results = Vec::with_capacity(self.endpoints.len());
+
+        for endpoint in self.endpoints.iter() {
+            results.extend(
+                endpoint
+                    .client
+                    .get_chain_ids()
+                    .await
+                    .into_iter()
+                    .map(|(_, res)| (endpoint.label.clone(), res)),
+            );
+        }
+
+        results
+    }
+
+    /// Broadcasts the signed transaction and returns the response of the first endpoint that
+    /// accepts it.
+    ///
+    /// If no endpoint accepts it, the first error object returned by an endpoint is returned so the
+    /// client can see why the transaction was rejected (e.g. nonce too low).
+    pub async fn send_raw_transaction(&self, tx: &Data) -> rpc_client::Result<RawResponse> {
+        let tx_hash = transaction_hash(tx.as_ref());
+        let params = serde_json::json!([tx]);
+
+        let (outcome_tx, mut outcome_rx) = mpsc::channel(self.endpoints.len().max(1));
+
+        for endpoint in self.endpoints.iter() {
+            tokio::spawn(
+                endpoint
+                    .clone()
+                    .send(params.clone(), tx_hash.clone(), outcome_tx.clone())
+                    .in_current_span(),
+            );
+        }
+        drop(outcome_tx);
+
+        let mut rejected = None;
+        let mut errs = Vec::new();
+
+        while let Some(outcome) = outcome_rx.recv().await {
+            match outcome {
+                Outcome::Accepted(res) => return Ok(res),
+                Outcome::Rejected(res) => {
+                    rejected.get_or_insert(res);
+                }
+                Outcome::Failed(e) => errs.push(e),
+            }
+        }
+
+        match rejected {
+            Some(res) => Ok(res),
+            None => Err(rpc_client::Error::NoHealthyEndpoints(errs)),
+        }
+    }
+}
+
+impl BroadcastEndpoint {
+    async fn send(
+        self: Arc<Self>,
+        params: serde_json::Value,
+        tx_hash: Hash,
+        outcome_tx: mpsc::Sender<Outcome>,
+    ) {
+        let req = RpcRequest::Single(RpcRequestImpl::Proxy {
+            params,
+            method: "eth_sendRawTransaction".to_owned(),
+        });
+
+        let outcome = match self.client.send_once(req).await {
+            Ok(RpcResponse::Single(RpcResponseImpl::Proxy(res))) => check_response(res, &tx_hash),
+            Ok(_) => Outcome::Failed(rpc_client::Error::InvalidRPCResponse(anyhow::anyhow!(
+                "unexpected response type"
+            ))),
+            Err(e) => Outcome::Failed(e),
+        };
+
+        match &outcome {
+            Outcome::Accepted(_) => log::info!(
+                "broadcast of {} to {} succeeded",
+                tx_hash.encode_hex(),
+                self.label
+            ),
+            Outcome::Rejected(res) => log::warn!(
+                "broadcast of {} to {} was rejected: {}",
+                tx_hash.encode_hex(),
+                self.label,
+                String::from_utf8_lossy(res.body())
+            ),
+            Outcome::Failed(e) => log::warn!(
+                "broadcast of {} to {} failed: {}",
+                tx_hash.encode_hex(),
+                self.label,
+                e
+            ),
+        }
+
+        // the receiver is gone once another endpoint accepted the transaction
+        outcome_tx.send(outcome).await.ok();
+    }
+}
+
+fn check_response(res: RawResponse, tx_hash: &Hash) -> Outcome {
+    let parsed = match serde_json::from_slice::<SendRawTransactionResponse>(res.body()) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            return Outcome::Failed(rpc_client::Error::InvalidRPCResponse(
+                anyhow::Error::new(e).context("parse eth_sendRawTransaction response"),
+            ))
+        }
+    };
+
+    match (parsed.result, parsed.error) {
+        (_, Some(_)) => Outcome::Rejected(res),
+        (Some(hash), None) if &hash == tx_hash => Outcome::Accepted(res),
+        (Some(hash), None) => {
+            Outcome::Failed(rpc_client::Error::InvalidRPCResponse(anyhow::anyhow!(
+                "returned transaction hash {} doesn't match the expected hash {}",
+                hash.encode_hex(),
+                tx_hash.encode_hex()
+            )))
+        }
+        (None, None) => Outcome::Failed(rpc_client::Error::InvalidRPCResponse(anyhow::anyhow!(
+            "response has neither a result nor an error"
+        ))),
+    }
+}
+
+/// Hash of a signed transaction as the endpoints return it.
+///
+/// Blob transactions can be sent in their network form, `0x03 || rlp([tx, blobs, commitments,
+/// proofs])`, and are hashed without the sidecar, i.e. as `0x03 || rlp(tx)`.
+fn transaction_hash(tx: &[u8]) -> Hash {
+    if let Some(inner) = blob_transaction_without_sidecar(tx) {
+        return keccak256(&inner);
+    }
+
+    keccak256(tx)
+}
+
+fn blob_transaction_without_sidecar(tx: &[u8]) -> Option<Vec<u8>> {
+    let (&tx_type, payload) = tx.split_first()?;
+    if tx_type != BLOB_TX_TYPE {
+        return None;
+    }
+
+    let outer = rlp::Header::decode(payload).filter(|header| header.list)?;
+    let items = &payload[outer.header_len..outer.item_len()];
+
+    // the first item of the canonical form is the chain id, in the network form it is the tx list
+    let inner = rlp::Header::decode(items).filter(|header| header.list)?;
+
+    let mut out = Vec::with_capacity(1 + inner.item_len());
+    out.push(BLOB_TX_TYPE);
+    out.extend_from_slice(&items[..inner.item_len()]);
+    Some(out)
+}
+
+const BLOB_TX_TYPE: u8 = 0x03;
+
+fn keccak256(data: &[u8]) -> Hash {
+    Hash::from(verify::keccak256(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+
+    fn raw(body: &'static str) -> RawResponse {
+        rpc_client::raw::parse_object(Bytes::from_static(body.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn test_keccak256() {
+        assert_eq!(
+            keccak256(&[]).encode_hex(),
+            "0xc5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+        );
+    }
+
+    #[test]
+    fn test_blob_transaction_hash() {
+        let tx = rlp::RlpList::new()
+            .u64(1)
+            .u64(0)
+            .bytes(&[0xaa; 20])
+            .finish();
+        let canonical = [&[BLOB_TX_TYPE][..], &tx].concat();
+
+        let network = [
+            &[BLOB_TX_TYPE][..],
+            &rlp::RlpList::new()
+                .raw(&tx)
+                .raw(&rlp::RlpList::new().bytes(&[0xbb; 100]).finish())
+                .raw(&rlp::RlpList::new().bytes(&[0xcc; 48]).finish())
+                .raw(&rlp::RlpList::new().bytes(&[0xdd; 48]).finish())
+                .finish(),
+        ]
+        .concat();
+
+        assert_eq!(transaction_hash(&canonical), keccak256(&canonical));
+        assert_eq!(transaction_hash(&network), keccak256(&canonical));
+
+        // other transaction types are hashed as they are
+        assert_eq!(transaction_hash(&tx), keccak256(&tx));
+    }
+
+    #[test]
+    fn test_check_response() {
+        let tx_hash = keccak256(&[]);
+
+        let accepted = raw(
+            r#"{"jsonrpc":"2.0","id":0,"result":"0xc5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"}"#,
+        );
+        assert!(matches!(
+            check_response(accepted, &tx_hash),
+            Outcome::Accepted(_)
+        ));
+
+        let wrong_hash = raw(
+            r#"{"jsonrpc":"2.0","id":0,"result":"0x0000000000000000000000000000000000000000000000000000000000000000"}"#,
+        );
+        assert!(matches!(
+            check_response(wrong_hash, &tx_hash),
+            Outcome::Failed(_)
+        ));
+
+        let rejected =
+            raw(r#"{"jsonrpc":"2.0","id":0,"error":{"code":-32000,"message":"nonce too low"}}"#);
+        assert!(matches!(
+            check_response(rejected, &tx_hash),
+            Outcome::Rejected(_)
+        ));
+    }
+}
@@ -57,7 +57,6 @@ impl BytesBuilder {
     //     self.list.len()
     // }
 
-    #[cfg(test)]
     pub fn build(&self) -> Bytes {
         use bytes::BytesMut;
 
@@ -1,17 +1,120 @@
 use std::net::SocketAddr;
+use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
 
+use crate::rpc_client::RpcClientConfig;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Config {
     pub eth_rpc: EthRpcConfig,
     pub http_server: HttpServerConfig,
     pub hypersync: skar_client::Config,
+    #[serde(default)]
+    pub routing: RoutingConfig,
+    /// Exports OpenTelemetry spans if set
+    pub tracing: Option<TracingConfig>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TracingConfig {
+    /// Base url of the OTLP/HTTP collector, spans are sent to `<otlp_endpoint>/v1/traces`
+    pub otlp_endpoint: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    /// Fraction of requests that are traced, between 0 and 1
+    #[serde(default = "default_sample_ratio")]
+    pub sample_ratio: f64,
+}
+
+fn default_service_name() -> String {
+    "local-hyperrpc".into()
+}
+
+fn default_sample_ratio() -> f64 {
+    1.0
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct HttpServerConfig {
     pub addr: SocketAddr,
+    /// Methods that are accepted on the default route
+    #[serde(default)]
+    pub methods: MethodFilterConfig,
+    /// Additional routes with their own method lists
+    #[serde(default)]
+    pub routes: Vec<HttpRouteConfig>,
+    /// Maximum number of blocks HyperSync and the upstream endpoints can be behind the chain head
+    /// for /ready to report ready
+    #[serde(default = "default_ready_max_lag_blocks")]
+    pub ready_max_lag_blocks: u64,
+    /// Writes every json-rpc call to a JSONL file if set
+    pub access_log: Option<AccessLogConfig>,
+}
+
+fn default_ready_max_lag_blocks() -> u64 {
+    20
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AccessLogConfig {
+    pub path: PathBuf,
+    /// The file is rotated to `<path>.1` when it reaches this size
+    #[serde(default = "default_access_log_max_file_size_mb")]
+    pub max_file_size_mb: u64,
+    /// Number of rotated files that are kept, older ones are deleted
+    #[serde(default = "default_access_log_max_files")]
+    pub max_files: usize,
+    /// Params aren't written for any call if false
+    #[serde(default = "default_log_params")]
+    pub log_params: bool,
+    /// Glob patterns of methods whose params aren't written
+    #[serde(default)]
+    pub redact_methods: Vec<String>,
+}
+
+fn default_access_log_max_file_size_mb() -> u64 {
+    100
+}
+
+fn default_access_log_max_files() -> usize {
+    10
+}
+
+fn default_log_params() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MethodFilterConfig {
+    /// Glob patterns of the methods that are accepted, all methods are accepted if not set
+    pub allow: Option<Vec<String>>,
+    /// Glob patterns of the methods that are rejected even if they are allowed
+    pub deny: Option<Vec<String>>,
+}
+
+// the lists aren't a flattened `MethodFilterConfig` because serde can't reject unknown fields
+// of flattened structs
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpRouteConfig {
+    /// Path of the route, e.g. "/internal"
+    pub path: String,
+    /// Replaces the allow list of the default route if set
+    pub allow: Option<Vec<String>>,
+    /// Replaces the deny list of the default route if set
+    pub deny: Option<Vec<String>>,
+}
+
+impl HttpRouteConfig {
+    pub fn methods(&self) -> MethodFilterConfig {
+        MethodFilterConfig {
+            allow: self.allow.clone(),
+            deny: self.deny.clone(),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -23,6 +126,10 @@ pub struct EthRpcConfig {
     pub hyperrpc_is_stateful: bool,
     /// Fallback RPC url
     pub fallback_url: Option<String>,
+    /// Fallback RPC endpoints with their limits, takes precedence over fallback_url if set
+    pub fallback: Option<RpcClientConfig>,
+    /// Endpoints that eth_sendRawTransaction is broadcast to, instead of sending it to the fallback
+    pub broadcast: Option<RpcClientConfig>,
     ///  Maximum number of requests in a batch request
     #[serde(default = "default_max_requests_in_batch")]
     pub max_requests_in_batch: usize,
@@ -41,8 +148,220 @@ pub struct EthRpcConfig {
     /// supported json version
     #[serde(default = "default_json_rpc_version")]
     pub json_rpc_version: String,
-    //  temp for chain_id
-    pub rpc_chain_id: u64,
+    /// Chain id of the network, detected from HyperSync if not set
+    pub rpc_chain_id: Option<u64>,
+    /// What to do if an upstream serves a different chain than rpc_chain_id at startup
+    #[serde(default)]
+    pub on_chain_id_mismatch: ChainIdMismatch,
+    /// Compares a sample of the responses served from HyperSync with the fallback if set
+    pub shadow: Option<ShadowConfig>,
+    /// Checks the data served from HyperSync against the block headers of the fallback if set
+    pub verify: Option<VerifyConfig>,
+    /// Caches data of finalized blocks that was fetched from HyperSync if set
+    pub cache: Option<CacheConfig>,
+    /// Fetches the blocks that concurrent http requests ask for together if set
+    pub micro_batch: Option<MicroBatchConfig>,
+    /// Follows the head of HyperSync to detect reorgs if set
+    pub reorg: Option<ReorgConfig>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReorgConfig {
+    /// Time between polls of the HyperSync height
+    #[serde(default = "default_reorg_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+    /// Number of recent block hashes that are kept, reorgs deeper than this aren't detected
+    #[serde(default = "default_reorg_depth")]
+    pub depth: usize,
+    /// Blocks with fewer confirmations than this aren't served from HyperSync if set
+    pub min_confirmations: Option<u64>,
+}
+
+fn default_reorg_poll_interval_ms() -> u64 {
+    1000
+}
+
+fn default_reorg_depth() -> usize {
+    256
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MicroBatchConfig {
+    /// Time single-block lookups are collected for before they are fetched
+    #[serde(default = "default_micro_batch_window_ms")]
+    pub window_ms: u64,
+}
+
+fn default_micro_batch_window_ms() -> u64 {
+    5
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CacheConfig {
+    /// Approximate memory the cached data can take up
+    #[serde(default = "default_cache_memory_size_mb")]
+    pub memory_size_mb: usize,
+    /// Only blocks that are at least this many blocks below the HyperSync height are cached
+    #[serde(default = "default_cache_confirmations")]
+    pub confirmations: u64,
+    /// Logs are cached per selection and segment of this many blocks
+    #[serde(default = "default_cache_log_segment_size")]
+    pub log_segment_size: u64,
+    /// Also keeps the cached data on disk if set
+    pub disk: Option<DiskCacheConfig>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DiskCacheConfig {
+    /// Directory of the cache, the data of each chain is kept in a subdirectory named by its chain id
+    pub path: PathBuf,
+    /// Size limit of the files in the directory of the chain
+    #[serde(default = "default_disk_cache_max_size_mb")]
+    pub max_size_mb: u64,
+}
+
+fn default_disk_cache_max_size_mb() -> u64 {
+    10 * 1024
+}
+
+fn default_cache_memory_size_mb() -> usize {
+    512
+}
+
+fn default_cache_confirmations() -> u64 {
+    64
+}
+
+fn default_cache_log_segment_size() -> u64 {
+    1000
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ShadowConfig {
+    /// Fraction of the responses that are compared, between 0 and 1
+    #[serde(default = "default_shadow_sample_ratio")]
+    pub sample_ratio: f64,
+    /// Glob patterns of the methods that are compared
+    #[serde(default = "default_shadow_methods")]
+    pub methods: Vec<String>,
+    /// Glob patterns of fields that aren't compared, array indices are left out of the field path,
+    /// e.g. `transactions[].yParity`
+    #[serde(default)]
+    pub ignore_fields: Vec<String>,
+}
+
+fn default_shadow_sample_ratio() -> f64 {
+    0.01
+}
+
+fn default_shadow_methods() -> Vec<String> {
+    [
+        "eth_getLogs",
+        "eth_getBlockByNumber",
+        "eth_getBlockReceipts",
+    ]
+    .into_iter()
+    .map(str::to_owned)
+    .collect()
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VerifyConfig {
+    /// Glob patterns of the methods that are verified, only eth_getBlockReceipts,
+    /// eth_getBlockByNumber and eth_getLogs can be verified
+    #[serde(default = "default_verify_methods")]
+    pub methods: Vec<String>,
+    /// What to do with a response that doesn't match the header
+    #[serde(default)]
+    pub on_mismatch: VerifyMismatch,
+    /// Maximum number of block headers fetched to check a single response, logs of later blocks
+    /// aren't checked
+    #[serde(default = "default_verify_max_blocks_per_response")]
+    pub max_blocks_per_response: usize,
+}
+
+fn default_verify_max_blocks_per_response() -> usize {
+    100
+}
+
+fn default_verify_methods() -> Vec<String> {
+    [
+        "eth_getBlockReceipts",
+        "eth_getBlockByNumber",
+        "eth_getLogs",
+    ]
+    .into_iter()
+    .map(str::to_owned)
+    .collect()
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VerifyMismatch {
+    /// Send the request to the fallback instead
+    #[default]
+    #[serde(rename = "fallback")]
+    Fallback,
+    /// Return an internal error
+    #[serde(rename = "reject")]
+    Reject,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChainIdMismatch {
+    /// Refuse to start
+    #[default]
+    #[serde(rename = "refuse")]
+    Refuse,
+    /// Log a warning and start anyway
+    #[serde(rename = "warn")]
+    Warn,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RoutingConfig {
+    /// Glob patterns of methods that are rejected without being sent anywhere
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// Rules are checked in order and the first one that matches the method is used.
+    /// Methods that don't match any rule use the built-in routing.
+    #[serde(default)]
+    pub rules: Vec<RoutingRule>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RoutingRule {
+    /// Glob pattern of method names, `*` matches any sequence of characters and `?` matches a single character
+    pub method: String,
+    /// Backend that handles the method
+    pub backend: Backend,
+    /// Backend to retry on if the primary backend fails
+    pub secondary: Option<Backend>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Backend {
+    /// Serve from HyperSync, only possible for the methods that have a local handler
+    #[serde(rename = "hypersync")]
+    HyperSync,
+    #[serde(rename = "hyperrpc")]
+    HyperRpc,
+    #[serde(rename = "fallback")]
+    Fallback,
+    /// Send to all broadcast endpoints, only possible for eth_sendRawTransaction
+    #[serde(rename = "broadcast")]
+    Broadcast,
+}
+
+impl Backend {
+    /// Name of the backend as it is written in the config
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::HyperSync => "hypersync",
+            Self::HyperRpc => "hyperrpc",
+            Self::Fallback => "fallback",
+            Self::Broadcast => "broadcast",
+        }
+    }
 }
 
 fn default_max_requests_in_batch() -> usize {
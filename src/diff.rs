@@ -0,0 +1,353 @@
+use std::collections::BTreeMap;
+use std::io::BufReader;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use futures::StreamExt;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::args::DiffArgs;
+use crate::json_diff::{self, FieldDiff};
+use crate::replay::{self, ReplayRequest};
+
+/// Mismatches of all calls, grouped by method and field
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Report {
+    calls: u64,
+    mismatches: u64,
+    errors: u64,
+    methods: BTreeMap<String, MethodReport>,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MethodReport {
+    calls: u64,
+    /// Calls whose responses differ
+    mismatches: u64,
+    /// Calls that failed on either endpoint so they couldn't be compared
+    errors: u64,
+    /// Keyed by the field path without array indices
+    fields: BTreeMap<String, FieldReport>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FieldReport {
+    /// Number of calls in which the field differs
+    calls: u64,
+    /// First difference found in the field
+    example: FieldExample,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FieldExample {
+    params: Value,
+    #[serde(flatten)]
+    diff: FieldDiff,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Outcome {
+    Same,
+    Differs(Vec<FieldDiff>),
+    Failed(String),
+}
+
+impl Report {
+    fn add(&mut self, method: &str, params: &Value, outcome: Outcome) {
+        let method_report = self.methods.entry(method.to_owned()).or_default();
+        self.calls += 1;
+        method_report.calls += 1;
+
+        match outcome {
+            Outcome::Same => (),
+            Outcome::Failed(_) => {
+                self.errors += 1;
+                method_report.errors += 1;
+            }
+            Outcome::Differs(diffs) => {
+                self.mismatches += 1;
+                method_report.mismatches += 1;
+
+                let mut fields = BTreeMap::new();
+                for diff in diffs {
+                    fields.entry(diff.field()).or_insert(diff);
+                }
+                for (field, diff) in fields {
+                    method_report
+                        .fields
+                        .entry(field)
+                        .or_insert_with(|| FieldReport {
+                            calls: 0,
+                            example: FieldExample {
+                                params: params.clone(),
+                                diff,
+                            },
+                        })
+                        .calls += 1;
+                }
+            }
+        }
+    }
+
+    fn print(&self, args: &DiffArgs) {
+        println!(
+            "{} vs {}: {} calls, {} differ, {} failed",
+            args.left, args.right, self.calls, self.mismatches, self.errors
+        );
+
+        for (method, report) in self.methods.iter() {
+            println!(
+                "\n{}: {} calls, {} differ, {} failed",
+                method, report.calls, report.mismatches, report.errors
+            );
+            for (field, field_report) in report.fields.iter() {
+                let example = &field_report.example;
+                println!(
+                    "    {} differs in {} calls, e.g. {}: {} vs {} with params {}",
+                    field,
+                    field_report.calls,
+                    example.diff.path,
+                    display_value(&example.diff.left),
+                    display_value(&example.diff.right),
+                    example.params
+                );
+            }
+        }
+    }
+}
+
+fn display_value(value: &Option<Value>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "missing".to_owned(),
+    }
+}
+
+/// Compares the responses of the two endpoints to each call of the request
+fn compare(
+    req: &ReplayRequest,
+    left: std::result::Result<Value, String>,
+    right: std::result::Result<Value, String>,
+    ignore_fields: &[String],
+) -> Vec<Outcome> {
+    let (left, right) = match (left, right) {
+        (Ok(left), Ok(right)) => (left, right),
+        (Err(e), _) | (_, Err(e)) => return vec![Outcome::Failed(e); req.calls.len()],
+    };
+
+    let left = replay::split_responses(req.calls.len(), &left);
+    let right = replay::split_responses(req.calls.len(), &right);
+
+    left.into_iter()
+        .zip(right)
+        .map(|(left, right)| match (left, right) {
+            (Some(left), Some(right)) => {
+                let diffs = json_diff::diff(
+                    &without_envelope(left),
+                    &without_envelope(right),
+                    ignore_fields,
+                );
+                if diffs.is_empty() {
+                    Outcome::Same
+                } else {
+                    Outcome::Differs(diffs)
+                }
+            }
+            _ => Outcome::Failed("missing_response".to_owned()),
+        })
+        .collect()
+}
+
+/// The response object without the id and jsonrpc members
+fn without_envelope(res: &Value) -> Value {
+    let mut res = res.clone();
+    if let Some(obj) = res.as_object_mut() {
+        obj.remove("id");
+        obj.remove("jsonrpc");
+    }
+    res
+}
+
+async fn diff_endpoints(
+    http_client: &reqwest::Client,
+    requests: &[ReplayRequest],
+    args: &DiffArgs,
+) -> Report {
+    let mut outcomes = futures::stream::iter(requests.iter())
+        .map(|req| async move {
+            let (left, right) = futures::join!(
+                replay::send(http_client, &args.left, req),
+                replay::send(http_client, &args.right, req)
+            );
+            (req, compare(req, left, right, &args.ignore_fields))
+        })
+        .buffered(args.concurrency.max(1));
+
+    let mut report = Report::default();
+    while let Some((req, call_outcomes)) = outcomes.next().await {
+        for (call, outcome) in req.calls.iter().zip(call_outcomes) {
+            report.add(&call.method, &call.params, outcome);
+        }
+    }
+
+    report
+}
+
+/// Sends every request of the log to both endpoints and prints the fields that differ.
+///
+/// Fails if any response differs so it can be used as a check in CI.
+pub async fn run(args: DiffArgs) -> Result<()> {
+    let file = std::fs::File::open(&args.log_path)
+        .with_context(|| format!("open {}", args.log_path.display()))?;
+    let (mut requests, skipped) =
+        replay::read_log(BufReader::new(file)).context("read request log")?;
+
+    if let Some(limit) = args.limit {
+        requests.truncate(limit);
+    }
+    if skipped > 0 {
+        println!("skipped {} calls with redacted params", skipped);
+    }
+
+    let http_client = reqwest::Client::builder()
+        .gzip(true)
+        .timeout(Duration::from_secs(args.timeout_secs))
+        .build()
+        .context("build http client")?;
+
+    let report = diff_endpoints(&http_client, &requests, &args).await;
+
+    if args.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).context("serialize report")?
+        );
+    } else {
+        report.print(&args);
+    }
+
+    if report.mismatches > 0 {
+        return Err(anyhow!(
+            "{} of {} calls differ",
+            report.mismatches,
+            report.calls
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::replay::Call;
+
+    /// Stand-in endpoint that answers every request with the given body
+    async fn serve(body: Value) -> url::Url {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = axum::Router::new().route(
+            "/",
+            axum::routing::post(move || {
+                let body = body.clone();
+                async move { axum::Json(body) }
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        format!("http://{}/", addr).parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_diff_endpoints() {
+        let left = serve(json!([
+            {"jsonrpc": "2.0", "id": 0, "result": [{"logIndex": "0x1", "data": "0xAB"}]},
+            {"jsonrpc": "2.0", "id": 1, "result": {"hash": "0x1", "type": "0x2"}},
+        ]))
+        .await;
+        let right = serve(json!([
+            {"jsonrpc": "2.0", "id": 1, "result": {"hash": "0x1"}},
+            {"jsonrpc": "2.0", "id": 0, "result": [{"logIndex": "0x2", "data": "0xab"}]},
+        ]))
+        .await;
+
+        let requests = vec![ReplayRequest {
+            calls: vec![
+                Call {
+                    method: "eth_getLogs".to_owned(),
+                    params: json!([{"fromBlock": "0x1"}]),
+                },
+                Call {
+                    method: "eth_getBlockByNumber".to_owned(),
+                    params: json!(["0x1", false]),
+                },
+            ],
+            batch: true,
+        }];
+        let args = DiffArgs {
+            log_path: "requests.jsonl".into(),
+            left,
+            right,
+            ignore_fields: Vec::new(),
+            concurrency: 1,
+            limit: None,
+            timeout_secs: 5,
+            json: true,
+        };
+
+        let report = diff_endpoints(&reqwest::Client::new(), &requests, &args).await;
+
+        assert_eq!(report.calls, 2);
+        assert_eq!(report.mismatches, 2);
+        assert_eq!(report.errors, 0);
+
+        let logs = &report.methods["eth_getLogs"];
+        let log_index = &logs.fields["result[].logIndex"];
+        assert_eq!(log_index.calls, 1);
+        assert_eq!(log_index.example.diff.path, "result[0].logIndex");
+        assert_eq!(log_index.example.params, json!([{"fromBlock": "0x1"}]));
+        assert_eq!(logs.fields.len(), 1);
+
+        let block = &report.methods["eth_getBlockByNumber"];
+        let tx_type = &block.fields["result.type"];
+        assert_eq!(tx_type.example.diff.left, Some(json!("0x2")));
+        assert_eq!(tx_type.example.diff.right, None);
+    }
+
+    #[test]
+    fn test_compare_failed() {
+        let req = ReplayRequest {
+            calls: vec![Call {
+                method: "eth_chainId".to_owned(),
+                params: json!([]),
+            }],
+            batch: false,
+        };
+
+        assert_eq!(
+            compare(
+                &req,
+                Ok(json!({"id": 0, "result": "0x1"})),
+                Err("timeout".to_owned()),
+                &[]
+            ),
+            vec![Outcome::Failed("timeout".to_owned())]
+        );
+        assert_eq!(
+            compare(
+                &req,
+                Ok(json!({"jsonrpc": "2.0", "id": 0, "result": "0x1"})),
+                Ok(json!({"id": 0, "result": "0x1"})),
+                &[]
+            ),
+            vec![Outcome::Same]
+        );
+    }
+}
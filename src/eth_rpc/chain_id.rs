@@ -0,0 +1,100 @@
+use anyhow::{anyhow, Context, Result};
+
+use crate::broadcast::Broadcaster;
+use crate::config::ChainIdMismatch;
+use crate::query_handler::QueryHandler;
+use crate::rpc_client::{self, GetChainId, RpcClient};
+
+/// Uses the configured chain id if it is set, otherwise reads it from HyperSync and then from the
+/// fallback rpc if one is given.
+///
+/// Also returns the chain id HyperSync serves if it could be read so it can be checked against
+/// the configured one.
+pub async fn detect(
+    query_handler: &QueryHandler,
+    fallback: Option<&RpcClient>,
+    configured: Option<u64>,
+) -> Result<(u64, Option<u64>)> {
+    let hypersync_chain_id = match query_handler.get_chain_id().await {
+        Ok(chain_id) => chain_id,
+        Err(e) => {
+            log::warn!("failed to get chain id from hypersync: {:?}", e);
+            None
+        }
+    };
+
+    if let Some(chain_id) = configured.or(hypersync_chain_id) {
+        return Ok((chain_id, hypersync_chain_id));
+    }
+
+    let fallback =
+        fallback.context("couldn't detect chain id from hypersync, rpc_chain_id has to be set")?;
+
+    let chain_id = fallback
+        .send(GetChainId.into())
+        .await
+        .context("get chain id from fallback rpc")?
+        .try_into_single()
+        .context("unexpected response type")?;
+
+    log::info!("detected chain id {} from the fallback rpc", chain_id);
+
+    Ok((chain_id, None))
+}
+
+/// Checks that all upstreams serve the given chain.
+///
+/// Upstreams that can't be reached are only logged since they might come up later.
+pub async fn check_upstreams(
+    chain_id: u64,
+    hypersync_chain_id: Option<u64>,
+    clients: &[(&str, &RpcClient)],
+    broadcaster: Option<&Broadcaster>,
+    on_mismatch: ChainIdMismatch,
+) -> Result<()> {
+    let mut results: Vec<(String, rpc_client::Result<u64>)> = Vec::new();
+
+    if let Some(hypersync_chain_id) = hypersync_chain_id {
+        results.push(("HyperSync".to_owned(), Ok(hypersync_chain_id)));
+    }
+
+    for (name, client) in clients {
+        for (endpoint, res) in client.get_chain_ids().await {
+            results.push((format!("{} ({})", name, endpoint), res));
+        }
+    }
+
+    if let Some(broadcaster) = broadcaster {
+        for (endpoint, res) in broadcaster.get_chain_ids().await {
+            results.push((format!("broadcast ({})", endpoint), res));
+        }
+    }
+
+    let mut mismatches = Vec::new();
+
+    for (upstream, res) in results {
+        match res {
+            Ok(id) if id == chain_id => log::info!("{} serves chain {}", upstream, id),
+            Ok(id) => mismatches.push(format!("{} serves chain {}", upstream, id)),
+            Err(e) => log::warn!("failed to get chain id of {}: {}", upstream, e),
+        }
+    }
+
+    if mismatches.is_empty() {
+        return Ok(());
+    }
+
+    let msg = format!(
+        "upstreams don't serve chain {}: {}",
+        chain_id,
+        mismatches.join(", ")
+    );
+
+    match on_mismatch {
+        ChainIdMismatch::Refuse => Err(anyhow!(msg)),
+        ChainIdMismatch::Warn => {
+            log::warn!("{}", msg);
+            Ok(())
+        }
+    }
+}
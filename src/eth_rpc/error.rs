@@ -1,6 +1,10 @@
 use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
+use skar_format::BlockNumber;
+
+use crate::query_handler::QueryTimeout;
+use crate::rpc_client;
 
 use super::types::RpcResponse;
 
@@ -8,6 +12,8 @@ use super::types::RpcResponse;
 pub struct RpcErrorCode {
     pub code: i64,
     pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Clone)]
@@ -17,14 +23,65 @@ pub enum RpcError {
     InvalidParams(String),
     JsonRpcVersionNotSupported(String),
     LimitExceeded(String),
+    HyperSyncTimeout(QueryTimeout),
+    UpstreamFailure(Arc<rpc_client::Error>),
+    /// Error object returned by the upstream rpc, it is passed on to the client as is
+    UpstreamError(RpcErrorCode),
+    UnsupportedMethod(String),
+    MethodNotFound(String),
+    ResourceNotFound(String),
+    /// The block is too close to the head to be served from HyperSync
+    Unconfirmed {
+        block_number: u64,
+        min_confirmations: u64,
+    },
+    /// Any of the other errors with a `data` field attached to it
+    WithData(Box<RpcError>, serde_json::Value),
 }
 
 impl From<anyhow::Error> for RpcError {
     fn from(e: anyhow::Error) -> Self {
+        if let Some(timeout) = e.chain().find_map(|e| e.downcast_ref::<QueryTimeout>()) {
+            return Self::HyperSyncTimeout(*timeout);
+        }
+
         Self::InternalError(Arc::new(e))
     }
 }
 
+impl From<rpc_client::Error> for RpcError {
+    fn from(e: rpc_client::Error) -> Self {
+        use rpc_client::Error::*;
+
+        let limited = match &e {
+            EndpointLimitTooLow | RetriesFailed => true,
+            NoHealthyEndpoints(errs) => errs.iter().all(|e| matches!(e, EndpointLimitTooLow)),
+            _ => false,
+        };
+
+        let upstream_err = match &e {
+            RpcErrorResponse(err) => Some(err),
+            NoHealthyEndpoints(errs) => errs.iter().find_map(|e| match e {
+                RpcErrorResponse(err) => Some(err),
+                _ => None,
+            }),
+            _ => None,
+        };
+
+        if limited {
+            Self::LimitExceeded(format!("upstream rpc: {}", e))
+        } else if let Some(err) = upstream_err {
+            Self::UpstreamError(RpcErrorCode {
+                code: err.code,
+                message: err.message.clone(),
+                data: err.data.clone(),
+            })
+        } else {
+            Self::UpstreamFailure(Arc::new(e))
+        }
+    }
+}
+
 impl PartialEq for RpcError {
     fn eq(&self, other: &Self) -> bool {
         use RpcError::*;
@@ -35,12 +92,39 @@ impl PartialEq for RpcError {
             (InvalidParams(a), InvalidParams(b)) => a == b,
             (JsonRpcVersionNotSupported(a), JsonRpcVersionNotSupported(b)) => a == b,
             (LimitExceeded(a), LimitExceeded(b)) => a == b,
+            (HyperSyncTimeout(a), HyperSyncTimeout(b)) => a == b,
+            (UpstreamFailure(a), UpstreamFailure(b)) => a.to_string() == b.to_string(),
+            (UpstreamError(a), UpstreamError(b)) => {
+                a.code == b.code && a.message == b.message && a.data == b.data
+            }
+            (UnsupportedMethod(a), UnsupportedMethod(b)) => a == b,
+            (MethodNotFound(a), MethodNotFound(b)) => a == b,
+            (ResourceNotFound(a), ResourceNotFound(b)) => a == b,
+            (
+                Unconfirmed {
+                    block_number: a,
+                    min_confirmations: a_min,
+                },
+                Unconfirmed {
+                    block_number: b,
+                    min_confirmations: b_min,
+                },
+            ) => a == b && a_min == b_min,
+            (WithData(a, a_data), WithData(b, b_data)) => a == b && a_data == b_data,
             _ => false,
         }
     }
 }
 
 impl RpcError {
+    /// Attaches `data` to the error, it is returned to the client in the `error.data` field
+    pub fn with_data(self, data: serde_json::Value) -> Self {
+        match self {
+            Self::WithData(err, _) => Self::WithData(err, data),
+            err => Self::WithData(Box::new(err), data),
+        }
+    }
+
     pub fn to_response(&self, req_id: &i64) -> RpcResponse {
         RpcResponse {
             id: *req_id,
@@ -49,27 +133,81 @@ impl RpcError {
         }
     }
 
+    /// Returns true if the request might succeed when it is sent to another backend
+    pub fn is_retryable_code(code: i64) -> bool {
+        // internal error, resource not found, resource unavailable, limit exceeded
+        matches!(code, -32603 | -32001 | -32002 | -32005)
+    }
+
     pub fn code(&self) -> RpcErrorCode {
         match self {
             RpcError::ParseError(msg) => RpcErrorCode {
                 code: -32700,
                 message: format!("Invalid JSON: {}", msg),
+                data: None,
             },
             RpcError::InvalidParams(msg) => RpcErrorCode {
                 code: -32602,
                 message: format!("Invalid params: {}", msg),
+                data: None,
             },
             RpcError::InternalError(msg) => RpcErrorCode {
                 code: -32603,
                 message: format!("Internal error: {:?}", msg),
+                data: None,
             },
             RpcError::JsonRpcVersionNotSupported(msg) => RpcErrorCode {
                 code: -32006,
                 message: format!("JSON-RPC version not supported: {}", msg),
+                data: None,
             },
             RpcError::LimitExceeded(msg) => RpcErrorCode {
                 code: -32005,
                 message: format!("Limit exceeded: {}", msg),
+                data: None,
+            },
+            RpcError::HyperSyncTimeout(timeout) => RpcErrorCode {
+                code: -32002,
+                message: format!("Resource unavailable: {}", timeout),
+                data: Some(serde_json::json!({
+                    "nextBlock": BlockNumber::from(timeout.next_block),
+                })),
+            },
+            RpcError::UpstreamFailure(e) => RpcErrorCode {
+                code: -32002,
+                message: format!("Resource unavailable: upstream rpc failed: {}", e),
+                data: None,
+            },
+            RpcError::UpstreamError(err) => err.clone(),
+            RpcError::UnsupportedMethod(method) => RpcErrorCode {
+                code: -32004,
+                message: format!("Method not supported: {}", method),
+                data: None,
+            },
+            RpcError::MethodNotFound(method) => RpcErrorCode {
+                code: -32601,
+                message: format!("Method not found: {}", method),
+                data: None,
+            },
+            RpcError::ResourceNotFound(msg) => RpcErrorCode {
+                code: -32001,
+                message: format!("Resource not found: {}", msg),
+                data: None,
+            },
+            RpcError::Unconfirmed {
+                block_number,
+                min_confirmations,
+            } => RpcErrorCode {
+                code: -32002,
+                message: format!(
+                    "Resource unavailable: block {} has fewer than {} confirmations",
+                    block_number, min_confirmations
+                ),
+                data: None,
+            },
+            RpcError::WithData(err, data) => RpcErrorCode {
+                data: Some(data.clone()),
+                ..err.code()
             },
         }
     }
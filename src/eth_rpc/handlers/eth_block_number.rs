@@ -1,10 +1,18 @@
 use super::*;
 use std::cmp;
 
-pub async fn handle(rpc_handler: Arc<RpcHandler>, reqs: &Vec<RpcRequest>) -> Vec<RpcResponse> {
+pub async fn handle(
+    rpc_handler: Arc<RpcHandler>,
+    reqs: &Vec<RpcRequest>,
+) -> (Vec<RpcResponse>, QueryMetrics) {
     let mut rpc_responses = Vec::new();
+    let mut metrics = QueryMetrics::default();
 
-    let height = match rpc_handler.skar_client.get_height().await {
+    let start = Instant::now();
+    let skar_height = rpc_handler.skar_client.get_height().await;
+    metrics.skar_wait_time += elapsed(&start);
+
+    let height = match skar_height {
         Ok(skar_height) => {
             let rpc_height = rpc_handler.rpc_client.last_block().await;
             Ok(Some(cmp::min(skar_height, rpc_height)))
@@ -18,7 +26,7 @@ pub async fn handle(rpc_handler: Arc<RpcHandler>, reqs: &Vec<RpcRequest>) -> Vec
             for i in reqs {
                 rpc_responses.push(rpc_error.to_response(&i.id));
             }
-            return rpc_responses;
+            return (rpc_responses, metrics);
         }
     };
 
@@ -31,5 +39,5 @@ pub async fn handle(rpc_handler: Arc<RpcHandler>, reqs: &Vec<RpcRequest>) -> Vec
         ))
     }
 
-    rpc_responses
+    (rpc_responses, metrics)
 }
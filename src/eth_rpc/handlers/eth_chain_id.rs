@@ -1,6 +1,9 @@
 use super::*;
 
-pub fn handle(rpc_handler: Arc<RpcHandler>, reqs: &Vec<RpcRequest>) -> Vec<RpcResponse> {
+pub fn handle(
+    rpc_handler: Arc<RpcHandler>,
+    reqs: &Vec<RpcRequest>,
+) -> (Vec<RpcResponse>, QueryMetrics) {
     let mut rpc_responses = Vec::new();
 
     for req in reqs {
@@ -15,5 +18,5 @@ pub fn handle(rpc_handler: Arc<RpcHandler>, reqs: &Vec<RpcRequest>) -> Vec<RpcRe
         ));
     }
 
-    rpc_responses
+    (rpc_responses, QueryMetrics::default())
 }
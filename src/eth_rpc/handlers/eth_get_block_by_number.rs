@@ -1,7 +1,17 @@
+use std::collections::BTreeSet;
+
+use crate::verify;
+
 use super::*;
 
-pub async fn handle(rpc_handler: Arc<RpcHandler>, reqs: &[RpcRequest]) -> Vec<RpcResponse> {
+pub async fn handle(
+    rpc_handler: Arc<RpcHandler>,
+    reqs: &[RpcRequest],
+) -> (Vec<RpcResponse>, QueryMetrics) {
     let mut rpc_responses = Vec::new();
+    let mut metrics = QueryMetrics::default();
+
+    let start = Instant::now();
 
     // parse params
     let mut from_blocks_for_txns: Vec<u64> = Vec::new();
@@ -20,7 +30,9 @@ pub async fn handle(rpc_handler: Arc<RpcHandler>, reqs: &[RpcRequest]) -> Vec<Rp
         let from_block = match resolve_block_number(
             Some(block_number),
             &rpc_handler.skar_client.get_height().await.map(Some),
-        ) {
+        )
+        .and_then(|block| check_confirmations(&rpc_handler, block).map(|_| block))
+        {
             Ok(from_block) => from_block,
             Err(rpc_error) => {
                 rpc_responses.push(rpc_error.to_response(&req.id));
@@ -37,21 +49,13 @@ pub async fn handle(rpc_handler: Arc<RpcHandler>, reqs: &[RpcRequest]) -> Vec<Rp
         req_ids_with_params.push((req.id, from_block, full_txns));
     }
 
-    // optimize query
-    let query_ranges_for_txns =
-        optimize_query_for_single_block_request(from_blocks_for_txns, rpc_handler.max_block_gap);
-    let query_ranges_for_headers =
-        optimize_query_for_single_block_request(from_blocks_for_headers, rpc_handler.max_block_gap);
+    metrics.query_prepare_time += elapsed(&start);
 
     // execute skar query
-    let res_block_txns =
-        execute_query_for_block_txns(rpc_handler.query_handler.clone(), query_ranges_for_txns)
-            .await;
-    let res_block_headers = execute_query_for_block_headers(
-        rpc_handler.query_handler.clone(),
-        query_ranges_for_headers,
-    )
-    .await;
+    let (res_block_txns, res_block_headers) = futures::join!(
+        fetch_block_txns(&rpc_handler, from_blocks_for_txns),
+        fetch_blocks(&rpc_handler, from_blocks_for_headers)
+    );
 
     // if there are any errors, return rpc_responses
     let (block_txns, block_headers) = match (res_block_txns, res_block_headers) {
@@ -60,32 +64,56 @@ pub async fn handle(rpc_handler: Arc<RpcHandler>, reqs: &[RpcRequest]) -> Vec<Rp
                 let rpc_response = rpc_err.to_response(&req_id);
                 rpc_responses.push(rpc_response);
             }
-            return rpc_responses;
+            return (rpc_responses, metrics);
+        }
+        (Ok((block_txns, txns_metrics)), Ok((block_headers, headers_metrics))) => {
+            metrics += txns_metrics;
+            metrics += headers_metrics;
+            (block_txns, block_headers)
         }
-        (Ok(block_txns), Ok(block_headers)) => (block_txns, block_headers),
     };
 
+    let start = Instant::now();
+
+    // consecutive blocks that don't chain are corrupted or from different sides of a reorg
+    let unlinked = verify::unlinked_blocks(block_txns.values().map(|block| &block.header))
+        .into_iter()
+        .chain(verify::unlinked_blocks(
+            block_headers.values().map(|block| &block.header),
+        ))
+        .collect::<BTreeSet<_>>();
+    if !unlinked.is_empty() {
+        log::warn!(
+            "blocks {:?} from hypersync don't chain to the blocks next to them",
+            unlinked
+        );
+        metrics::UNLINKED_BLOCKS.inc_by(unlinked.len() as u64);
+    }
+
     // build responses
     for (req_id, from_block, full_txn) in req_ids_with_params {
-        let rpc_result = if full_txn {
+        let rpc_result = if unlinked.contains(&from_block) {
+            Err(RpcError::InternalError(
+                anyhow!(
+                    "block {} doesn't chain to the blocks next to it",
+                    from_block
+                )
+                .into(),
+            )
+            .code())
+        } else if full_txn {
             match block_txns.get(&from_block) {
                 Some(block) => Ok(RpcResponseData::Block(Some(BlockVariant::Transactions(
                     Box::new(block.clone()),
                 )))),
-                None => Err(RpcError::InternalError(
-                    anyhow!("Block {} not found", from_block).into(),
-                )
-                .code()),
+                None => Err(RpcError::ResourceNotFound(format!("block {}", from_block)).code()),
             }
         } else {
             match block_headers.get(&from_block) {
                 Some(block) => Ok(RpcResponseData::Block(Some(BlockVariant::Headers(
                     Box::new(block.clone()),
                 )))),
-                None => Err(RpcError::InternalError(
-                    anyhow!("Block {} not found", from_block).into(),
-                )
-                .code()),
+                None => Err(RpcError::ResourceNotFound(format!("block {}", from_block)).code()),
             }
         };
 
@@ -96,5 +124,7 @@ pub async fn handle(rpc_handler: Arc<RpcHandler>, reqs: &[RpcRequest]) -> Vec<Rp
         ));
     }
 
-    rpc_responses
+    metrics.response_encode_time += elapsed(&start);
+
+    (rpc_responses, metrics)
 }
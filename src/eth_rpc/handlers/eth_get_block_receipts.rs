@@ -1,8 +1,14 @@
 use super::*;
 
-pub async fn handle(rpc_handler: Arc<RpcHandler>, reqs: &Vec<RpcRequest>) -> Vec<RpcResponse> {
+pub async fn handle(
+    rpc_handler: Arc<RpcHandler>,
+    reqs: &Vec<RpcRequest>,
+) -> (Vec<RpcResponse>, QueryMetrics) {
     let rpc_version = &rpc_handler.rpc_version;
     let mut rpc_responses = Vec::new();
+    let mut metrics = QueryMetrics::default();
+
+    let start = Instant::now();
 
     let mut from_blocks: Vec<u64> = Vec::new();
     let mut req_ids_with_blocks: Vec<(i64, u64)> = Vec::new();
@@ -18,7 +24,9 @@ pub async fn handle(rpc_handler: Arc<RpcHandler>, reqs: &Vec<RpcRequest>) -> Vec
         let from_block = match resolve_block_number(
             Some(block_number),
             &rpc_handler.skar_client.get_height().await.map(Some),
-        ) {
+        )
+        .and_then(|block| check_confirmations(&rpc_handler, block).map(|_| block))
+        {
             Ok(from_block) => from_block,
             Err(rpc_error) => {
                 rpc_responses.push(rpc_error.to_response(&req.id));
@@ -30,24 +38,24 @@ pub async fn handle(rpc_handler: Arc<RpcHandler>, reqs: &Vec<RpcRequest>) -> Vec
         req_ids_with_blocks.push((req.id, from_block));
     }
 
-    // optimize query
-    let query_ranges =
-        optimize_query_for_single_block_request(from_blocks, rpc_handler.max_block_gap);
+    metrics.query_prepare_time += elapsed(&start);
 
     // execute queries
-    let receipts =
-        match execute_query_for_block_receipts(rpc_handler.query_handler.clone(), query_ranges)
-            .await
-        {
-            Ok(receipts) => receipts,
-            Err(rpc_error) => {
-                for (req_id, _) in req_ids_with_blocks {
-                    let response = rpc_error.to_response(&req_id);
-                    rpc_responses.push(response);
-                }
-                return rpc_responses;
+    let receipts = match fetch_block_receipts(&rpc_handler, from_blocks).await {
+        Ok((receipts, query_metrics)) => {
+            metrics += query_metrics;
+            receipts
+        }
+        Err(rpc_error) => {
+            for (req_id, _) in req_ids_with_blocks {
+                let response = rpc_error.to_response(&req_id);
+                rpc_responses.push(response);
             }
-        };
+            return (rpc_responses, metrics);
+        }
+    };
+
+    let start = Instant::now();
 
     // combine inner BTreeMap on blockNumber
     let mut res_receipts_by_block: BTreeMap<u64, Vec<TransactionReceipt>> = BTreeMap::new();
@@ -67,5 +75,7 @@ pub async fn handle(rpc_handler: Arc<RpcHandler>, reqs: &Vec<RpcRequest>) -> Vec
         rpc_responses.push(RpcResponse::new(req_id, rpc_version, rpc_result));
     }
 
-    rpc_responses
+    metrics.response_encode_time += elapsed(&start);
+
+    (rpc_responses, metrics)
 }
@@ -1,7 +1,13 @@
 use super::*;
 
-pub async fn handle(rpc_handler: Arc<RpcHandler>, reqs: &Vec<RpcRequest>) -> Vec<RpcResponse> {
+pub async fn handle(
+    rpc_handler: Arc<RpcHandler>,
+    reqs: &Vec<RpcRequest>,
+) -> (Vec<RpcResponse>, QueryMetrics) {
     let mut rpc_responses = Vec::new();
+    let mut metrics = QueryMetrics::default();
+
+    let start = Instant::now();
 
     // parse params
     let mut block_ranges: Vec<BlockRange> = Vec::new();
@@ -23,6 +29,14 @@ pub async fn handle(rpc_handler: Arc<RpcHandler>, reqs: &Vec<RpcRequest>) -> Vec
             }
         };
 
+        // to_block of the log filter is exclusive
+        if let Err(rpc_error) =
+            check_confirmations(&rpc_handler, log_filter.to_block.saturating_sub(1))
+        {
+            rpc_responses.push(rpc_error.to_response(&req.id));
+            continue;
+        }
+
         // we don't care about filter_id or log_filter.next_poll_block_number
         // we just want this struct for composability
         let mimic = LogFilterDataWithReqId {
@@ -33,8 +47,10 @@ pub async fn handle(rpc_handler: Arc<RpcHandler>, reqs: &Vec<RpcRequest>) -> Vec
         block_ranges.push(BlockRange(log_filter.from_block, log_filter.to_block));
     }
 
-    let (successful_request_info, logs_tree) = concurrent_batch_skar_log_query(
-        rpc_handler.skar_client.clone(),
+    metrics.query_prepare_time += elapsed(&start);
+
+    let (successful_request_info, logs_tree, query_metrics) = concurrent_batch_skar_log_query(
+        rpc_handler.query_handler.clone(),
         rpc_handler.max_logs_returned_per_request,
         rpc_handler.max_get_logs_block_range,
         log_filter_data_with_req_ids_validated,
@@ -43,6 +59,10 @@ pub async fn handle(rpc_handler: Arc<RpcHandler>, reqs: &Vec<RpcRequest>) -> Vec
     )
     .await;
 
+    metrics += query_metrics;
+
+    let start = Instant::now();
+
     for log_filter_data_with_req_id in successful_request_info {
         // let log_filter = log_filter_with_req_id.log_filter;
         let log_selection = log_filter_data_with_req_id.log_filter.selection;
@@ -62,5 +82,7 @@ pub async fn handle(rpc_handler: Arc<RpcHandler>, reqs: &Vec<RpcRequest>) -> Vec
         );
     }
 
-    rpc_responses
+    metrics.response_encode_time += elapsed(&start);
+
+    (rpc_responses, metrics)
 }
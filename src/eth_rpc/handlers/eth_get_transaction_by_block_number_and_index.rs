@@ -2,8 +2,14 @@ use crate::eth_rpc::types::RpcResult;
 
 use super::*;
 
-pub async fn handle(rpc_handler: Arc<RpcHandler>, reqs: &Vec<RpcRequest>) -> Vec<RpcResponse> {
+pub async fn handle(
+    rpc_handler: Arc<RpcHandler>,
+    reqs: &Vec<RpcRequest>,
+) -> (Vec<RpcResponse>, QueryMetrics) {
     let mut rpc_responses: Vec<RpcResponse> = Vec::new();
+    let mut metrics = QueryMetrics::default();
+
+    let start = Instant::now();
 
     // parse params
     let mut from_blocks: Vec<u64> = Vec::new();
@@ -22,7 +28,9 @@ pub async fn handle(rpc_handler: Arc<RpcHandler>, reqs: &Vec<RpcRequest>) -> Vec
         let from_block = match resolve_block_number(
             Some(block_number),
             &rpc_handler.skar_client.get_height().await.map(Some),
-        ) {
+        )
+        .and_then(|block| check_confirmations(&rpc_handler, block).map(|_| block))
+        {
             Ok(from_block) => from_block,
             Err(rpc_error) => {
                 rpc_responses.push(rpc_error.to_response(&req.id));
@@ -34,22 +42,24 @@ pub async fn handle(rpc_handler: Arc<RpcHandler>, reqs: &Vec<RpcRequest>) -> Vec
         req_ids_with_block_num_and_tx_idx.push((req.id, from_block, tx_index.into()));
     }
 
-    // optimize query
-    let query_ranges =
-        optimize_query_for_single_block_request(from_blocks, rpc_handler.max_block_gap);
+    metrics.query_prepare_time += elapsed(&start);
 
     // execute query
-    let res_blocks =
-        match execute_query_for_block_txns(rpc_handler.query_handler.clone(), query_ranges).await {
-            Ok(res) => res,
-            Err(rpc_err) => {
-                for (req_id, _, _) in req_ids_with_block_num_and_tx_idx {
-                    let response = rpc_err.to_response(&req_id);
-                    rpc_responses.push(response);
-                }
-                return rpc_responses;
+    let res_blocks = match fetch_block_txns(&rpc_handler, from_blocks).await {
+        Ok((res, query_metrics)) => {
+            metrics += query_metrics;
+            res
+        }
+        Err(rpc_err) => {
+            for (req_id, _, _) in req_ids_with_block_num_and_tx_idx {
+                let response = rpc_err.to_response(&req_id);
+                rpc_responses.push(response);
             }
-        };
+            return (rpc_responses, metrics);
+        }
+    };
+
+    let start = Instant::now();
 
     for (req_id, from_block, tx_index) in req_ids_with_block_num_and_tx_idx {
         let rpc_result = extract_rpc_result(&res_blocks, from_block, tx_index);
@@ -61,7 +71,9 @@ pub async fn handle(rpc_handler: Arc<RpcHandler>, reqs: &Vec<RpcRequest>) -> Vec
         ));
     }
 
-    rpc_responses
+    metrics.response_encode_time += elapsed(&start);
+
+    (rpc_responses, metrics)
 }
 
 fn extract_rpc_result(
@@ -72,9 +84,7 @@ fn extract_rpc_result(
     let block = match res_blocks.get(&from_block) {
         Some(block) => block,
         None => {
-            return Err(
-                RpcError::InternalError(anyhow!("Block {} not found", from_block).into()).code(),
-            );
+            return Err(RpcError::ResourceNotFound(format!("block {}", from_block)).code());
         }
     };
 
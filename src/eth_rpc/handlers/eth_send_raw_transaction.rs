@@ -0,0 +1,45 @@
+use skar_format::Data;
+
+use super::*;
+
+pub async fn handle(
+    rpc_handler: Arc<RpcHandler>,
+    reqs: &[RpcRequest],
+) -> (Vec<RpcResponse>, QueryMetrics) {
+    let broadcaster = match &rpc_handler.broadcaster {
+        Some(broadcaster) => broadcaster,
+        None => {
+            let rpc_error =
+                RpcError::InternalError(anyhow!("no broadcast endpoints are configured").into());
+            let responses = reqs
+                .iter()
+                .map(|req| rpc_error.to_response(&req.id))
+                .collect();
+            return (responses, QueryMetrics::default());
+        }
+    };
+
+    let futs = reqs.iter().map(|req| async move {
+        let (tx,) = match serde_json::from_value::<(Data,)>(req.params.clone()) {
+            Ok(params) => params,
+            Err(e) => return RpcError::InvalidParams(e.to_string()).to_response(&req.id),
+        };
+
+        let result = match broadcaster.send_raw_transaction(&tx).await {
+            Ok(res) => Ok(RpcResponseData::Proxy(res)),
+            Err(e) => Err(RpcError::from(e).code()),
+        };
+
+        RpcResponse::new(req.id, &req.jsonrpc, result)
+    });
+
+    let start = Instant::now();
+    let responses = futures::future::join_all(futs).await;
+
+    let metrics = QueryMetrics {
+        proxy_time: elapsed(&start),
+        ..Default::default()
+    };
+
+    (responses, metrics)
+}
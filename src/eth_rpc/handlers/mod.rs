@@ -1,25 +1,27 @@
-use crate::query_handler::from_arrow::batch_to_logs;
+use crate::config::Backend;
+use crate::metrics;
 use crate::query_handler::QueryHandler;
-use crate::rpc_client::{self, RpcClient, RpcRequestImpl};
+use crate::rpc_client::{self, RpcClient, RpcRequestImpl, RpcResponseImpl};
+use crate::single_flight::{Flight, SingleFlight};
+use crate::types::{elapsed, QueryMetrics};
 use crate::BlockRange;
 
 use super::error::RpcError;
 use super::types::{
     BlockVariant, FilterParams, LogFilterDataWithReqId, RpcBlockNumber, RpcRequest, RpcResponse,
-    RpcResponseData,
+    RpcResponseData, RpcResult,
 };
 use super::RpcHandler;
 use anyhow::Result;
 
 use arrayvec::ArrayVec;
 use futures::{Future, StreamExt};
-use skar_net_types::FieldSelection;
 use skar_net_types::LogSelection;
-use skar_net_types::Query;
 use std::collections::BTreeMap;
 use std::sync::Arc;
+use std::time::Instant;
 
-use anyhow::{anyhow, Context, Error};
+use anyhow::anyhow;
 
 use skar_format::{Block, BlockNumber, Hash, Log, LogArgument, Transaction, TransactionReceipt};
 
@@ -29,42 +31,162 @@ pub mod eth_get_block_by_number;
 pub mod eth_get_block_receipts;
 pub mod eth_get_logs;
 pub mod eth_get_transaction_by_block_number_and_index;
+pub mod eth_send_raw_transaction;
 
 // various helper and shared methods
 
+/// Upstream calls in flight, keyed by backend and `coalesce_key`
+pub type ProxyCalls = SingleFlight<(Backend, String), RpcResult>;
+
+/// Proxies the requests like `handle_method_not_found`. Calls that read state at a fixed block
+/// share a single upstream call with identical calls that are in flight, from the same batch or
+/// from other http requests.
+pub async fn handle_proxied(
+    rpc_client: &RpcClient,
+    in_flight: &ProxyCalls,
+    backend: Backend,
+    reqs: &[RpcRequest],
+) -> (Vec<RpcResponse>, QueryMetrics) {
+    let mut to_send = Vec::with_capacity(reqs.len());
+    let mut leaders = Vec::with_capacity(reqs.len());
+    let mut followers = Vec::new();
+
+    for req in reqs {
+        match coalesce_key(req).map(|key| in_flight.join((backend, key))) {
+            Some(Flight::Follower(follower)) => followers.push((req, follower)),
+            leader => {
+                leaders.push(match leader {
+                    Some(Flight::Leader(leader)) => Some(leader),
+                    _ => None,
+                });
+                to_send.push(req.clone());
+            }
+        }
+    }
+
+    let (mut rpc_responses, mut metrics) = handle_method_not_found(rpc_client, &to_send).await;
+
+    // leaders are completed before waiting for any follower so calls that follow each other can't
+    // deadlock
+    for (leader, res) in leaders.into_iter().zip(rpc_responses.iter()) {
+        if let Some(leader) = leader {
+            leader.complete(res.result.clone());
+        }
+    }
+
+    let mut cancelled = Vec::new();
+    for (req, follower) in followers {
+        match follower.wait().await {
+            Some(result) => rpc_responses.push(RpcResponse::new(req.id, &req.jsonrpc, result)),
+            None => cancelled.push(req.clone()),
+        }
+    }
+
+    if !cancelled.is_empty() {
+        let (responses, retry_metrics) = handle_method_not_found(rpc_client, &cancelled).await;
+        rpc_responses.extend(responses);
+        metrics += retry_metrics;
+    }
+
+    (rpc_responses, metrics)
+}
+
+/// Key of a call whose result only depends on its params, i.e. one that reads state at a block
+/// given by number or hash. Returns None for other calls, e.g. the ones at `latest`.
+fn coalesce_key(req: &RpcRequest) -> Option<String> {
+    let block_param = match req.method.as_str() {
+        "eth_call"
+        | "eth_estimateGas"
+        | "eth_getBalance"
+        | "eth_getCode"
+        | "eth_getTransactionCount" => 1,
+        "eth_getStorageAt" | "eth_getProof" => 2,
+        _ => return None,
+    };
+
+    let fixed_block = match req.params.get(block_param)? {
+        serde_json::Value::String(block) => block.starts_with("0x"),
+        // block given as an object like in EIP-1898
+        serde_json::Value::Object(block) => {
+            block.contains_key("blockHash")
+                || block
+                    .get("blockNumber")
+                    .and_then(serde_json::Value::as_str)
+                    .is_some_and(|number| number.starts_with("0x"))
+        }
+        _ => false,
+    };
+
+    fixed_block.then(|| format!("{}{}", req.method, req.params))
+}
+
+/// Sends the requests to upstream as they are, responses are in the order of the requests
 pub async fn handle_method_not_found(
     rpc_client: &RpcClient,
     reqs_validated: &[RpcRequest],
-) -> Vec<RpcResponse> {
-    let mut resps = Vec::new();
+) -> (Vec<RpcResponse>, QueryMetrics) {
+    let mut rpc_responses = Vec::with_capacity(reqs_validated.len());
+    let mut metrics = QueryMetrics::default();
+
+    let proxy_reqs = reqs_validated
+        .iter()
+        .map(|req| RpcRequestImpl::Proxy {
+            params: req.params.clone(),
+            method: req.method.clone(),
+        })
+        .collect();
 
-    for chunk in reqs_validated.chunks(50) {
-        let chunk = chunk
-            .iter()
-            .map(|req| RpcRequestImpl::Proxy {
-                params: req.params.clone(),
-                method: req.method.clone(),
-            })
-            .collect::<Vec<_>>();
+    let start = Instant::now();
+    let chunks = rpc_client.send_chunked(proxy_reqs).await;
+    metrics.proxy_time += elapsed(&start);
+
+    for (range, res) in chunks {
+        let chunk = &reqs_validated[range];
+
+        let resps = match res {
+            Ok(rpc_client::RpcResponse::Batch(resps)) => resps,
+            Ok(rpc_client::RpcResponse::Single(_)) => {
+                let rpc_error = RpcError::InternalError(
+                    anyhow!("upstream returned a single response to a batch request").into(),
+                );
+                rpc_responses.extend(chunk.iter().map(|req| rpc_error.to_response(&req.id)));
+                continue;
+            }
+            Err(e) => {
+                let rpc_error = RpcError::from(e);
+                rpc_responses.extend(chunk.iter().map(|req| rpc_error.to_response(&req.id)));
+                continue;
+            }
+        };
 
-        let req = rpc_client::RpcRequest::Batch(chunk);
+        for (res, req) in resps.into_iter().zip(chunk.iter()) {
+            let result = match res {
+                RpcResponseImpl::Proxy(res) => Ok(RpcResponseData::Proxy(res)),
+                RpcResponseImpl::Missing => {
+                    Err(RpcError::from(rpc_client::Error::MissingResponse).code())
+                }
+                _ => Err(RpcError::InternalError(
+                    anyhow!("unexpected response type from upstream").into(),
+                )
+                .code()),
+            };
 
-        // TODO: actually handle error
-        let r = rpc_client.send(req).await.unwrap();
+            rpc_responses.push(RpcResponse::new(req.id, &req.jsonrpc, result));
+        }
+    }
 
-        let r: Vec<serde_json::Value> = r.try_into().unwrap();
+    (rpc_responses, metrics)
+}
 
-        resps.extend_from_slice(&r);
-    }
+pub fn handle_unsupported_method(reqs: &[RpcRequest]) -> Vec<RpcResponse> {
+    reqs.iter()
+        .map(|req| RpcError::UnsupportedMethod(req.method.clone()).to_response(&req.id))
+        .collect()
+}
 
-    resps
-        .into_iter()
-        .zip(reqs_validated.iter())
-        .map(|(res, req)| RpcResponse {
-            id: req.id,
-            jsonrpc: req.jsonrpc.clone(),
-            result: Ok(RpcResponseData::Proxy(res)),
-        })
+pub fn handle_denied_method(reqs: &[RpcRequest]) -> Vec<RpcResponse> {
+    reqs.iter()
+        .map(|req| RpcError::MethodNotFound(req.method.clone()).to_response(&req.id))
         .collect()
 }
 
@@ -129,68 +251,18 @@ fn trim_log_tree_into_response(
     rpc_responses.push(rpc_response);
 }
 
-async fn single_skar_log_query(
-    client: skar_client::Client,
-    max_logs_per_request: usize,
-    log_selection: LogSelection,
-    block_range: BlockRange,
-) -> anyhow::Result<Vec<Log>> {
-    let log_field_selection = FieldSelection {
-        log: skar_schema::log()
-            .fields
-            .iter()
-            .map(|field| field.name.clone())
-            .collect(),
-        ..Default::default()
-    };
-
-    let query = Query {
-        from_block: block_range.0,
-        // +1 since skar query is exclusive
-        to_block: Some(block_range.1 + 1),
-        logs: vec![log_selection],
-        field_selection: log_field_selection,
-        max_num_logs: Some(max_logs_per_request),
-        ..Default::default()
-    };
-
-    let query_res = client
-        .send::<skar_client::ArrowIpc>(&query)
-        .await
-        .context("send skar query")?;
-
-    let mut num_logs_returned = 0;
-    let mut logs_res: Vec<Log> = Vec::new();
-    for arrow_batch in query_res.data.logs {
-        for log in batch_to_logs(&arrow_batch).context("arrow data to logs")? {
-            if num_logs_returned >= max_logs_per_request {
-                return Err(anyhow!(format!(
-                    "More than {} logs returned",
-                    max_logs_per_request
-                )));
-            }
-            logs_res.push(log);
-            num_logs_returned += 1;
-        }
-    }
-
-    // make sure on the final query result that the next_block is the `to_block` that I initially passed in
-    // otherwise the skar query timed out
-    if query_res.next_block < block_range.1 && query_res.next_block != 0 {
-        return Err(anyhow!("Query timed out"));
-    }
-
-    Ok(logs_res)
-}
-
 async fn concurrent_batch_skar_log_query(
-    client: skar_client::Client,
+    query_handler: QueryHandler,
     max_logs_per_request: usize,
     max_get_logs_block_range: u64,
     requested_log_data: Vec<LogFilterDataWithReqId>,
     requested_block_ranges: Vec<BlockRange>,
     rpc_responses: &mut Vec<RpcResponse>,
-) -> (Vec<LogFilterDataWithReqId>, BTreeMap<u64, Vec<Log>>) {
+) -> (
+    Vec<LogFilterDataWithReqId>,
+    BTreeMap<u64, Vec<Log>>,
+    QueryMetrics,
+) {
     let mut futures = Vec::new();
 
     let mut valid_requested_log_data: Vec<LogFilterDataWithReqId> = Vec::new();
@@ -200,16 +272,35 @@ async fn concurrent_batch_skar_log_query(
                 "Requested block range is greater than {}",
                 max_get_logs_block_range
             ))
+            .with_data(serde_json::json!({
+                "maxBlockRange": BlockNumber::from(max_get_logs_block_range),
+            }))
             .to_response(&request_data.req_id);
             rpc_responses.push(rpc_response);
         } else {
             let log_selection = request_data.log_filter.selection.clone();
-            let future = single_skar_log_query(
-                client.clone(),
-                max_logs_per_request,
-                log_selection,
-                block_range,
-            );
+            let query_handler = query_handler.clone();
+            let future = async move {
+                // only the parts of the range that aren't in cached segments are queried
+                let (mut logs, uncovered) = query_handler
+                    .get_cached_logs(&log_selection, block_range)
+                    .await;
+                let mut metrics = QueryMetrics::default();
+
+                for uncovered in uncovered {
+                    let (fetched, query_metrics) = query_handler
+                        .get_uncovered_logs(log_selection.clone(), uncovered, max_logs_per_request)
+                        .await?;
+                    metrics += query_metrics;
+                    logs.extend(fetched);
+                }
+
+                if logs.len() > max_logs_per_request {
+                    return Err(anyhow!("More than {} logs returned", max_logs_per_request));
+                }
+
+                Ok((logs, metrics))
+            };
             valid_requested_log_data.push(request_data.clone());
             futures.push(future);
         }
@@ -217,10 +308,12 @@ async fn concurrent_batch_skar_log_query(
 
     let mut successful_requested_log_data = Vec::new();
     let mut logs_tree: BTreeMap<u64, Vec<Log>> = BTreeMap::new();
+    let mut metrics = QueryMetrics::default();
     let logs_res = join_buffered(futures.into_iter(), CONCURRENCY).await;
     for (query_res, requested_data) in logs_res.into_iter().zip(valid_requested_log_data.iter()) {
         match query_res {
-            Ok(logs) => {
+            Ok((logs, query_metrics)) => {
+                metrics += query_metrics;
                 // add logs to tree
                 for log in logs {
                     logs_tree
@@ -236,14 +329,13 @@ async fn concurrent_batch_skar_log_query(
                         RpcError::LimitExceeded(e.to_string()).to_response(&requested_data.req_id),
                     )
                 } else {
-                    rpc_responses
-                        .push(RpcError::InternalError(e.into()).to_response(&requested_data.req_id))
+                    rpc_responses.push(RpcError::from(e).to_response(&requested_data.req_id))
                 }
             }
         }
     }
 
-    (successful_requested_log_data, logs_tree)
+    (successful_requested_log_data, logs_tree, metrics)
 }
 
 pub fn resolve_block_number(
@@ -273,6 +365,14 @@ pub fn resolve_block_number(
     }
 }
 
+/// Fails for blocks that are too close to the head of HyperSync, no-op without reorg tracking
+fn check_confirmations(rpc_handler: &RpcHandler, block_number: u64) -> Result<(), RpcError> {
+    match &rpc_handler.reorg_tracker {
+        Some(tracker) => tracker.check_confirmations(block_number),
+        None => Ok(()),
+    }
+}
+
 fn resolve_latest_block(archive_height: &anyhow::Result<Option<u64>>) -> Result<u64, RpcError> {
     match archive_height {
         Ok(Some(block_number)) => Ok(*block_number),
@@ -317,10 +417,71 @@ fn optimize_query_for_single_block_request(
     query_ranges
 }
 
+/// Fetches the blocks with the gap merging planner. With micro-batching, the blocks that concurrent
+/// requests ask for during the aggregation window are planned and fetched together.
+async fn fetch_blocks(
+    rpc_handler: &RpcHandler,
+    blocks: Vec<u64>,
+) -> Result<(BTreeMap<u64, Block<Hash>>, QueryMetrics), RpcError> {
+    let query_handler = rpc_handler.query_handler.clone();
+    let max_block_gap = rpc_handler.max_block_gap;
+    let fetch = move |blocks| {
+        execute_query_for_block_headers(
+            query_handler.clone(),
+            optimize_query_for_single_block_request(blocks, max_block_gap),
+        )
+    };
+
+    match &rpc_handler.micro_batches {
+        Some(batches) if !blocks.is_empty() => batches.blocks.fetch(blocks, fetch).await,
+        _ => fetch(blocks).await,
+    }
+}
+
+/// Same as `fetch_blocks` for blocks with their transactions
+async fn fetch_block_txns(
+    rpc_handler: &RpcHandler,
+    blocks: Vec<u64>,
+) -> Result<(BTreeMap<u64, Block<Transaction>>, QueryMetrics), RpcError> {
+    let query_handler = rpc_handler.query_handler.clone();
+    let max_block_gap = rpc_handler.max_block_gap;
+    let fetch = move |blocks| {
+        execute_query_for_block_txns(
+            query_handler.clone(),
+            optimize_query_for_single_block_request(blocks, max_block_gap),
+        )
+    };
+
+    match &rpc_handler.micro_batches {
+        Some(batches) if !blocks.is_empty() => batches.blocks_with_txs.fetch(blocks, fetch).await,
+        _ => fetch(blocks).await,
+    }
+}
+
+/// Same as `fetch_blocks` for the receipts of the blocks
+async fn fetch_block_receipts(
+    rpc_handler: &RpcHandler,
+    blocks: Vec<u64>,
+) -> Result<(BTreeMap<(u64, u64), TransactionReceipt>, QueryMetrics), RpcError> {
+    let query_handler = rpc_handler.query_handler.clone();
+    let max_block_gap = rpc_handler.max_block_gap;
+    let fetch = move |blocks| {
+        execute_query_for_block_receipts(
+            query_handler.clone(),
+            optimize_query_for_single_block_request(blocks, max_block_gap),
+        )
+    };
+
+    match &rpc_handler.micro_batches {
+        Some(batches) if !blocks.is_empty() => batches.receipts.fetch(blocks, fetch).await,
+        _ => fetch(blocks).await,
+    }
+}
+
 async fn execute_query_for_block_receipts(
     handler: QueryHandler,
     query_ranges: Vec<BlockRange>,
-) -> Result<BTreeMap<(u64, u64), TransactionReceipt>, RpcError> {
+) -> Result<(BTreeMap<(u64, u64), TransactionReceipt>, QueryMetrics), RpcError> {
     let mut futures = Vec::new();
 
     for block_range in query_ranges {
@@ -331,21 +492,23 @@ async fn execute_query_for_block_receipts(
 
     let resp = try_join_buffered(futures.into_iter(), CONCURRENCY)
         .await
-        .map_err(|e: Error| RpcError::InternalError(e.into()))?;
+        .map_err(RpcError::from)?;
 
     let mut resps = BTreeMap::new();
+    let mut metrics = QueryMetrics::default();
 
-    for res in resp {
+    for (res, query_metrics) in resp {
         resps.extend(res);
+        metrics += query_metrics;
     }
 
-    Ok(resps)
+    Ok((resps, metrics))
 }
 
 async fn execute_query_for_block_txns(
     handler: QueryHandler,
     query_ranges: Vec<BlockRange>,
-) -> Result<BTreeMap<u64, Block<Transaction>>, RpcError> {
+) -> Result<(BTreeMap<u64, Block<Transaction>>, QueryMetrics), RpcError> {
     let mut futures = Vec::new();
     for block_range in query_ranges {
         let handler = handler.clone();
@@ -357,21 +520,23 @@ async fn execute_query_for_block_txns(
 
     let resp = try_join_buffered(futures.into_iter(), CONCURRENCY)
         .await
-        .map_err(|e| RpcError::InternalError(e.into()))?;
+        .map_err(RpcError::from)?;
 
     let mut resps = BTreeMap::new();
+    let mut metrics = QueryMetrics::default();
 
-    for res in resp {
+    for (res, query_metrics) in resp {
         resps.extend(res);
+        metrics += query_metrics;
     }
 
-    Ok(resps)
+    Ok((resps, metrics))
 }
 
 async fn execute_query_for_block_headers(
     handler: QueryHandler,
     query_ranges: Vec<BlockRange>,
-) -> Result<BTreeMap<u64, Block<Hash>>, RpcError> {
+) -> Result<(BTreeMap<u64, Block<Hash>>, QueryMetrics), RpcError> {
     let mut futures = Vec::new();
     for block_range in query_ranges {
         let handler = handler.clone();
@@ -383,15 +548,17 @@ async fn execute_query_for_block_headers(
 
     let resp = try_join_buffered(futures.into_iter(), CONCURRENCY)
         .await
-        .map_err(|e| RpcError::InternalError(e.into()))?;
+        .map_err(RpcError::from)?;
 
     let mut resps = BTreeMap::new();
+    let mut metrics = QueryMetrics::default();
 
-    for res in resp {
+    for (res, query_metrics) in resp {
         resps.extend(res);
+        metrics += query_metrics;
     }
 
-    Ok(resps)
+    Ok((resps, metrics))
 }
 
 const CONCURRENCY: usize = 4;
@@ -428,3 +595,40 @@ where
 
     Ok(res)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn req(method: &str, params: serde_json::Value) -> RpcRequest {
+        RpcRequest {
+            method: method.to_owned(),
+            params,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_coalesce_key() {
+        let call = serde_json::json!({"to": "0x1111111111111111111111111111111111111111"});
+
+        assert!(coalesce_key(&req("eth_call", serde_json::json!([call, "0x10"]))).is_some());
+        assert!(coalesce_key(&req(
+            "eth_call",
+            serde_json::json!([call, {"blockHash": "0x01"}])
+        ))
+        .is_some());
+        assert!(coalesce_key(&req("eth_call", serde_json::json!([call, "latest"]))).is_none());
+        assert!(coalesce_key(&req("eth_call", serde_json::json!([call]))).is_none());
+        assert!(coalesce_key(&req(
+            "eth_getStorageAt",
+            serde_json::json!(["0x11", "0x0", "0x10"])
+        ))
+        .is_some());
+        assert!(coalesce_key(&req(
+            "eth_sendRawTransaction",
+            serde_json::json!(["0x10", "0x10"])
+        ))
+        .is_none());
+    }
+}
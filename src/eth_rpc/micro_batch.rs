@@ -0,0 +1,249 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::channel::oneshot;
+use futures::future::{FutureExt, Shared};
+use skar_format::{Block, Hash, Transaction, TransactionReceipt};
+
+use crate::config::MicroBatchConfig;
+use crate::metrics;
+use crate::types::QueryMetrics;
+
+use super::error::RpcError;
+
+/// Batches of the single-block lookups of the HyperSync handlers
+pub struct MicroBatches {
+    pub blocks: MicroBatch<u64, Block<Hash>>,
+    pub blocks_with_txs: MicroBatch<u64, Block<Transaction>>,
+    /// Receipts keyed by block number and transaction index
+    pub receipts: MicroBatch<(u64, u64), TransactionReceipt>,
+}
+
+impl MicroBatches {
+    pub fn new(cfg: MicroBatchConfig) -> Self {
+        let window = Duration::from_millis(cfg.window_ms);
+
+        Self {
+            blocks: MicroBatch::new("blocks", window),
+            blocks_with_txs: MicroBatch::new("blocks_with_txs", window),
+            receipts: MicroBatch::new("receipts", window),
+        }
+    }
+}
+
+/// Keys of the fetched data that start with the block number
+pub trait BlockKey: Ord + Clone {
+    fn block_number(&self) -> u64;
+}
+
+impl BlockKey for u64 {
+    fn block_number(&self) -> u64 {
+        *self
+    }
+}
+
+impl BlockKey for (u64, u64) {
+    fn block_number(&self) -> u64 {
+        self.0
+    }
+}
+
+type Fetched<K, T> = Result<(BTreeMap<K, T>, QueryMetrics), RpcError>;
+
+type SharedFetched<K, T> = Result<(Arc<BTreeMap<K, T>>, QueryMetrics), RpcError>;
+
+/// Collects the blocks that concurrent http requests ask for during a short window, so they are
+/// planned and fetched together instead of one query per http request.
+pub struct MicroBatch<K, T> {
+    kind: &'static str,
+    window: Duration,
+    pending: Mutex<Option<Pending<K, T>>>,
+}
+
+struct Pending<K, T> {
+    blocks: Vec<u64>,
+    num_requests: usize,
+    result: Shared<oneshot::Receiver<SharedFetched<K, T>>>,
+}
+
+impl<K: BlockKey, T: Clone> MicroBatch<K, T> {
+    pub fn new(kind: &'static str, window: Duration) -> Self {
+        Self {
+            kind,
+            window,
+            pending: Mutex::new(None),
+        }
+    }
+
+    /// Adds the blocks to the batch that is collecting blocks, or starts a new batch.
+    ///
+    /// The caller that starts a batch waits for the window to pass and fetches all blocks of the
+    /// batch with `fetch`. Returns the data of the blocks of the caller only. The other callers get
+    /// empty metrics since the fetch is counted once, by the caller that made it.
+    ///
+    /// If the fetch of a batch with more than one request fails, every caller fetches its own
+    /// blocks again, so the blocks of one request can't fail the others.
+    pub async fn fetch<Fut>(
+        &self,
+        blocks: Vec<u64>,
+        fetch: impl Fn(Vec<u64>) -> Fut,
+    ) -> Fetched<K, T>
+    where
+        Fut: Future<Output = Fetched<K, T>>,
+    {
+        let (tx, result) = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.as_mut() {
+                Some(pending) => {
+                    pending.blocks.extend_from_slice(&blocks);
+                    pending.num_requests += 1;
+                    (None, pending.result.clone())
+                }
+                None => {
+                    let (tx, rx) = oneshot::channel();
+                    let result = rx.shared();
+                    *pending = Some(Pending {
+                        blocks: blocks.clone(),
+                        num_requests: 1,
+                        result: result.clone(),
+                    });
+                    (Some(tx), result)
+                }
+            }
+        };
+
+        let tx = match tx {
+            Some(tx) => tx,
+            None => {
+                return match result.await {
+                    Ok(Ok((data, _))) => Ok((select(&data, &blocks), QueryMetrics::default())),
+                    // the batch failed or the caller that started it went away before fetching it
+                    Ok(Err(_)) | Err(_) => fetch(blocks).await,
+                };
+            }
+        };
+
+        // takes the batch back out if this caller is dropped while it is collecting
+        let guard = Guard {
+            batch: self,
+            result: result.clone(),
+        };
+        tokio::time::sleep(self.window).await;
+        let batch = guard.take();
+
+        metrics::MICRO_BATCH_REQUESTS
+            .with_label_values(&[self.kind])
+            .observe(batch.num_requests as f64);
+
+        let res = fetch(batch.blocks)
+            .await
+            .map(|(data, metrics)| (Arc::new(data), metrics));
+        let _ = tx.send(res.clone());
+
+        match res {
+            Ok((data, metrics)) => Ok((select(&data, &blocks), metrics)),
+            // the blocks of another request might have failed the batch
+            Err(_) if batch.num_requests > 1 => fetch(blocks).await,
+            Err(e) => Err(e),
+        }
+    }
+}
+
+struct Guard<'a, K, T> {
+    batch: &'a MicroBatch<K, T>,
+    result: Shared<oneshot::Receiver<SharedFetched<K, T>>>,
+}
+
+impl<K, T> Guard<'_, K, T> {
+    /// Closes the batch so callers that come after this start a new one
+    fn take(self) -> Pending<K, T> {
+        self.batch.pending.lock().unwrap().take().unwrap()
+    }
+}
+
+impl<K, T> Drop for Guard<'_, K, T> {
+    fn drop(&mut self) {
+        let mut pending = self.batch.pending.lock().unwrap();
+        if pending
+            .as_ref()
+            .is_some_and(|pending| pending.result.ptr_eq(&self.result))
+        {
+            *pending = None;
+        }
+    }
+}
+
+/// Entries of the blocks out of all fetched data
+fn select<K: BlockKey, T: Clone>(data: &BTreeMap<K, T>, blocks: &[u64]) -> BTreeMap<K, T> {
+    let blocks = blocks.iter().collect::<BTreeSet<_>>();
+
+    data.iter()
+        .filter(|(key, _)| blocks.contains(&key.block_number()))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_micro_batch() {
+        let batch = MicroBatch::<u64, u64>::new("test", Duration::from_millis(10));
+        let fetches = &AtomicUsize::new(0);
+        let fetch = |blocks: Vec<u64>| async move {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            let metrics = QueryMetrics {
+                rows_fetched: blocks.len() as u64,
+                ..Default::default()
+            };
+            Ok((
+                blocks
+                    .into_iter()
+                    .map(|block| (block, block * 10))
+                    .collect(),
+                metrics,
+            ))
+        };
+
+        let (a, b) = tokio::join!(batch.fetch(vec![1, 2], fetch), batch.fetch(vec![3], fetch));
+        let (a, b) = (a.unwrap(), b.unwrap());
+        assert_eq!(a.0, BTreeMap::from([(1, 10), (2, 20)]));
+        assert_eq!(b.0, BTreeMap::from([(3, 30)]));
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        // the fetch is counted once
+        assert_eq!((a.1.rows_fetched, b.1), (3, QueryMetrics::default()));
+
+        // the next batch starts after the previous one is closed
+        let c = batch.fetch(vec![3], fetch).await;
+        assert_eq!(c.unwrap().0, BTreeMap::from([(3, 30)]));
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+
+        // a block that fails the batch only fails the request that asked for it, the others fetch
+        // their own blocks again
+        let failures = &AtomicUsize::new(0);
+        let fetch_or_fail = |blocks: Vec<u64>| async move {
+            if blocks.contains(&4) {
+                failures.fetch_add(1, Ordering::SeqCst);
+                Err(RpcError::InvalidParams("failed".to_owned()))
+            } else {
+                fetch(blocks).await
+            }
+        };
+        let (a, b) = tokio::join!(
+            batch.fetch(vec![1], fetch_or_fail),
+            batch.fetch(vec![4], fetch_or_fail)
+        );
+        assert_eq!(a.unwrap().0, BTreeMap::from([(1, 10)]));
+        assert!(b.is_err());
+        assert_eq!(failures.load(Ordering::SeqCst), 2);
+
+        // a batch of a single request isn't fetched again
+        assert!(batch.fetch(vec![4], fetch_or_fail).await.is_err());
+        assert_eq!(failures.load(Ordering::SeqCst), 3);
+    }
+}
@@ -1,12 +1,25 @@
+use std::collections::HashSet;
 use std::sync::Arc;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 
-use crate::config::EthRpcConfig;
+use crate::broadcast::Broadcaster;
+use crate::config::{Backend, EthRpcConfig, RoutingConfig, VerifyMismatch};
+use crate::metrics;
+use crate::query_handler::cache::Cache;
 use crate::query_handler::QueryHandler;
 use crate::rpc_client::RpcClient;
+use crate::single_flight::SingleFlight;
+use crate::types::QueryMetrics;
 
+use self::error::RpcError;
+use self::handlers::ProxyCalls;
+use self::micro_batch::MicroBatches;
+use self::reorg::ReorgTracker;
+use self::routing::{Route, Router};
+use self::shadow::Shadow;
 use self::types::{RpcRequest, RpcResponse};
+use self::verifier::Verifier;
 
 use skar_client::Client as SkarClient;
 
@@ -18,12 +31,30 @@ pub mod handlers;
 
 pub mod error;
 
+pub mod routing;
+
+mod chain_id;
+
+mod shadow;
+
+mod verifier;
+
+mod micro_batch;
+
+mod reorg;
+
 pub struct RpcHandler {
     pub skar_client: SkarClient,
     pub query_handler: QueryHandler,
     pub rpc_client: RpcClient,
     pub hyperrpc_client: RpcClient,
-    pub hyperrpc_is_stateful: bool,
+    pub broadcaster: Option<Broadcaster>,
+    pub router: Router,
+    pub shadow: Option<Shadow>,
+    pub verifier: Option<Verifier>,
+    pub micro_batches: Option<MicroBatches>,
+    pub reorg_tracker: Option<Arc<ReorgTracker>>,
+    pub proxy_calls: ProxyCalls,
     pub rpc_version: String,
     pub chain_id: u64,
     pub max_block_gap: u64,
@@ -34,33 +65,88 @@ pub struct RpcHandler {
 }
 
 impl RpcHandler {
-    pub fn new(skar_client: SkarClient, rpc_cfg: EthRpcConfig) -> Result<Self> {
-        let (fallback_name, fallback_url) = if let Some(fallback_url) = rpc_cfg.fallback_url {
-            ("FallbackRPC".to_owned(), fallback_url)
-        } else {
-            let mesc_cfg = mesc::get_endpoint_by_network(rpc_cfg.rpc_chain_id, None)
-                .context("load mesc config")?
-                .context("endpoint for this chain not found")?;
-
-            (mesc_cfg.name, mesc_cfg.url)
+    pub async fn new(
+        skar_client: SkarClient,
+        rpc_cfg: EthRpcConfig,
+        routing_cfg: RoutingConfig,
+    ) -> Result<Self> {
+        // the fallback from mesc can only be looked up once the chain id is known
+        let configured_fallback = match (rpc_cfg.fallback, rpc_cfg.fallback_url) {
+            (Some(fallback), _) => Some(RpcClient::from_config(fallback)),
+            (None, Some(fallback_url)) => Some(
+                RpcClient::new("FallbackRPC".to_owned(), fallback_url)
+                    .context("create rpc client")?,
+            ),
+            (None, None) => None,
+        };
+
+        let (chain_id, hypersync_chain_id) = chain_id::detect(
+            &QueryHandler::new(skar_client.clone(), None),
+            configured_fallback.as_ref(),
+            rpc_cfg.rpc_chain_id,
+        )
+        .await
+        .context("detect chain id")?;
+
+        // the disk cache is kept per chain so the chain id has to be known first
+        let cache = match rpc_cfg.cache {
+            Some(cfg) => Some(Arc::new(Cache::new(cfg, chain_id).context("create cache")?)),
+            None => None,
         };
+        let query_handler = QueryHandler::new(skar_client.clone(), cache.clone());
 
-        let rpc_client =
-            RpcClient::new(fallback_name, fallback_url).context("create rpc client")?;
+        // follows the head without the cache so replaced blocks are seen
+        let reorg_tracker = rpc_cfg.reorg.map(|cfg| {
+            ReorgTracker::start(cfg, QueryHandler::new(skar_client.clone(), None), cache)
+        });
+
+        let rpc_client = match configured_fallback {
+            Some(rpc_client) => rpc_client,
+            None => {
+                let mesc_cfg = mesc::get_endpoint_by_network(chain_id, None)
+                    .context("load mesc config")?
+                    .context("endpoint for this chain not found")?;
+
+                RpcClient::new(mesc_cfg.name, mesc_cfg.url).context("create rpc client")?
+            }
+        };
 
         let hyperrpc_client = RpcClient::new("HyperRPC".to_owned(), rpc_cfg.hyperrpc_url)
             .context("create hyperrpc client")?;
 
-        let query_handler = QueryHandler::new(skar_client.clone());
+        let broadcaster = rpc_cfg.broadcast.map(Broadcaster::new);
+
+        chain_id::check_upstreams(
+            chain_id,
+            hypersync_chain_id,
+            &[("HyperRPC", &hyperrpc_client), ("fallback", &rpc_client)],
+            broadcaster.as_ref(),
+            rpc_cfg.on_chain_id_mismatch,
+        )
+        .await
+        .context("check chain id of upstreams")?;
+
+        let router = Router::new(
+            routing_cfg,
+            rpc_cfg.hyperrpc_is_stateful,
+            broadcaster.is_some(),
+        )
+        .context("create router")?;
 
         Ok(RpcHandler {
             skar_client,
             query_handler,
             rpc_client,
             hyperrpc_client,
-            hyperrpc_is_stateful: rpc_cfg.hyperrpc_is_stateful,
+            broadcaster,
+            router,
+            shadow: rpc_cfg.shadow.map(Shadow::new),
+            verifier: rpc_cfg.verify.map(Verifier::new),
+            micro_batches: rpc_cfg.micro_batch.map(MicroBatches::new),
+            reorg_tracker,
+            proxy_calls: SingleFlight::new("upstream"),
             rpc_version: rpc_cfg.json_rpc_version,
-            chain_id: rpc_cfg.rpc_chain_id,
+            chain_id,
             max_block_gap: rpc_cfg.max_block_gap,
             max_get_logs_block_range: rpc_cfg.max_get_logs_block_range,
             max_logs_returned_per_request: rpc_cfg.max_logs_returned_per_request,
@@ -69,37 +155,223 @@ impl RpcHandler {
         })
     }
 
+    /// Executes the requests of a method, each response comes with the backend that served it or
+    /// None if the router rejected the method
+    #[tracing::instrument(skip_all, fields(method = %method, num_reqs = reqs.len()))]
     pub async fn execute_rpc_method(
         self: Arc<Self>,
         method: &str,
         reqs: &Vec<RpcRequest>,
-    ) -> Vec<RpcResponse> {
+    ) -> (Vec<(RpcResponse, Option<Backend>)>, QueryMetrics) {
         log::trace!("handling {} reqs of type {}", reqs.len(), method);
 
-        match method {
-            "eth_newFilter"
-            | "eth_getFilterLogs"
-            | "eth_getFilterChanges"
-            | "eth_uninstallFilter"
-                if self.hyperrpc_is_stateful =>
-            {
-                handlers::handle_method_not_found(&self.hyperrpc_client, reqs).await
+        let rejected = |responses: Vec<RpcResponse>| {
+            let responses = responses.into_iter().map(|res| (res, None)).collect();
+            (responses, QueryMetrics::default())
+        };
+
+        let (primary, secondary) = match self.router.route(method) {
+            Route::Backend { primary, secondary } => (primary, secondary),
+            Route::Denied => return rejected(handlers::handle_denied_method(reqs)),
+            Route::Unsupported => return rejected(handlers::handle_unsupported_method(reqs)),
+        };
+
+        let (mut responses, mut metrics) =
+            self.clone().execute_and_check(primary, method, reqs).await;
+
+        let secondary = match secondary {
+            Some(secondary) => secondary,
+            None => return (responses, metrics),
+        };
+
+        let failed_ids = responses
+            .iter()
+            .filter(|(res, _)| matches!(&res.result, Err(e) if RpcError::is_retryable_code(e.code)))
+            .map(|(res, _)| res.id)
+            .collect::<HashSet<_>>();
+
+        if failed_ids.is_empty() {
+            return (responses, metrics);
+        }
+
+        log::debug!(
+            "retrying {} reqs of type {} on {:?}",
+            failed_ids.len(),
+            method,
+            secondary
+        );
+
+        let retry_reqs = reqs
+            .iter()
+            .filter(|req| failed_ids.contains(&req.id))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let (retried, retry_metrics) = self.execute_and_check(secondary, method, &retry_reqs).await;
+
+        responses.retain(|(res, _)| !failed_ids.contains(&res.id));
+        responses.extend(retried);
+        metrics += retry_metrics;
+
+        (responses, metrics)
+    }
+
+    /// Executes the requests on the backend, responses served from HyperSync are compared in the
+    /// background and checked against the fallback block headers
+    async fn execute_and_check(
+        self: Arc<Self>,
+        backend: Backend,
+        method: &str,
+        reqs: &Vec<RpcRequest>,
+    ) -> (Vec<(RpcResponse, Option<Backend>)>, QueryMetrics) {
+        let (responses, mut metrics) = self.clone().execute_on_backend(backend, method, reqs).await;
+
+        if backend != Backend::HyperSync {
+            return (served_by(responses, backend), metrics);
+        }
+
+        self.clone().spawn_shadow(method, reqs, &responses);
+
+        let verifier = match &self.verifier {
+            Some(verifier) if verifier.verifies(method) => verifier,
+            _ => return (served_by(responses, backend), metrics),
+        };
+
+        let mismatched_ids = verifier
+            .mismatched(
+                &self.rpc_client,
+                method,
+                self.router.metric_label(method),
+                &responses,
+            )
+            .await;
+        if mismatched_ids.is_empty() {
+            return (served_by(responses, backend), metrics);
+        }
+
+        let (mismatched, responses): (Vec<_>, Vec<_>) = responses
+            .into_iter()
+            .partition(|res| mismatched_ids.contains(&res.id));
+        let mut responses = served_by(responses, backend);
+
+        match verifier.on_mismatch {
+            VerifyMismatch::Reject => {
+                let rpc_error = RpcError::InternalError(
+                    anyhow!("data from hypersync doesn't match the block header").into(),
+                );
+                responses.extend(
+                    mismatched
+                        .iter()
+                        .map(|res| (rpc_error.to_response(&res.id), Some(backend))),
+                );
             }
-            "eth_getTransactionByBlockHashAndIndex"
-            | "eth_getTransactionByHash"
-            | "eth_getBlockByHash"
-            | "eth_getTransactionReceipt" => {
-                handlers::handle_method_not_found(&self.hyperrpc_client, reqs).await
+            VerifyMismatch::Fallback => {
+                log::debug!(
+                    "sending {} reqs of type {} that failed verification to the fallback",
+                    mismatched.len(),
+                    method
+                );
+
+                let fallback_reqs = reqs
+                    .iter()
+                    .filter(|req| mismatched_ids.contains(&req.id))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let (fallback_responses, fallback_metrics) = self
+                    .clone()
+                    .execute_on_backend(Backend::Fallback, method, &fallback_reqs)
+                    .await;
+
+                responses.extend(served_by(fallback_responses, Backend::Fallback));
+                metrics += fallback_metrics;
             }
-            "eth_getBlockByNumber" => handlers::eth_get_block_by_number::handle(self, reqs).await,
-            "eth_getTransactionByBlockNumberAndIndex" => {
-                handlers::eth_get_transaction_by_block_number_and_index::handle(self, reqs).await
+        }
+
+        (responses, metrics)
+    }
+
+    /// Compares a sample of the responses with the fallback in the background
+    fn spawn_shadow(self: Arc<Self>, method: &str, reqs: &[RpcRequest], responses: &[RpcResponse]) {
+        let shadow = match &self.shadow {
+            Some(shadow) => shadow,
+            None => return,
+        };
+
+        let sampled = shadow.sample(method, reqs, responses.iter());
+        if sampled.is_empty() {
+            return;
+        }
+
+        let method = method.to_owned();
+        tokio::spawn(async move {
+            if let Some(shadow) = &self.shadow {
+                shadow.compare(self.clone(), method, sampled).await;
+            }
+        });
+    }
+
+    #[tracing::instrument(skip_all, fields(backend = backend.as_str()))]
+    async fn execute_on_backend(
+        self: Arc<Self>,
+        backend: Backend,
+        method: &str,
+        reqs: &Vec<RpcRequest>,
+    ) -> (Vec<RpcResponse>, QueryMetrics) {
+        let method_label = self.router.metric_label(method);
+        let labels = [method_label, backend.as_str()];
+        let _timer = metrics::RPC_REQUEST_DURATION
+            .with_label_values(&labels)
+            .start_timer();
+        metrics::RPC_REQUESTS
+            .with_label_values(&labels)
+            .inc_by(reqs.len() as u64);
+
+        let (responses, query_metrics) = match backend {
+            Backend::HyperRpc => {
+                handlers::handle_proxied(&self.hyperrpc_client, &self.proxy_calls, backend, reqs)
+                    .await
+            }
+            Backend::Fallback => {
+                handlers::handle_proxied(&self.rpc_client, &self.proxy_calls, backend, reqs).await
+            }
+            Backend::Broadcast => handlers::eth_send_raw_transaction::handle(self, reqs).await,
+            Backend::HyperSync => match method {
+                "eth_getBlockByNumber" => {
+                    handlers::eth_get_block_by_number::handle(self, reqs).await
+                }
+                "eth_getTransactionByBlockNumberAndIndex" => {
+                    handlers::eth_get_transaction_by_block_number_and_index::handle(self, reqs)
+                        .await
+                }
+                "eth_getBlockReceipts" => {
+                    handlers::eth_get_block_receipts::handle(self, reqs).await
+                }
+                "eth_getLogs" => handlers::eth_get_logs::handle(self, reqs).await,
+                "eth_blockNumber" => handlers::eth_block_number::handle(self, reqs).await,
+                "eth_chainId" => handlers::eth_chain_id::handle(self, reqs),
+                // the router only sends methods with a local handler to hypersync
+                _ => (
+                    handlers::handle_unsupported_method(reqs),
+                    QueryMetrics::default(),
+                ),
+            },
+        };
+
+        for res in responses.iter() {
+            if let Err(e) = &res.result {
+                metrics::RPC_ERRORS
+                    .with_label_values(&[method_label, backend.as_str(), &e.code.to_string()])
+                    .inc();
             }
-            "eth_getBlockReceipts" => handlers::eth_get_block_receipts::handle(self, reqs).await,
-            "eth_getLogs" => handlers::eth_get_logs::handle(self, reqs).await,
-            "eth_blockNumber" => handlers::eth_block_number::handle(self, reqs).await,
-            "eth_chainId" => handlers::eth_chain_id::handle(self, reqs),
-            _ => handlers::handle_method_not_found(&self.rpc_client, reqs).await,
         }
+
+        (responses, query_metrics)
     }
 }
+
+fn served_by(responses: Vec<RpcResponse>, backend: Backend) -> Vec<(RpcResponse, Option<Backend>)> {
+    responses
+        .into_iter()
+        .map(|res| (res, Some(backend)))
+        .collect()
+}
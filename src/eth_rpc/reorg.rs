@@ -0,0 +1,275 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use skar_format::{BlockHeader, Hash};
+
+use crate::config::ReorgConfig;
+use crate::metrics;
+use crate::query_handler::cache::Cache;
+use crate::query_handler::QueryHandler;
+use crate::BlockRange;
+
+use super::error::RpcError;
+
+/// Follows the blocks at the head of HyperSync to detect reorgs.
+///
+/// The hashes of the most recent blocks are kept in a ring buffer that is extended by polling the
+/// HyperSync height. A new block whose parent hash isn't the hash of the block before it means
+/// blocks were replaced, the recent blocks are fetched again to find the first replaced block and
+/// cached data from that block on is dropped.
+pub struct ReorgTracker {
+    min_confirmations: Option<u64>,
+    state: Mutex<State>,
+}
+
+struct State {
+    recent: RecentBlocks,
+    /// Last polled HyperSync height
+    head: Option<u64>,
+}
+
+impl ReorgTracker {
+    /// Starts polling HyperSync in the background.
+    ///
+    /// `query_handler` shouldn't have a cache, the blocks have to come from HyperSync.
+    pub fn start(
+        cfg: ReorgConfig,
+        query_handler: QueryHandler,
+        cache: Option<Arc<Cache>>,
+    ) -> Arc<Self> {
+        let tracker = Arc::new(Self {
+            min_confirmations: cfg.min_confirmations,
+            state: Mutex::new(State {
+                recent: RecentBlocks::new(cfg.depth.max(1)),
+                head: None,
+            }),
+        });
+
+        let poll_interval = Duration::from_millis(cfg.poll_interval_ms);
+        let weak = Arc::downgrade(&tracker);
+        tokio::spawn(async move {
+            // stops when the rpc handler is dropped
+            while let Some(tracker) = weak.upgrade() {
+                if let Err(e) = tracker.poll(&query_handler, cache.as_deref()).await {
+                    log::warn!("failed to poll hypersync for reorgs: {:?}", e);
+                }
+                drop(tracker);
+                tokio::time::sleep(poll_interval).await;
+            }
+        });
+
+        tracker
+    }
+
+    /// Fails if the block has fewer confirmations than `min_confirmations`, so it is served by a
+    /// secondary backend instead
+    pub fn check_confirmations(&self, block_number: u64) -> Result<(), RpcError> {
+        let min_confirmations = match self.min_confirmations {
+            Some(min_confirmations) => min_confirmations,
+            None => return Ok(()),
+        };
+
+        let head = self.state.lock().unwrap().head;
+        match head {
+            Some(head) if block_number.saturating_add(min_confirmations) <= head => Ok(()),
+            _ => Err(RpcError::Unconfirmed {
+                block_number,
+                min_confirmations,
+            }),
+        }
+    }
+
+    async fn poll(&self, query_handler: &QueryHandler, cache: Option<&Cache>) -> Result<()> {
+        let height = query_handler.get_height().await.context("get height")?;
+
+        let (last, first, depth) = {
+            let mut state = self.state.lock().unwrap();
+            state.head = Some(height);
+            (
+                state.recent.last(),
+                state.recent.first(),
+                state.recent.capacity as u64,
+            )
+        };
+
+        let oldest = height.saturating_sub(depth - 1);
+        let fork_point = match last {
+            // HyperSync rolled back, some of the recent blocks might be gone
+            Some(last) if last > height => {
+                let window = fetch(query_handler, first.unwrap_or(oldest).max(oldest), height)
+                    .await
+                    .context("fetch recent blocks")?;
+                self.state.lock().unwrap().recent.replace(window)
+            }
+            Some(last) if last == height => None,
+            _ => {
+                let from = last.map(|last| last + 1).unwrap_or(oldest).max(oldest);
+                let new = fetch(query_handler, from, height)
+                    .await
+                    .context("fetch new blocks")?;
+
+                if self
+                    .state
+                    .lock()
+                    .unwrap()
+                    .recent
+                    .extend(new.iter().cloned())
+                {
+                    None
+                } else {
+                    let window = fetch(query_handler, first.unwrap_or(oldest).max(oldest), height)
+                        .await
+                        .context("fetch recent blocks")?;
+                    self.state.lock().unwrap().recent.replace(window)
+                }
+            }
+        };
+
+        if let Some(fork_point) = fork_point {
+            log::warn!(
+                "reorg detected, blocks from {} to {} were replaced",
+                fork_point,
+                last.unwrap_or(fork_point)
+            );
+            metrics::REORGS.inc();
+            if let Some(cache) = cache {
+                cache.invalidate_from(fork_point);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Fetches the headers of the inclusive block range
+async fn fetch(query_handler: &QueryHandler, from: u64, to: u64) -> Result<Vec<RecentBlock>> {
+    if from > to {
+        return Ok(Vec::new());
+    }
+
+    let (blocks, _) = query_handler.get_blocks(BlockRange(from, to + 1)).await?;
+
+    Ok(blocks
+        .values()
+        .map(|block| RecentBlock::from(&block.header))
+        .collect())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct RecentBlock {
+    number: u64,
+    hash: Hash,
+    parent_hash: Hash,
+}
+
+impl From<&BlockHeader> for RecentBlock {
+    fn from(header: &BlockHeader) -> Self {
+        Self {
+            number: header.number.into(),
+            hash: header.hash.clone(),
+            parent_hash: header.parent_hash.clone(),
+        }
+    }
+}
+
+/// Ring buffer of the most recent blocks, ordered by number
+struct RecentBlocks {
+    blocks: VecDeque<RecentBlock>,
+    capacity: usize,
+}
+
+impl RecentBlocks {
+    fn new(capacity: usize) -> Self {
+        Self {
+            blocks: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    fn first(&self) -> Option<u64> {
+        self.blocks.front().map(|block| block.number)
+    }
+
+    fn last(&self) -> Option<u64> {
+        self.blocks.back().map(|block| block.number)
+    }
+
+    /// Appends the blocks that come after the last block. Returns false without changing anything
+    /// if they don't chain to the last block.
+    fn extend(&mut self, blocks: impl IntoIterator<Item = RecentBlock>) -> bool {
+        let blocks = blocks.into_iter().collect::<Vec<_>>();
+
+        let mut prev = self.blocks.back();
+        for block in blocks.iter() {
+            if let Some(prev) = prev.filter(|prev| prev.number + 1 == block.number) {
+                if block.parent_hash != prev.hash {
+                    return false;
+                }
+            }
+            prev = Some(block);
+        }
+
+        for block in blocks {
+            if self.blocks.len() == self.capacity {
+                self.blocks.pop_front();
+            }
+            self.blocks.push_back(block);
+        }
+
+        true
+    }
+
+    /// Replaces the blocks with a fresh copy of the same range and returns the number of the first
+    /// block that was replaced or is gone, if any
+    fn replace(&mut self, blocks: Vec<RecentBlock>) -> Option<u64> {
+        let fork_point = self
+            .blocks
+            .iter()
+            .find(|old| {
+                !blocks
+                    .iter()
+                    .any(|new| new.number == old.number && new.hash == old.hash)
+            })
+            .map(|old| old.number);
+
+        self.blocks.clear();
+        self.extend(blocks);
+
+        fork_point
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(number: u64, hash: u8, parent_hash: u8) -> RecentBlock {
+        RecentBlock {
+            number,
+            hash: Hash::from([hash; 32]),
+            parent_hash: Hash::from([parent_hash; 32]),
+        }
+    }
+
+    #[test]
+    fn test_recent_blocks() {
+        let mut recent = RecentBlocks::new(3);
+        assert!(recent.extend([block(1, 1, 0), block(2, 2, 1)]));
+        assert!(recent.extend([block(3, 3, 2), block(4, 4, 3)]));
+        assert_eq!((recent.first(), recent.last()), (Some(2), Some(4)));
+
+        // 4 was replaced by 14
+        assert!(!recent.extend([block(5, 5, 14)]));
+        assert_eq!(recent.last(), Some(4));
+
+        let fork_point = recent.replace(vec![block(2, 2, 1), block(3, 3, 2), block(4, 14, 3)]);
+        assert_eq!(fork_point, Some(4));
+        assert!(recent.extend([block(5, 5, 14)]));
+
+        // blocks that are gone after a rollback count as replaced
+        assert_eq!(recent.replace(vec![block(3, 3, 2)]), Some(4));
+        assert_eq!(recent.replace(vec![block(3, 3, 2)]), None);
+    }
+}
@@ -0,0 +1,409 @@
+use anyhow::{anyhow, Result};
+
+use crate::config::{Backend, RoutingConfig, RoutingRule};
+
+/// Methods that can be served from HyperSync data by the local handlers
+pub const HYPERSYNC_METHODS: &[&str] = &[
+    "eth_getBlockByNumber",
+    "eth_getTransactionByBlockNumberAndIndex",
+    "eth_getBlockReceipts",
+    "eth_getLogs",
+    "eth_blockNumber",
+    "eth_chainId",
+];
+
+const FILTER_METHODS: &[&str] = &[
+    "eth_newFilter",
+    "eth_getFilterLogs",
+    "eth_getFilterChanges",
+    "eth_uninstallFilter",
+];
+
+const HYPERRPC_METHODS: &[&str] = &[
+    "eth_getTransactionByBlockHashAndIndex",
+    "eth_getTransactionByHash",
+    "eth_getBlockByHash",
+    "eth_getTransactionReceipt",
+];
+
+// subscriptions need a persistent connection which the http server doesn't have
+const UNSUPPORTED_METHODS: &[&str] = &["eth_subscribe", "eth_unsubscribe"];
+
+/// Other methods of the standard eth, net and web3 namespaces, they are labelled with their name
+/// in metrics even though the router has nothing special for them
+const STANDARD_METHODS: &[&str] = &[
+    "eth_accounts",
+    "eth_blobBaseFee",
+    "eth_call",
+    "eth_coinbase",
+    "eth_createAccessList",
+    "eth_estimateGas",
+    "eth_feeHistory",
+    "eth_gasPrice",
+    "eth_getBalance",
+    "eth_getBlockTransactionCountByHash",
+    "eth_getBlockTransactionCountByNumber",
+    "eth_getCode",
+    "eth_getProof",
+    "eth_getStorageAt",
+    "eth_getTransactionCount",
+    "eth_getUncleByBlockHashAndIndex",
+    "eth_getUncleByBlockNumberAndIndex",
+    "eth_getUncleCountByBlockHash",
+    "eth_getUncleCountByBlockNumber",
+    "eth_maxPriorityFeePerGas",
+    "eth_mining",
+    "eth_newBlockFilter",
+    "eth_newPendingTransactionFilter",
+    "eth_protocolVersion",
+    "eth_sendTransaction",
+    "eth_sign",
+    "eth_signTransaction",
+    "eth_syncing",
+    "net_listening",
+    "net_peerCount",
+    "net_version",
+    "web3_clientVersion",
+    "web3_sha3",
+];
+
+/// Metric label of the methods the router doesn't know about
+const OTHER_METHOD_LABEL: &str = "other";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Route {
+    Backend {
+        primary: Backend,
+        secondary: Option<Backend>,
+    },
+    Denied,
+    Unsupported,
+}
+
+/// Methods that are sent to the broadcast endpoints if they are configured
+pub const BROADCAST_METHODS: &[&str] = &["eth_sendRawTransaction"];
+
+/// Decides which backend handles a method.
+///
+/// Deny rules are checked first, then the configured rules in order. Methods that don't match any
+/// configured rule use the built-in routing.
+pub struct Router {
+    deny: Vec<String>,
+    rules: Vec<RoutingRule>,
+    hyperrpc_is_stateful: bool,
+    has_broadcast: bool,
+}
+
+impl Router {
+    pub fn new(
+        cfg: RoutingConfig,
+        hyperrpc_is_stateful: bool,
+        has_broadcast: bool,
+    ) -> Result<Self> {
+        let router = Self {
+            deny: cfg.deny,
+            rules: cfg.rules,
+            hyperrpc_is_stateful,
+            has_broadcast,
+        };
+
+        for rule in router.rules.iter() {
+            for backend in std::iter::once(rule.backend).chain(rule.secondary) {
+                if !router.serves_any(backend, &rule.method) {
+                    return Err(anyhow!(
+                        "routing rule for '{}' uses {:?} but it doesn't match any method that can be served from it",
+                        rule.method,
+                        backend
+                    ));
+                }
+            }
+        }
+
+        Ok(router)
+    }
+
+    pub fn route(&self, method: &str) -> Route {
+        if self.deny.iter().any(|pattern| glob_match(pattern, method)) {
+            return Route::Denied;
+        }
+
+        // rules only apply to the methods their primary backend can serve
+        let rule = self
+            .rules
+            .iter()
+            .find(|rule| glob_match(&rule.method, method) && self.serves(rule.backend, method));
+
+        match rule {
+            Some(rule) => Route::Backend {
+                primary: rule.backend,
+                secondary: rule
+                    .secondary
+                    .filter(|&backend| self.serves(backend, method)),
+            },
+            None => self.builtin_route(method),
+        }
+    }
+
+    /// Label of the method in metrics. Method names come from clients, so only standard methods and
+    /// the methods the router knows about are used as they are, to keep the number of series
+    /// bounded.
+    pub fn metric_label<'a>(&self, method: &'a str) -> &'a str {
+        let builtin = [
+            HYPERSYNC_METHODS,
+            FILTER_METHODS,
+            HYPERRPC_METHODS,
+            BROADCAST_METHODS,
+            UNSUPPORTED_METHODS,
+            STANDARD_METHODS,
+        ]
+        .iter()
+        .any(|methods| methods.contains(&method));
+
+        // a pattern can match any method a client makes up, so only rules for a single method count
+        let has_rule = self.rules.iter().any(|rule| rule.method == method);
+
+        if builtin || has_rule {
+            method
+        } else {
+            OTHER_METHOD_LABEL
+        }
+    }
+
+    fn builtin_route(&self, method: &str) -> Route {
+        let is_stateful_filter = FILTER_METHODS.contains(&method) && self.hyperrpc_is_stateful;
+
+        let primary = if is_stateful_filter || HYPERRPC_METHODS.contains(&method) {
+            Backend::HyperRpc
+        } else if HYPERSYNC_METHODS.contains(&method) {
+            Backend::HyperSync
+        } else if self.serves(Backend::Broadcast, method) {
+            Backend::Broadcast
+        } else if UNSUPPORTED_METHODS.contains(&method) {
+            return Route::Unsupported;
+        } else {
+            Backend::Fallback
+        };
+
+        Route::Backend {
+            primary,
+            secondary: None,
+        }
+    }
+
+    /// Returns true if the backend can handle the method
+    fn serves(&self, backend: Backend, method: &str) -> bool {
+        match backend {
+            Backend::HyperSync => HYPERSYNC_METHODS.contains(&method),
+            Backend::Broadcast => self.has_broadcast && BROADCAST_METHODS.contains(&method),
+            Backend::HyperRpc | Backend::Fallback => true,
+        }
+    }
+
+    /// Returns true if the backend can handle any of the methods matching the pattern
+    fn serves_any(&self, backend: Backend, pattern: &str) -> bool {
+        match backend {
+            Backend::HyperSync | Backend::Broadcast => HYPERSYNC_METHODS
+                .iter()
+                .chain(BROADCAST_METHODS)
+                .any(|method| glob_match(pattern, method) && self.serves(backend, method)),
+            Backend::HyperRpc | Backend::Fallback => true,
+        }
+    }
+}
+
+/// Matches a method name against a pattern where `*` matches any sequence of characters and `?`
+/// matches a single character.
+pub fn glob_match(pattern: &str, method: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let method = method.as_bytes();
+
+    let (mut p, mut m) = (0, 0);
+    // position of the last '*' in pattern and the position in method it was matched at
+    let mut backtrack = None;
+
+    while m < method.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, m));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == method[m] => {
+                p += 1;
+                m += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_m)) => {
+                    p = star_p + 1;
+                    m = star_m + 1;
+                    backtrack = Some((star_p, star_m + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("eth_getLogs", "eth_getLogs"));
+        assert!(!glob_match("eth_getLogs", "eth_getLogsX"));
+        assert!(glob_match("debug_*", "debug_traceTransaction"));
+        assert!(glob_match("*", "eth_call"));
+        assert!(glob_match("eth_get*By*", "eth_getBlockByNumber"));
+        assert!(!glob_match("eth_get*By*", "eth_getLogs"));
+        assert!(glob_match("eth_?etLogs", "eth_getLogs"));
+        assert!(!glob_match("trace_*", "eth_call"));
+    }
+
+    fn rule(method: &str, backend: Backend, secondary: Option<Backend>) -> RoutingRule {
+        RoutingRule {
+            method: method.to_owned(),
+            backend,
+            secondary,
+        }
+    }
+
+    #[test]
+    fn test_route() {
+        let router = Router::new(
+            RoutingConfig {
+                deny: vec!["admin_*".to_owned()],
+                rules: vec![
+                    rule("eth_getTransactionReceipt", Backend::Fallback, None),
+                    rule("eth_get*", Backend::HyperSync, Some(Backend::Fallback)),
+                ],
+            },
+            false,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(router.route("admin_peers"), Route::Denied);
+        assert_eq!(
+            router.route("eth_getTransactionReceipt"),
+            Route::Backend {
+                primary: Backend::Fallback,
+                secondary: None
+            }
+        );
+        assert_eq!(
+            router.route("eth_getLogs"),
+            Route::Backend {
+                primary: Backend::HyperSync,
+                secondary: Some(Backend::Fallback)
+            }
+        );
+        // hypersync rule doesn't apply to methods it can't serve
+        assert_eq!(
+            router.route("eth_getBlockByHash"),
+            Route::Backend {
+                primary: Backend::HyperRpc,
+                secondary: None
+            }
+        );
+        assert_eq!(
+            router.route("eth_newFilter"),
+            Route::Backend {
+                primary: Backend::Fallback,
+                secondary: None
+            }
+        );
+        assert_eq!(router.route("eth_subscribe"), Route::Unsupported);
+    }
+
+    #[test]
+    fn test_broadcast_route() {
+        let routing = RoutingConfig {
+            deny: Vec::new(),
+            rules: vec![rule("eth_*", Backend::Broadcast, Some(Backend::Fallback))],
+        };
+
+        let router = Router::new(routing.clone(), false, true).unwrap();
+        assert_eq!(
+            router.route("eth_sendRawTransaction"),
+            Route::Backend {
+                primary: Backend::Broadcast,
+                secondary: Some(Backend::Fallback)
+            }
+        );
+        assert_eq!(
+            router.route("eth_call"),
+            Route::Backend {
+                primary: Backend::Fallback,
+                secondary: None
+            }
+        );
+
+        let router = Router::new(RoutingConfig::default(), false, true).unwrap();
+        assert_eq!(
+            router.route("eth_sendRawTransaction"),
+            Route::Backend {
+                primary: Backend::Broadcast,
+                secondary: None
+            }
+        );
+
+        let router = Router::new(RoutingConfig::default(), false, false).unwrap();
+        assert_eq!(
+            router.route("eth_sendRawTransaction"),
+            Route::Backend {
+                primary: Backend::Fallback,
+                secondary: None
+            }
+        );
+    }
+
+    #[test]
+    fn test_invalid_hypersync_rule() {
+        let cfg = RoutingConfig {
+            deny: Vec::new(),
+            rules: vec![rule("debug_*", Backend::HyperSync, None)],
+        };
+
+        assert!(Router::new(cfg.clone(), false, true).is_err());
+
+        let cfg = RoutingConfig {
+            deny: Vec::new(),
+            rules: vec![rule("eth_send*", Backend::Broadcast, None)],
+        };
+
+        assert!(Router::new(cfg.clone(), false, false).is_err());
+        assert!(Router::new(cfg, false, true).is_ok());
+    }
+
+    #[test]
+    fn test_metric_label() {
+        let router = Router::new(
+            RoutingConfig {
+                deny: Vec::new(),
+                rules: vec![
+                    rule("debug_traceTransaction", Backend::Fallback, None),
+                    rule("trace_*", Backend::Fallback, None),
+                ],
+            },
+            false,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(router.metric_label("eth_getLogs"), "eth_getLogs");
+        assert_eq!(
+            router.metric_label("eth_getBlockByHash"),
+            "eth_getBlockByHash"
+        );
+        assert_eq!(
+            router.metric_label("debug_traceTransaction"),
+            "debug_traceTransaction"
+        );
+        assert_eq!(router.metric_label("eth_call"), "eth_call");
+        assert_eq!(router.metric_label("net_version"), "net_version");
+        assert_eq!(router.metric_label("trace_block"), "other");
+        assert_eq!(router.metric_label("made_upMethod123"), "other");
+    }
+}
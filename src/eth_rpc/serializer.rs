@@ -6,7 +6,11 @@ use rayon::prelude::*;
 use skar_format::Log;
 use skar_format::{BlockHeader, Hex, Transaction, TransactionReceipt};
 
-pub fn parallel_serialize(responses: Vec<RpcResponse>, batch_flag: bool) -> BytesBuilder {
+/// Serializes the responses, also returns the serialized size of each response
+pub fn parallel_serialize(
+    responses: Vec<RpcResponse>,
+    batch_flag: bool,
+) -> (BytesBuilder, Vec<usize>) {
     let mut builder = BytesBuilder::new();
 
     if responses.len() > 1 {
@@ -18,6 +22,7 @@ pub fn parallel_serialize(responses: Vec<RpcResponse>, batch_flag: bool) -> Byte
                 b
             })
             .collect::<Vec<_>>();
+        let sizes = responses.iter().map(|resp| resp.total_len()).collect();
 
         builder.push_static("[");
         let mut start = "";
@@ -27,18 +32,29 @@ pub fn parallel_serialize(responses: Vec<RpcResponse>, batch_flag: bool) -> Byte
             start = ",";
         }
         builder.push_static("]");
+
+        (builder, sizes)
     } else if batch_flag {
         builder.push_static("[");
         serialize_individual_response(&mut builder, responses.first().unwrap());
         builder.push_static("]");
+
+        let size = builder.total_len() - 2;
+        (builder, vec![size])
     } else {
         serialize_individual_response(&mut builder, responses.first().unwrap());
-    }
 
-    builder
+        let size = builder.total_len();
+        (builder, vec![size])
+    }
 }
 
 pub fn serialize_individual_response(builder: &mut BytesBuilder, response: &RpcResponse) {
+    if let Ok(RpcResponseData::Proxy(raw)) = &response.result {
+        raw.write_with_id(builder, response.id);
+        return;
+    }
+
     match &response.result {
         Ok(data) => {
             builder.push_static(r#"{"id":"#);
@@ -103,21 +119,24 @@ pub fn serialize_individual_response(builder: &mut BytesBuilder, response: &RpcR
                 RpcResponseData::UninstallFilter(filter_uninstalled) => {
                     builder.push(Bytes::from(filter_uninstalled.to_string()));
                 }
-                RpcResponseData::Proxy(res) => {
-                    // TODO: maybe handle error instead of unwrap
-                    builder.push(Bytes::from(serde_json::to_vec(&res).unwrap()));
-                }
+                RpcResponseData::Proxy(_) => unreachable!("proxied responses are written as is"),
             }
 
             builder.push_static(r#"}"#);
         }
         Err(rpc_error) => {
+            let data = match &rpc_error.data {
+                Some(data) => format!(r#","data":{}"#, data),
+                None => String::new(),
+            };
+
             builder.push(Bytes::from(format!(
-                r#"{{"id":{},"jsonrpc":"2.0","error":{{"code":{},"message":{}}}}}"#,
+                r#"{{"id":{},"jsonrpc":"2.0","error":{{"code":{},"message":{}{}}}}}"#,
                 response.id,
                 rpc_error.code,
                 // do this so we have proper json escaping
                 serde_json::to_string(&rpc_error.message).unwrap(),
+                data,
             )));
         }
     }
@@ -281,6 +300,7 @@ mod tests {
     use skar_format::{Block, Hash, LogArgument};
 
     use super::*;
+    use crate::eth_rpc::error::RpcError;
 
     #[test]
     fn test_serialize_receipt() {
@@ -357,4 +377,29 @@ mod tests {
 
         assert_eq!(blk_src, blk);
     }
+
+    #[test]
+    fn test_serialize_error_with_data() {
+        let resp = RpcError::ResourceNotFound("block 1".into())
+            .with_data(serde_json::json!({ "hint": [1, "a"] }))
+            .to_response(&3);
+
+        let mut builder = BytesBuilder::new();
+        serialize_individual_response(&mut builder, &resp);
+
+        let resp: serde_json::Value = serde_json::from_slice(&builder.build()).unwrap();
+
+        assert_eq!(
+            resp,
+            serde_json::json!({
+                "id": 3,
+                "jsonrpc": "2.0",
+                "error": {
+                    "code": -32001,
+                    "message": "Resource not found: block 1",
+                    "data": { "hint": [1, "a"] },
+                },
+            })
+        );
+    }
 }
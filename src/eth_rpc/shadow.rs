@@ -0,0 +1,321 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+use crate::bytes_builder::BytesBuilder;
+use crate::config::ShadowConfig;
+use crate::json_diff::{self, FieldDiff};
+use crate::metrics;
+
+use super::handlers;
+use super::routing::glob_match;
+use super::serializer::serialize_individual_response;
+use super::types::{RpcRequest, RpcResponse};
+use super::RpcHandler;
+
+/// Maximum number of differences written to the log per response
+const MAX_LOGGED_DIFFS: usize = 10;
+
+/// Compares a sample of the responses served from HyperSync with the responses of the fallback.
+///
+/// The comparison runs in the background after the client got its response, differences are
+/// only logged and counted in metrics.
+pub struct Shadow {
+    sample_ratio: f64,
+    methods: Vec<String>,
+    ignore_fields: Vec<String>,
+}
+
+impl Shadow {
+    pub fn new(cfg: ShadowConfig) -> Self {
+        Self {
+            sample_ratio: cfg.sample_ratio,
+            methods: cfg.methods,
+            ignore_fields: cfg.ignore_fields,
+        }
+    }
+
+    /// Picks the successful responses to compare and serializes them.
+    ///
+    /// Requests that read the head of the chain, e.g. with a `latest` block tag, are left out since
+    /// HyperSync and the fallback resolve the head to different blocks.
+    ///
+    /// This has to run before the responses are handed to the serializer of the http response.
+    pub fn sample<'a>(
+        &self,
+        method: &str,
+        reqs: &[RpcRequest],
+        responses: impl Iterator<Item = &'a RpcResponse>,
+    ) -> Vec<(RpcRequest, Value)> {
+        if !self
+            .methods
+            .iter()
+            .any(|pattern| glob_match(pattern, method))
+        {
+            return Vec::new();
+        }
+
+        responses
+            .filter(|res| res.result.is_ok())
+            .filter(|_| rand::random::<f64>() < self.sample_ratio)
+            .filter_map(|res| {
+                let req = reqs.iter().find(|req| req.id == res.id)?;
+                if reads_head(req) {
+                    return None;
+                }
+                match response_json(res) {
+                    Ok(json) => Some((req.clone(), json)),
+                    Err(e) => {
+                        log::warn!(
+                            "failed to serialize response for shadow comparison: {:?}",
+                            e
+                        );
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Sends the sampled requests to the fallback and compares the responses
+    pub async fn compare(
+        &self,
+        rpc_handler: Arc<RpcHandler>,
+        method: String,
+        sampled: Vec<(RpcRequest, Value)>,
+    ) {
+        let reqs = sampled
+            .iter()
+            .map(|(req, _)| req.clone())
+            .collect::<Vec<_>>();
+        let (fallback_responses, _) =
+            handlers::handle_method_not_found(&rpc_handler.rpc_client, &reqs).await;
+        let method_label = rpc_handler.router.metric_label(&method);
+
+        for (req, local) in sampled {
+            metrics::SHADOW_COMPARISONS
+                .with_label_values(&[method_label])
+                .inc();
+
+            let fallback = fallback_responses
+                .iter()
+                .find(|res| res.id == req.id)
+                .filter(|res| res.result.is_ok())
+                .map(response_json);
+            let fallback = match fallback {
+                Some(Ok(fallback)) => fallback,
+                Some(Err(e)) => {
+                    log::warn!(
+                        "failed to read fallback response for shadow comparison: {:?}",
+                        e
+                    );
+                    metrics::SHADOW_ERRORS
+                        .with_label_values(&[method_label])
+                        .inc();
+                    continue;
+                }
+                None => {
+                    log::debug!("fallback failed on shadow request of {}", method);
+                    metrics::SHADOW_ERRORS
+                        .with_label_values(&[method_label])
+                        .inc();
+                    continue;
+                }
+            };
+
+            let diffs = json_diff::diff(&local, &fallback, &self.ignore_fields);
+            if diffs.is_empty() {
+                continue;
+            }
+
+            self.report(&method, method_label, &req, &diffs);
+        }
+    }
+
+    fn report(&self, method: &str, method_label: &str, req: &RpcRequest, diffs: &[FieldDiff]) {
+        metrics::SHADOW_MISMATCHES
+            .with_label_values(&[method_label])
+            .inc();
+
+        let mut fields = diffs.iter().map(|diff| diff.field()).collect::<Vec<_>>();
+        fields.sort();
+        fields.dedup();
+        for field in fields.iter() {
+            metrics::SHADOW_FIELD_MISMATCHES
+                .with_label_values(&[method_label, field])
+                .inc();
+        }
+
+        let logged = diffs
+            .iter()
+            .take(MAX_LOGGED_DIFFS)
+            .map(|diff| {
+                format!(
+                    "{}: hypersync={} fallback={}",
+                    diff.path,
+                    display_value(&diff.left),
+                    display_value(&diff.right)
+                )
+            })
+            .collect::<Vec<_>>();
+
+        log::warn!(
+            "shadow mismatch on {} with params {}: {} differences in fields [{}]. {}",
+            method,
+            req.params,
+            diffs.len(),
+            fields.join(", "),
+            logged.join("; ")
+        );
+    }
+}
+
+/// Block tags that resolve to a block relative to the head of the chain
+const HEAD_BLOCK_TAGS: &[&str] = &["latest", "pending", "safe", "finalized"];
+
+/// Returns true if the answer to the request depends on the head of the chain
+fn reads_head(req: &RpcRequest) -> bool {
+    let is_head_tag = |value: &Value| {
+        value
+            .as_str()
+            .is_some_and(|tag| HEAD_BLOCK_TAGS.contains(&tag))
+    };
+
+    match req.method.as_str() {
+        "eth_blockNumber" => true,
+        // a filter without a block hash or a block range defaults to latest
+        "eth_getLogs" => match req.params.get(0) {
+            Some(filter) if filter.get("blockHash").is_some() => false,
+            Some(filter) => ["fromBlock", "toBlock"].iter().any(|field| {
+                filter
+                    .get(field)
+                    .filter(|value| !value.is_null())
+                    .is_none_or(is_head_tag)
+            }),
+            None => false,
+        },
+        _ => req
+            .params
+            .as_array()
+            .is_some_and(|params| params.iter().any(is_head_tag)),
+    }
+}
+
+/// The response as the client would get it, without the id and jsonrpc members
+fn response_json(res: &RpcResponse) -> Result<Value> {
+    let mut builder = BytesBuilder::new();
+    serialize_individual_response(&mut builder, res);
+
+    let mut json: Value =
+        serde_json::from_slice(&builder.build()).context("parse serialized response")?;
+    if let Some(obj) = json.as_object_mut() {
+        obj.remove("id");
+        obj.remove("jsonrpc");
+    }
+
+    Ok(json)
+}
+
+fn display_value(value: &Option<Value>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "missing".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::error::RpcError;
+    use super::super::types::RpcResponseData;
+    use super::*;
+
+    fn shadow(sample_ratio: f64) -> Shadow {
+        Shadow::new(ShadowConfig {
+            sample_ratio,
+            methods: vec!["eth_get*".to_owned()],
+            ignore_fields: Vec::new(),
+        })
+    }
+
+    fn request(id: i64, method: &str, params: Value) -> RpcRequest {
+        RpcRequest {
+            id,
+            jsonrpc: "2.0".to_owned(),
+            method: method.to_owned(),
+            params,
+        }
+    }
+
+    #[test]
+    fn test_sample() {
+        let reqs = (1..=2)
+            .map(|id| {
+                request(
+                    id,
+                    "eth_getLogs",
+                    serde_json::json!([{ "fromBlock": "0x1", "toBlock": "0x2" }]),
+                )
+            })
+            .collect::<Vec<_>>();
+        let responses = [
+            RpcResponse::new(1, "2.0", Ok(RpcResponseData::UninstallFilter(true))),
+            RpcError::InternalError(anyhow::anyhow!("failed").into()).to_response(&2),
+        ];
+
+        let sampled = shadow(1.0).sample("eth_getLogs", &reqs, responses.iter());
+        assert_eq!(sampled.len(), 1);
+        assert_eq!(sampled[0].0, reqs[0]);
+        assert_eq!(sampled[0].1, serde_json::json!({ "result": true }));
+
+        assert!(shadow(0.0)
+            .sample("eth_getLogs", &reqs, responses.iter())
+            .is_empty());
+        assert!(shadow(1.0)
+            .sample("eth_call", &reqs, responses.iter())
+            .is_empty());
+
+        let latest = [request(
+            1,
+            "eth_getLogs",
+            serde_json::json!([{ "fromBlock": "0x1", "toBlock": "latest" }]),
+        )];
+        assert!(shadow(1.0)
+            .sample("eth_getLogs", &latest, responses.iter())
+            .is_empty());
+    }
+
+    #[test]
+    fn test_reads_head() {
+        let reads_head = |method, params| reads_head(&request(1, method, params));
+
+        assert!(reads_head(
+            "eth_getBlockByNumber",
+            serde_json::json!(["latest", false])
+        ));
+        assert!(!reads_head(
+            "eth_getBlockByNumber",
+            serde_json::json!(["0x10", false])
+        ));
+        assert!(!reads_head(
+            "eth_getBlockByNumber",
+            serde_json::json!(["earliest", false])
+        ));
+        assert!(reads_head(
+            "eth_getBlockReceipts",
+            serde_json::json!(["safe"])
+        ));
+        assert!(reads_head(
+            "eth_getLogs",
+            serde_json::json!([{ "fromBlock": "0x10" }])
+        ));
+        assert!(!reads_head(
+            "eth_getLogs",
+            serde_json::json!([{ "fromBlock": "0x10", "toBlock": "0x20" }])
+        ));
+        assert!(!reads_head(
+            "eth_getLogs",
+            serde_json::json!([{ "blockHash": "0x01" }])
+        ));
+    }
+}
@@ -12,6 +12,7 @@ use std::str::FromStr;
 use super::error::{RpcError, RpcErrorCode};
 use super::handlers::resolve_block_number;
 use super::RpcHandler;
+use crate::rpc_client::RawResponse;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum RpcBlockNumber {
@@ -161,7 +162,9 @@ pub enum RpcResponseData {
     BlockNumber(Option<BlockNumber>),
     Transaction(Option<Transaction>),
     UninstallFilter(bool),
-    Proxy(serde_json::Value),
+    /// Response object from upstream, written to the client as is with only the id replaced
+    #[serde(skip)]
+    Proxy(RawResponse),
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
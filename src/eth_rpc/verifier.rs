@@ -0,0 +1,495 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use skar_format::{BlockHeader, BlockNumber, Hash, Hex, Log, Transaction, TransactionReceipt};
+
+use crate::config::{VerifyConfig, VerifyMismatch};
+use crate::metrics;
+use crate::rpc_client::{self, RpcClient, RpcRequestImpl, RpcResponseImpl};
+use crate::verify;
+
+use super::routing::glob_match;
+use super::types::{BlockVariant, RpcResponse, RpcResponseData};
+
+/// Methods that have a check implemented
+const VERIFIABLE_METHODS: [&str; 3] = [
+    "eth_getBlockReceipts",
+    "eth_getBlockByNumber",
+    "eth_getLogs",
+];
+
+/// Checks the data served from HyperSync against the block headers of the fallback.
+///
+/// Receipts are checked by recomputing the receipts root and the logs bloom, blocks with full
+/// transactions by recomputing the transactions root and logs by looking them up in the logs bloom
+/// of their block.
+pub struct Verifier {
+    methods: Vec<String>,
+    max_blocks_per_response: usize,
+    pub on_mismatch: VerifyMismatch,
+}
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Verified,
+    Mismatch(String),
+    /// The data can't be checked, e.g. transactions whose encoding can't be rebuilt
+    Unverifiable(String),
+    /// The fallback didn't return the header of the block
+    MissingHeader(u64),
+}
+
+impl Outcome {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Verified => "verified",
+            Self::Mismatch(_) => "mismatch",
+            Self::Unverifiable(_) => "unverifiable",
+            Self::MissingHeader(_) => "missing_header",
+        }
+    }
+}
+
+impl Verifier {
+    pub fn new(cfg: VerifyConfig) -> Self {
+        Self {
+            methods: cfg.methods,
+            max_blocks_per_response: cfg.max_blocks_per_response,
+            on_mismatch: cfg.on_mismatch,
+        }
+    }
+
+    pub fn verifies(&self, method: &str) -> bool {
+        VERIFIABLE_METHODS.contains(&method)
+            && self
+                .methods
+                .iter()
+                .any(|pattern| glob_match(pattern, method))
+    }
+
+    /// Returns the ids of the responses that don't match the block headers of the fallback.
+    ///
+    /// Responses that can't be checked, e.g. because the fallback doesn't have the block, are
+    /// let through. Only the headers of the first `max_blocks_per_response` blocks of a response
+    /// are fetched, responses with logs of more blocks are unverifiable if those blocks match.
+    /// Outcomes are counted with `method_label` as the method.
+    pub async fn mismatched(
+        &self,
+        rpc_client: &RpcClient,
+        method: &str,
+        method_label: &str,
+        responses: &[RpcResponse],
+    ) -> Vec<i64> {
+        let block_numbers = responses
+            .iter()
+            .filter_map(|res| res.result.as_ref().ok())
+            .flat_map(|data| block_numbers(data, self.max_blocks_per_response))
+            .collect::<BTreeSet<_>>();
+        if block_numbers.is_empty() {
+            return Vec::new();
+        }
+
+        let headers = fetch_headers(rpc_client, block_numbers).await;
+
+        let mut mismatched = Vec::new();
+        for res in responses {
+            let outcome = match res
+                .result
+                .as_ref()
+                .ok()
+                .and_then(|data| check(data, &headers, self.max_blocks_per_response))
+            {
+                Some(outcome) => outcome,
+                None => continue,
+            };
+
+            metrics::VERIFIED_RESPONSES
+                .with_label_values(&[method_label, outcome.label()])
+                .inc();
+
+            match outcome {
+                Outcome::Verified => (),
+                Outcome::Mismatch(reason) => {
+                    log::warn!(
+                        "{} response from hypersync doesn't match the fallback block header: {}",
+                        method,
+                        reason
+                    );
+                    mismatched.push(res.id);
+                }
+                Outcome::Unverifiable(reason) => {
+                    log::debug!("can't verify {} response: {}", method, reason)
+                }
+                Outcome::MissingHeader(block_number) => log::debug!(
+                    "can't verify {} response: no header for block {} from the fallback",
+                    method,
+                    block_number
+                ),
+            }
+        }
+
+        mismatched
+    }
+}
+
+/// Numbers of the blocks whose headers are needed to check the response
+fn block_numbers(data: &RpcResponseData, max_blocks: usize) -> Vec<u64> {
+    match data {
+        RpcResponseData::Receipts(Some(receipts)) => receipts
+            .first()
+            .map(|receipt| receipt.block_number.into())
+            .into_iter()
+            .collect(),
+        RpcResponseData::Block(Some(BlockVariant::Transactions(block))) => {
+            vec![block.header.number.into()]
+        }
+        RpcResponseData::Block(Some(BlockVariant::Headers(block))) => {
+            vec![block.header.number.into()]
+        }
+        RpcResponseData::Logs(Some(logs)) => {
+            checked_log_blocks(logs, max_blocks).into_iter().collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// The lowest `max_blocks` blocks that have logs in the response, the logs of other blocks aren't
+/// checked to bound the number of headers fetched for a wide eth_getLogs response
+fn checked_log_blocks(logs: &[Log], max_blocks: usize) -> BTreeSet<u64> {
+    logs.iter()
+        .map(|log| log.block_number.into())
+        .collect::<BTreeSet<u64>>()
+        .into_iter()
+        .take(max_blocks)
+        .collect()
+}
+
+/// Returns None if there is nothing to check in the response
+fn check(
+    data: &RpcResponseData,
+    headers: &BTreeMap<u64, BlockHeader>,
+    max_blocks: usize,
+) -> Option<Outcome> {
+    let header = |block_number: u64| {
+        headers
+            .get(&block_number)
+            .ok_or(Outcome::MissingHeader(block_number))
+    };
+
+    let outcome = match data {
+        RpcResponseData::Receipts(Some(receipts)) if !receipts.is_empty() => {
+            header(receipts[0].block_number.into()).map(|header| check_receipts(receipts, header))
+        }
+        RpcResponseData::Block(Some(BlockVariant::Transactions(block))) => {
+            header(block.header.number.into())
+                .map(|header| check_block(&block.header, Some(&block.transactions), header))
+        }
+        RpcResponseData::Block(Some(BlockVariant::Headers(block))) => {
+            header(block.header.number.into())
+                .map(|header| check_block(&block.header, None, header))
+        }
+        RpcResponseData::Logs(Some(logs)) if !logs.is_empty() => {
+            Ok(check_logs(logs, headers, max_blocks))
+        }
+        _ => return None,
+    };
+
+    Some(outcome.unwrap_or_else(|missing| missing))
+}
+
+fn check_receipts(receipts: &[TransactionReceipt], header: &BlockHeader) -> Outcome {
+    let mut block_bloom = [0; verify::BLOOM_SIZE];
+
+    for receipt in receipts {
+        if receipt.block_hash != header.hash {
+            return Outcome::Mismatch(format!(
+                "receipt {} has block hash {} instead of {}",
+                receipt.transaction_hash.encode_hex(),
+                receipt.block_hash.encode_hex(),
+                header.hash.encode_hex()
+            ));
+        }
+
+        let bloom = verify::logs_bloom(receipt.logs.iter());
+        if bloom.as_slice() != receipt.logs_bloom.as_ref() {
+            return Outcome::Mismatch(format!(
+                "logs bloom of receipt {} doesn't match its logs",
+                receipt.transaction_hash.encode_hex()
+            ));
+        }
+
+        for (block_byte, byte) in block_bloom.iter_mut().zip(bloom) {
+            *block_byte |= byte;
+        }
+    }
+
+    if block_bloom.as_slice() != header.logs_bloom.as_ref() {
+        return Outcome::Mismatch(format!(
+            "logs bloom of the receipts of block {} doesn't match the header",
+            header.number.encode_hex()
+        ));
+    }
+
+    let root = verify::receipts_root(receipts);
+    if root != header.receipts_root.as_ref() {
+        return Outcome::Mismatch(format!(
+            "receipts root of block {} is {} instead of {}",
+            header.number.encode_hex(),
+            Hash::from(root).encode_hex(),
+            header.receipts_root.encode_hex()
+        ));
+    }
+
+    Outcome::Verified
+}
+
+fn check_block(
+    local: &BlockHeader,
+    transactions: Option<&[Transaction]>,
+    header: &BlockHeader,
+) -> Outcome {
+    let fields = [
+        ("hash", &local.hash, &header.hash),
+        ("parentHash", &local.parent_hash, &header.parent_hash),
+        ("stateRoot", &local.state_root, &header.state_root),
+        (
+            "transactionsRoot",
+            &local.transactions_root,
+            &header.transactions_root,
+        ),
+        ("receiptsRoot", &local.receipts_root, &header.receipts_root),
+    ];
+    for (name, local_value, value) in fields {
+        if local_value != value {
+            return Outcome::Mismatch(format!(
+                "{} of block {} is {} instead of {}",
+                name,
+                header.number.encode_hex(),
+                local_value.encode_hex(),
+                value.encode_hex()
+            ));
+        }
+    }
+    if local.logs_bloom != header.logs_bloom {
+        return Outcome::Mismatch(format!(
+            "logsBloom of block {} doesn't match",
+            header.number.encode_hex()
+        ));
+    }
+
+    let transactions = match transactions {
+        Some(transactions) => transactions,
+        None => return Outcome::Verified,
+    };
+
+    match verify::transactions_root(transactions) {
+        Some(root) if root == header.transactions_root.as_ref() => Outcome::Verified,
+        Some(root) => Outcome::Mismatch(format!(
+            "transactions root of block {} is {} instead of {}",
+            header.number.encode_hex(),
+            Hash::from(root).encode_hex(),
+            header.transactions_root.encode_hex()
+        )),
+        None => Outcome::Unverifiable(format!(
+            "transactions of block {} can't be encoded from the returned fields",
+            header.number.encode_hex()
+        )),
+    }
+}
+
+fn check_logs(logs: &[Log], headers: &BTreeMap<u64, BlockHeader>, max_blocks: usize) -> Outcome {
+    let checked = checked_log_blocks(logs, max_blocks);
+    let mut skipped = 0;
+
+    for log in logs {
+        if !checked.contains(&log.block_number.into()) {
+            skipped += 1;
+            continue;
+        }
+
+        let header = match headers.get(&log.block_number.into()) {
+            Some(header) => header,
+            None => return Outcome::MissingHeader(log.block_number.into()),
+        };
+
+        if log.block_hash != header.hash {
+            return Outcome::Mismatch(format!(
+                "log {} of block {} has block hash {} instead of {}",
+                log.log_index.encode_hex(),
+                header.number.encode_hex(),
+                log.block_hash.encode_hex(),
+                header.hash.encode_hex()
+            ));
+        }
+
+        if !verify::bloom_contains_log(header.logs_bloom.as_ref(), log) {
+            return Outcome::Mismatch(format!(
+                "log {} of block {} isn't in the logs bloom of the header",
+                log.log_index.encode_hex(),
+                header.number.encode_hex()
+            ));
+        }
+    }
+
+    if skipped > 0 {
+        return Outcome::Unverifiable(format!(
+            "{} logs are in blocks after the first {} and aren't checked",
+            skipped, max_blocks
+        ));
+    }
+
+    Outcome::Verified
+}
+
+async fn fetch_headers(
+    rpc_client: &RpcClient,
+    block_numbers: BTreeSet<u64>,
+) -> BTreeMap<u64, BlockHeader> {
+    let block_numbers = block_numbers.into_iter().collect::<Vec<_>>();
+    let reqs = block_numbers
+        .iter()
+        .map(|&block_number| RpcRequestImpl::Proxy {
+            method: "eth_getBlockByNumber".to_owned(),
+            params: serde_json::json!([BlockNumber::from(block_number), false]),
+        })
+        .collect();
+
+    let mut headers = BTreeMap::new();
+    for (range, res) in rpc_client.send_chunked(reqs).await {
+        let resps = match res {
+            Ok(rpc_client::RpcResponse::Batch(resps)) => resps,
+            Ok(rpc_client::RpcResponse::Single(_)) => {
+                log::warn!("fallback returned a single response to a batch of header requests");
+                continue;
+            }
+            Err(e) => {
+                log::warn!("failed to get block headers for verification: {}", e);
+                continue;
+            }
+        };
+
+        for (res, &block_number) in resps.into_iter().zip(&block_numbers[range]) {
+            match parse_header(res) {
+                Ok(header) => {
+                    headers.insert(block_number, header);
+                }
+                Err(e) => log::debug!("no header for block {}: {:?}", block_number, e),
+            }
+        }
+    }
+
+    headers
+}
+
+fn parse_header(res: RpcResponseImpl) -> Result<BlockHeader> {
+    #[derive(Deserialize)]
+    struct HeaderResponse {
+        result: Option<BlockHeader>,
+    }
+
+    let raw = match res {
+        RpcResponseImpl::Proxy(raw) => raw,
+        _ => return Err(anyhow!("missing response")),
+    };
+
+    let res: HeaderResponse =
+        serde_json::from_slice(raw.body()).context("parse header response")?;
+
+    res.result.context("block not found")
+}
+
+#[cfg(test)]
+mod tests {
+    use skar_format::{Address, TransactionStatus};
+
+    use super::*;
+
+    fn receipts() -> Vec<TransactionReceipt> {
+        (0..3u8)
+            .map(|i| {
+                let logs = vec![Log {
+                    block_number: 7.into(),
+                    block_hash: Hash::from([7; 32]),
+                    address: Address::from([i; 20]),
+                    topics: [Hash::from([i + 10; 32])].into_iter().collect(),
+                    ..Default::default()
+                }];
+                TransactionReceipt {
+                    block_number: 7.into(),
+                    block_hash: Hash::from([7; 32]),
+                    kind: Some(2.into()),
+                    status: Some(TransactionStatus::Success),
+                    cumulative_gas_used: [i + 1].into(),
+                    logs_bloom: verify::logs_bloom(logs.iter()).into(),
+                    logs,
+                    ..Default::default()
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_check() {
+        let receipts = receipts();
+        let logs = receipts
+            .iter()
+            .flat_map(|receipt| receipt.logs.clone())
+            .collect::<Vec<_>>();
+
+        let header = BlockHeader {
+            number: 7.into(),
+            hash: Hash::from([7; 32]),
+            logs_bloom: verify::logs_bloom(logs.iter()).into(),
+            receipts_root: Hash::from(verify::receipts_root(&receipts)),
+            ..Default::default()
+        };
+        let headers = BTreeMap::from([(7, header)]);
+
+        let check_receipts = |receipts: &Vec<TransactionReceipt>| {
+            check(
+                &RpcResponseData::Receipts(Some(receipts.clone())),
+                &headers,
+                100,
+            )
+        };
+        let check_logs =
+            |logs: &Vec<Log>| check(&RpcResponseData::Logs(Some(logs.clone())), &headers, 100);
+
+        assert_eq!(check_receipts(&receipts), Some(Outcome::Verified));
+        assert_eq!(check_logs(&logs), Some(Outcome::Verified));
+        assert_eq!(
+            check(&RpcResponseData::Logs(Some(Vec::new())), &headers, 100),
+            None
+        );
+
+        let mut tampered = receipts.clone();
+        tampered[1].cumulative_gas_used = [9].into();
+        assert!(matches!(
+            check_receipts(&tampered),
+            Some(Outcome::Mismatch(_))
+        ));
+
+        // a receipt was left out
+        assert!(matches!(
+            check_receipts(&receipts[..2].to_vec()),
+            Some(Outcome::Mismatch(_))
+        ));
+
+        let mut tampered = logs.clone();
+        tampered[0].address = Address::from([0xff; 20]);
+        assert!(matches!(check_logs(&tampered), Some(Outcome::Mismatch(_))));
+
+        let mut other_block = logs.clone();
+        other_block[0].block_number = 8.into();
+        assert_eq!(check_logs(&other_block), Some(Outcome::MissingHeader(8)));
+
+        // only the header of the first block is fetched, the log of the other block isn't checked
+        let capped = RpcResponseData::Logs(Some(other_block));
+        assert_eq!(block_numbers(&capped, 1), vec![7]);
+        assert!(matches!(
+            check(&capped, &headers, 1),
+            Some(Outcome::Unverifiable(_))
+        ));
+    }
+}
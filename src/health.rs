@@ -0,0 +1,141 @@
+use serde::Serialize;
+
+use crate::eth_rpc::RpcHandler;
+
+/// Body of the /ready endpoint
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadyStatus {
+    pub ready: bool,
+    /// Highest block known by HyperSync or any of the upstream endpoints
+    pub chain_head: Option<u64>,
+    pub max_lag: u64,
+    pub hypersync: HeightStatus,
+    pub hyperrpc: Vec<EndpointStatus>,
+    pub fallback: Vec<EndpointStatus>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HeightStatus {
+    /// None if the height couldn't be fetched
+    pub height: Option<u64>,
+    pub lag: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EndpointStatus {
+    pub endpoint: String,
+    /// None if the endpoint is unhealthy
+    pub last_block: Option<u64>,
+    pub lag: Option<u64>,
+}
+
+/// Collects the heights of HyperSync and the upstream endpoints.
+///
+/// The proxy is ready if HyperSync and at least one endpoint of each rpc client are within
+/// max_lag blocks of the chain head.
+pub async fn ready_status(rpc_handler: &RpcHandler, max_lag: u64) -> ReadyStatus {
+    let hypersync_height = match rpc_handler.skar_client.get_height().await {
+        Ok(height) => Some(height),
+        Err(e) => {
+            log::debug!("failed to get hypersync height: {:?}", e);
+            None
+        }
+    };
+
+    let hyperrpc = rpc_handler.hyperrpc_client.endpoint_heights().await;
+    let fallback = rpc_handler.rpc_client.endpoint_heights().await;
+
+    build_status(hypersync_height, hyperrpc, fallback, max_lag)
+}
+
+fn build_status(
+    hypersync_height: Option<u64>,
+    hyperrpc: Vec<(String, Option<u64>)>,
+    fallback: Vec<(String, Option<u64>)>,
+    max_lag: u64,
+) -> ReadyStatus {
+    let chain_head = hyperrpc
+        .iter()
+        .chain(fallback.iter())
+        .filter_map(|(_, last_block)| *last_block)
+        .chain(hypersync_height)
+        .max();
+
+    let lag = |height: Option<u64>| Some(chain_head?.saturating_sub(height?));
+    let in_sync = |height: Option<u64>| lag(height).is_some_and(|lag| lag <= max_lag);
+
+    let hypersync = HeightStatus {
+        height: hypersync_height,
+        lag: lag(hypersync_height),
+    };
+
+    let endpoints = |heights: Vec<(String, Option<u64>)>| {
+        heights
+            .into_iter()
+            .map(|(endpoint, last_block)| EndpointStatus {
+                endpoint,
+                last_block,
+                lag: lag(last_block),
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let hyperrpc = endpoints(hyperrpc);
+    let fallback = endpoints(fallback);
+
+    let any_in_sync = |endpoints: &[EndpointStatus]| {
+        endpoints
+            .iter()
+            .any(|endpoint| in_sync(endpoint.last_block))
+    };
+
+    ReadyStatus {
+        ready: in_sync(hypersync_height) && any_in_sync(&hyperrpc) && any_in_sync(&fallback),
+        chain_head,
+        max_lag,
+        hypersync,
+        hyperrpc,
+        fallback,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heights(heights: &[Option<u64>]) -> Vec<(String, Option<u64>)> {
+        heights
+            .iter()
+            .enumerate()
+            .map(|(i, h)| (format!("endpoint{}", i), *h))
+            .collect()
+    }
+
+    #[test]
+    fn test_ready() {
+        let status = build_status(
+            Some(100),
+            heights(&[Some(101)]),
+            heights(&[None, Some(95)]),
+            10,
+        );
+        assert!(status.ready);
+        assert_eq!(status.chain_head, Some(101));
+        assert_eq!(status.hypersync.lag, Some(1));
+        assert_eq!(status.fallback[0].lag, None);
+        assert_eq!(status.fallback[1].lag, Some(6));
+    }
+
+    #[test]
+    fn test_not_ready() {
+        // hypersync height unknown
+        assert!(!build_status(None, heights(&[Some(100)]), heights(&[Some(100)]), 10).ready);
+        // no healthy fallback endpoint
+        assert!(!build_status(Some(100), heights(&[Some(100)]), heights(&[None]), 10).ready);
+        // hypersync is behind
+        assert!(!build_status(Some(80), heights(&[Some(100)]), heights(&[Some(100)]), 10).ready);
+    }
+}
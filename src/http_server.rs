@@ -1,24 +1,70 @@
 use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Instant;
 
+use crate::access_log::{self, AccessLog, AccessLogEntry, CallStatus};
 use crate::bytes_builder::BytesBuilder;
+use crate::config::{Backend, HttpRouteConfig, MethodFilterConfig};
 use crate::eth_rpc::error::RpcError;
+use crate::eth_rpc::routing::glob_match;
 use crate::eth_rpc::serializer::parallel_serialize;
 use crate::eth_rpc::types::{RpcRequest, RpcRequestErrorCheck, RpcResponse};
+use crate::health;
+use crate::metrics;
+use crate::telemetry;
+use crate::types::{elapsed, QueryMetrics};
 use crate::{config::HttpServerConfig, eth_rpc::RpcHandler};
 
 use anyhow::Context;
 use axum::body::Body;
+use axum::extract::ConnectInfo;
 use axum::extract::Json as AxumJson;
 use axum::extract::State as AxumState;
-use axum::http::StatusCode;
+use axum::http::{HeaderMap, StatusCode};
 use axum::response::{IntoResponse, Response};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 
 pub struct HttpServer;
 
 pub struct State {
     pub rpc_handler: Arc<RpcHandler>,
     pub cfg: HttpServerConfig,
+    /// Methods accepted on the route this state belongs to
+    pub methods: MethodFilter,
+    pub access_log: Option<Arc<AccessLog>>,
+}
+
+/// Allow and deny lists of methods, requests for methods that don't pass are answered with a
+/// method not found error.
+#[derive(Debug, Clone, Default)]
+pub struct MethodFilter {
+    allow: Option<Vec<String>>,
+    deny: Vec<String>,
+}
+
+impl MethodFilter {
+    /// Creates the filter of a route, lists that are set on the route replace the default ones
+    pub fn new(default: &MethodFilterConfig, route: Option<&MethodFilterConfig>) -> Self {
+        let route = route.cloned().unwrap_or_default();
+
+        Self {
+            allow: route.allow.or_else(|| default.allow.clone()),
+            deny: route
+                .deny
+                .or_else(|| default.deny.clone())
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn is_allowed(&self, method: &str) -> bool {
+        let allowed = match &self.allow {
+            Some(allow) => allow.iter().any(|pattern| glob_match(pattern, method)),
+            None => true,
+        };
+
+        allowed && !self.deny.iter().any(|pattern| glob_match(pattern, method))
+    }
 }
 
 impl HttpServer {
@@ -28,20 +74,61 @@ impl HttpServer {
     ) -> Result<(), anyhow::Error> {
         let addr = cfg.addr;
 
-        let state = Arc::new(State { rpc_handler, cfg });
-
-        let app = axum::Router::new().route(
-            "/",
-            axum::routing::post(run_rpc_query).with_state(state.clone()),
+        let mut paths = vec!["/".to_owned()];
+        paths.extend(
+            cfg.routes
+                .iter()
+                .map(|route| route.path.clone())
+                .filter(|path| path != "/"),
         );
 
+        let access_log = match cfg.access_log.clone() {
+            Some(access_log_cfg) => Some(Arc::new(
+                AccessLog::new(access_log_cfg).context("create access log")?,
+            )),
+            None => None,
+        };
+
+        let mut app = axum::Router::new();
+
+        for path in paths {
+            let route = cfg.routes.iter().find(|route| route.path == path);
+
+            let state = Arc::new(State {
+                rpc_handler: rpc_handler.clone(),
+                cfg: cfg.clone(),
+                methods: MethodFilter::new(
+                    &cfg.methods,
+                    route.map(HttpRouteConfig::methods).as_ref(),
+                ),
+                access_log: access_log.clone(),
+            });
+
+            app = app.route(&path, axum::routing::post(run_rpc_query).with_state(state));
+        }
+
+        let state = Arc::new(State {
+            rpc_handler: rpc_handler.clone(),
+            cfg: cfg.clone(),
+            methods: MethodFilter::default(),
+            access_log: None,
+        });
+
+        app = app
+            .route("/health", axum::routing::get(health))
+            .route("/metrics", axum::routing::get(metrics))
+            .route("/ready", axum::routing::get(ready).with_state(state));
+
         let listener = tokio::net::TcpListener::bind(&addr)
             .await
             .context("bind listener")?;
 
-        axum::serve(listener, app)
-            .await
-            .context("run http server")?;
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .context("run http server")?;
 
         Ok(())
     }
@@ -72,14 +159,53 @@ where
     }
 }
 
+/// Liveness probe, only checks that the process is able to serve http requests
+pub async fn health() -> Response {
+    AxumJson(serde_json::json!({ "status": "ok" })).into_response()
+}
+
+/// Prometheus metrics in the text exposition format
+pub async fn metrics() -> Result<Response, AppError> {
+    let body = metrics::encode()?;
+
+    Ok(([("content-type", "text/plain; version=0.0.4")], body).into_response())
+}
+
+/// Readiness probe, responds with 503 until HyperSync and the upstreams are reachable and in sync
+pub async fn ready(AxumState(state): AxumState<Arc<State>>) -> Response {
+    let status = health::ready_status(&state.rpc_handler, state.cfg.ready_max_lag_blocks).await;
+
+    let code = if status.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (code, AxumJson(status)).into_response()
+}
+
+#[tracing::instrument(name = "http_request", skip_all, fields(num_methods))]
 pub async fn run_rpc_query(
     AxumState(state): AxumState<Arc<State>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     AxumJson(request): AxumJson<serde_json::Value>,
 ) -> Result<Response, AppError> {
+    let span = tracing::Span::current();
+    span.set_parent(telemetry::parent_context(&headers));
+
     let rpc_handler = state.rpc_handler.clone();
 
+    metrics::HTTP_REQUESTS.inc();
+    let _timer = metrics::HTTP_REQUEST_DURATION.start_timer();
+
+    let request_start = Instant::now();
+    let mut query_metrics = QueryMetrics::default();
+
     let mut rpc_responses: Vec<RpcResponse> = Vec::new();
 
+    let start = Instant::now();
+
     // deserialize, groups into vec, finds some ParseError
     let (requests_deserialized, batch_flag) = deserialize_req(request);
 
@@ -88,32 +214,87 @@ pub async fn run_rpc_query(
         requests_deserialized,
         max_number_of_requests,
         rpc_handler.rpc_version.clone(),
+        &state.methods,
     );
 
+    // the requests that fail the checks are only needed for the access log
+    let mut calls: HashMap<i64, CallInfo> = HashMap::new();
+    if state.access_log.is_some() {
+        for req in requests_param_checked
+            .iter()
+            .filter(|req| req.error.is_some())
+        {
+            calls.entry(req.request.id).or_insert(CallInfo {
+                request: req.request.clone(),
+                backend: None,
+                latency: 0,
+            });
+        }
+    }
+
     let requests_validated = handle_errors(requests_param_checked, &mut rpc_responses);
 
     // group by method
     let requests_by_method = group_by_method(requests_validated);
 
+    query_metrics.parse_time += elapsed(&start);
+    span.record("num_methods", requests_by_method.len());
+
     // execute the rpc requests for each method
 
     for (method, reqs) in &requests_by_method {
         let rpc_handler = rpc_handler.clone();
-        let responses = rpc_handler.execute_rpc_method(method, reqs).await;
-
-        for response in responses {
+        let start = Instant::now();
+        let (responses, method_metrics) = rpc_handler.execute_rpc_method(method, reqs).await;
+        let latency = elapsed(&start);
+
+        for (response, backend) in responses {
+            if state.access_log.is_some() {
+                if let Some(request) = reqs.iter().find(|req| req.id == response.id) {
+                    calls.insert(
+                        response.id,
+                        CallInfo {
+                            request: request.clone(),
+                            backend,
+                            latency,
+                        },
+                    );
+                }
+            }
             rpc_responses.push(response);
         }
+        query_metrics += method_metrics;
     }
     // sort requests by id
     rpc_responses.sort_by_key(|response| response.id);
 
+    let access_log_entries = state.access_log.as_ref().map(|access_log| {
+        access_log_entries(access_log, &calls, &rpc_responses, client, batch_flag)
+    });
+
     // serialize response
-    let serialized_response = serialize_response(
+    let start = Instant::now();
+    let (serialized_response, response_sizes) = serialize_response(
         rpc_responses,
         rpc_handler.max_payload_size_in_mb,
         batch_flag,
     );
+    query_metrics.response_encode_time += elapsed(&start);
+
+    if let (Some(access_log), Some(entries)) = (&state.access_log, access_log_entries) {
+        write_access_log(access_log, entries, response_sizes);
+    }
+
+    let total_time = elapsed(&request_start);
+    let server_timing = query_metrics.server_timing(total_time);
+    log::debug!(
+        "served {} methods in {}us: {}",
+        requests_by_method.len(),
+        total_time,
+        server_timing
+    );
+
+    metrics::HTTP_RESPONSE_BYTES.inc_by(serialized_response.total_len() as u64);
 
     let body = Body::from_stream(futures::stream::iter(
         serialized_response.into_iter().map(Ok::<_, std::io::Error>),
@@ -128,30 +309,115 @@ pub async fn run_rpc_query(
             .try_into()
             .context("Inserting content into response")?,
     );
+    response.headers_mut().insert(
+        "server-timing",
+        server_timing
+            .try_into()
+            .context("Inserting server timing into response")?,
+    );
 
     Ok(response)
 }
 
+/// Serializes the responses, also returns the size of each response or an error if they were
+/// replaced by a single error because the payload is too large
 fn serialize_response(
     rpc_responses: Vec<RpcResponse>,
     max_response_size: usize,
     batch_flag: bool,
-) -> BytesBuilder {
-    let serialized_response =
+) -> (BytesBuilder, Result<Vec<usize>, RpcError>) {
+    let (serialized_response, sizes) =
         tokio::task::block_in_place(|| parallel_serialize(rpc_responses, batch_flag));
 
     let max_response_size_in_bytes: usize = max_response_size * 1_000_000;
 
     if serialized_response.total_len() > max_response_size_in_bytes {
-        let err_response = RpcError::LimitExceeded(format!(
+        let err = RpcError::LimitExceeded(format!(
             "Response size larger than {} MB",
             max_response_size
-        ))
-        .to_response(&0);
+        ));
 
-        parallel_serialize(vec![err_response], batch_flag)
+        let (serialized_err, _) = parallel_serialize(vec![err.to_response(&0)], batch_flag);
+        (serialized_err, Err(err))
     } else {
-        serialized_response
+        (serialized_response, Ok(sizes))
+    }
+}
+
+/// What the access log needs to know about a call besides its response
+struct CallInfo {
+    request: RpcRequest,
+    backend: Option<Backend>,
+    latency: u64,
+}
+
+/// Builds the access log entries of the responses, sizes are filled in after serialization
+fn access_log_entries(
+    access_log: &AccessLog,
+    calls: &HashMap<i64, CallInfo>,
+    rpc_responses: &[RpcResponse],
+    client: SocketAddr,
+    batch_flag: bool,
+) -> Vec<AccessLogEntry> {
+    let timestamp_ms = access_log::timestamp_ms();
+    let http_request = access_log.next_http_request();
+
+    rpc_responses
+        .iter()
+        .map(|response| {
+            let call = calls.get(&response.id);
+            let (method, (params, redacted)) = match call {
+                Some(call) => (
+                    call.request.method.clone(),
+                    access_log.redact(&call.request.method, &call.request.params),
+                ),
+                None => (String::new(), (serde_json::Value::Null, false)),
+            };
+            let error_code = response.result.as_ref().err().map(|e| e.code);
+
+            AccessLogEntry {
+                timestamp_ms,
+                http_request,
+                batch: batch_flag,
+                client: Some(client.ip()),
+                id: response.id,
+                method,
+                params,
+                redacted,
+                backend: call.and_then(|call| call.backend),
+                latency_us: call.map(|call| call.latency).unwrap_or_default(),
+                status: match error_code {
+                    Some(_) => CallStatus::Error,
+                    None => CallStatus::Ok,
+                },
+                error_code,
+                response_bytes: 0,
+            }
+        })
+        .collect()
+}
+
+fn write_access_log(
+    access_log: &AccessLog,
+    mut entries: Vec<AccessLogEntry>,
+    response_sizes: Result<Vec<usize>, RpcError>,
+) {
+    match response_sizes {
+        Ok(sizes) => {
+            for (entry, size) in entries.iter_mut().zip(sizes) {
+                entry.response_bytes = size;
+            }
+        }
+        Err(err) => {
+            for entry in entries.iter_mut() {
+                entry.status = CallStatus::Error;
+                entry.error_code = Some(err.code().code);
+            }
+        }
+    }
+
+    for entry in entries {
+        access_log.write(entry);
     }
 }
 
@@ -197,11 +463,12 @@ fn deserialize_single_req(request_serialized: serde_json::Value) -> RpcRequestEr
     }
 }
 
-// finds LimitExceeded, JsonRpcVersionNotSupported, DuplicateID errors
+// finds LimitExceeded, JsonRpcVersionNotSupported, DuplicateID and MethodNotFound errors
 fn check_req_fields(
     reqs: Vec<RpcRequestErrorCheck>,
     max_number_of_requests: usize,
     rpc_version: String,
+    methods: &MethodFilter,
 ) -> Vec<RpcRequestErrorCheck> {
     if reqs.len() > max_number_of_requests {
         return vec![RpcRequestErrorCheck {
@@ -230,6 +497,10 @@ fn check_req_fields(
                     } else if req.jsonrpc != rpc_version {
                         request_ids.insert(req.id);
                         Some(RpcError::JsonRpcVersionNotSupported(req.jsonrpc.clone()))
+                    // allow and deny list check
+                    } else if !methods.is_allowed(&req.method) {
+                        request_ids.insert(req.id);
+                        Some(RpcError::MethodNotFound(req.method.clone()))
                     } else {
                         request_ids.insert(req.id);
                         None
@@ -274,3 +545,89 @@ fn group_by_method(reqs: Vec<RpcRequest>) -> HashMap<String, Vec<RpcRequest>> {
 
     reqs_by_method
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(patterns: &[&str]) -> Option<Vec<String>> {
+        Some(patterns.iter().map(|p| p.to_string()).collect())
+    }
+
+    #[test]
+    fn test_method_filter() {
+        let default = MethodFilterConfig {
+            allow: None,
+            deny: patterns(&["admin_*", "debug_*", "trace_*"]),
+        };
+
+        let filter = MethodFilter::new(&default, None);
+        assert!(filter.is_allowed("eth_call"));
+        assert!(!filter.is_allowed("debug_traceTransaction"));
+
+        // route only replaces the deny list, so everything else is allowed
+        let internal = MethodFilterConfig {
+            allow: None,
+            deny: patterns(&["admin_*"]),
+        };
+        let filter = MethodFilter::new(&default, Some(&internal));
+        assert!(filter.is_allowed("debug_traceTransaction"));
+        assert!(!filter.is_allowed("admin_peers"));
+
+        // route only sets an allow list, the default deny list still applies
+        let logs_only = MethodFilterConfig {
+            allow: patterns(&["eth_getLogs", "eth_blockNumber", "trace_*"]),
+            deny: None,
+        };
+        let filter = MethodFilter::new(&default, Some(&logs_only));
+        assert!(filter.is_allowed("eth_getLogs"));
+        assert!(!filter.is_allowed("eth_call"));
+        assert!(!filter.is_allowed("trace_block"));
+    }
+
+    #[test]
+    fn test_route_config_rejects_unknown_fields() {
+        let route: HttpRouteConfig =
+            toml::de::from_str("path = \"/internal\"\ndeny = [\"admin_*\"]").unwrap();
+        assert_eq!(route.methods().deny, patterns(&["admin_*"]));
+
+        // a misspelled list would otherwise leave every method allowed
+        assert!(
+            toml::de::from_str::<HttpRouteConfig>("path = \"/internal\"\ndenied = []").is_err()
+        );
+    }
+
+    #[test]
+    fn test_check_req_fields_method_not_found() {
+        let filter = MethodFilter::new(
+            &MethodFilterConfig {
+                allow: None,
+                deny: patterns(&["debug_*"]),
+            },
+            None,
+        );
+
+        let reqs = ["eth_call", "debug_traceCall"]
+            .iter()
+            .enumerate()
+            .map(|(id, method)| RpcRequestErrorCheck {
+                request: RpcRequest {
+                    id: id as i64,
+                    jsonrpc: "2.0".into(),
+                    method: method.to_string(),
+                    ..Default::default()
+                },
+                error: None,
+            })
+            .collect();
+
+        let checked = check_req_fields(reqs, 10, "2.0".into(), &filter);
+
+        assert_eq!(checked[0].error, None);
+        assert_eq!(
+            checked[1].error,
+            Some(RpcError::MethodNotFound("debug_traceCall".into()))
+        );
+        assert_eq!(checked[1].error.as_ref().unwrap().code().code, -32601);
+    }
+}
@@ -0,0 +1,161 @@
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::eth_rpc::routing::glob_match;
+
+/// A field that has a different value in two json documents
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldDiff {
+    /// Path of the field, e.g. `transactions[3].type`
+    pub path: String,
+    /// None if the field is missing
+    pub left: Option<Value>,
+    pub right: Option<Value>,
+}
+
+impl FieldDiff {
+    /// Path without array indices, e.g. `transactions[].type`, so diffs of all elements of an array
+    /// are grouped together
+    pub fn field(&self) -> String {
+        let mut field = String::with_capacity(self.path.len());
+        let mut in_index = false;
+        for c in self.path.chars() {
+            match c {
+                '[' => {
+                    in_index = true;
+                    field.push(c);
+                }
+                ']' => {
+                    in_index = false;
+                    field.push(c);
+                }
+                _ if in_index => (),
+                _ => field.push(c),
+            }
+        }
+        field
+    }
+}
+
+/// Compares two json documents field by field.
+///
+/// Hex strings are compared case-insensitively and a null field is the same as a missing one.
+/// Fields whose grouped path (see [`FieldDiff::field`]) matches one of the `ignore` glob patterns
+/// are skipped.
+pub fn diff(left: &Value, right: &Value, ignore: &[String]) -> Vec<FieldDiff> {
+    let mut diffs = Vec::new();
+    diff_impl(String::new(), Some(left), Some(right), ignore, &mut diffs);
+    diffs
+}
+
+fn diff_impl(
+    path: String,
+    left: Option<&Value>,
+    right: Option<&Value>,
+    ignore: &[String],
+    diffs: &mut Vec<FieldDiff>,
+) {
+    let left = left.filter(|v| !v.is_null());
+    let right = right.filter(|v| !v.is_null());
+
+    match (left, right) {
+        (Some(Value::Object(l)), Some(Value::Object(r))) => {
+            let mut keys = l.keys().chain(r.keys()).collect::<Vec<_>>();
+            keys.sort();
+            keys.dedup();
+
+            for key in keys {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                diff_impl(path, l.get(key), r.get(key), ignore, diffs);
+            }
+        }
+        (Some(Value::Array(l)), Some(Value::Array(r))) => {
+            for i in 0..l.len().max(r.len()) {
+                diff_impl(
+                    format!("{}[{}]", path, i),
+                    l.get(i),
+                    r.get(i),
+                    ignore,
+                    diffs,
+                );
+            }
+        }
+        (l, r) if values_eq(l, r) => (),
+        (l, r) => {
+            let diff = FieldDiff {
+                path,
+                left: l.cloned(),
+                right: r.cloned(),
+            };
+            let field = diff.field();
+            if !ignore.iter().any(|pattern| glob_match(pattern, &field)) {
+                diffs.push(diff);
+            }
+        }
+    }
+}
+
+fn values_eq(left: Option<&Value>, right: Option<&Value>) -> bool {
+    match (left, right) {
+        (Some(Value::String(l)), Some(Value::String(r))) if l.starts_with("0x") => {
+            l.eq_ignore_ascii_case(r)
+        }
+        (l, r) => l == r,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let left = json!({
+            "hash": "0xABCD",
+            "to": null,
+            "transactions": [
+                {"type": "0x2", "logIndex": "0x1"},
+                {"type": "0x0", "logIndex": "0x2"},
+            ],
+        });
+        let right = json!({
+            "hash": "0xabcd",
+            "transactions": [
+                {"logIndex": "0x1"},
+                {"type": "0x0", "logIndex": "0x3"},
+                {"type": "0x0"},
+            ],
+            "extra": "0x1",
+        });
+
+        let diffs = diff(&left, &right, &["extra".to_owned()]);
+        assert_eq!(
+            diffs,
+            vec![
+                FieldDiff {
+                    path: "transactions[0].type".to_owned(),
+                    left: Some(json!("0x2")),
+                    right: None,
+                },
+                FieldDiff {
+                    path: "transactions[1].logIndex".to_owned(),
+                    left: Some(json!("0x2")),
+                    right: Some(json!("0x3")),
+                },
+                FieldDiff {
+                    path: "transactions[2]".to_owned(),
+                    left: None,
+                    right: Some(json!({"type": "0x0"})),
+                },
+            ]
+        );
+        assert_eq!(diffs[0].field(), "transactions[].type");
+        assert_eq!(diffs[2].field(), "transactions[]");
+    }
+}
@@ -1,13 +1,24 @@
+mod access_log;
 mod args;
+mod broadcast;
 mod bytes_builder;
 mod config;
+mod diff;
 mod eth_rpc;
+mod health;
 mod http_server;
+mod json_diff;
+mod metrics;
+mod replay;
 mod rpc_client;
 mod runner;
+mod single_flight;
+mod telemetry;
+mod types;
+mod verify;
 pub use args::Args;
 pub use runner::Runner;
 mod query_handler;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 struct BlockRange(u64, u64);
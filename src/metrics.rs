@@ -0,0 +1,279 @@
+use std::future::Future;
+use std::result::Result as StdResult;
+use std::sync::LazyLock;
+
+use anyhow::{Context, Result};
+use tracing::Instrument;
+
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge_vec, Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec,
+    IntGaugeVec, TextEncoder,
+};
+
+pub static HTTP_REQUESTS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("http_requests_total", "Number of json-rpc http requests").unwrap()
+});
+
+pub static HTTP_REQUEST_DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "http_request_duration_seconds",
+        "Time spent on a json-rpc http request, including serialization of the response"
+    )
+    .unwrap()
+});
+
+pub static HTTP_RESPONSE_BYTES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "http_response_bytes_total",
+        "Number of response body bytes served"
+    )
+    .unwrap()
+});
+
+pub static RPC_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "rpc_requests_total",
+        "Number of json-rpc requests by the backend that served them",
+        &["method", "backend"]
+    )
+    .unwrap()
+});
+
+pub static RPC_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "rpc_errors_total",
+        "Number of json-rpc requests that were answered with an error by the proxy",
+        &["method", "backend", "code"]
+    )
+    .unwrap()
+});
+
+pub static RPC_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "rpc_request_duration_seconds",
+        "Time spent executing all requests of a method in a batch",
+        &["method", "backend"]
+    )
+    .unwrap()
+});
+
+pub static HYPERSYNC_QUERIES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "hypersync_queries_total",
+        "Number of queries sent to HyperSync",
+        &["query"]
+    )
+    .unwrap()
+});
+
+pub static HYPERSYNC_QUERY_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "hypersync_query_errors_total",
+        "Number of HyperSync queries that failed",
+        &["query"]
+    )
+    .unwrap()
+});
+
+pub static HYPERSYNC_QUERY_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "hypersync_query_duration_seconds",
+        "Time spent waiting for the response of a HyperSync query",
+        &["query"]
+    )
+    .unwrap()
+});
+
+pub static UPSTREAM_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "upstream_requests_total",
+        "Number of http requests sent to upstream rpc endpoints",
+        &["endpoint"]
+    )
+    .unwrap()
+});
+
+pub static UPSTREAM_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "upstream_errors_total",
+        "Number of errors returned by the rpc client, by kind",
+        &["endpoint", "error"]
+    )
+    .unwrap()
+});
+
+pub static UPSTREAM_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "upstream_request_duration_seconds",
+        "Time spent on an http request to an upstream rpc endpoint",
+        &["endpoint"]
+    )
+    .unwrap()
+});
+
+pub static SHADOW_COMPARISONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "shadow_comparisons_total",
+        "Number of HyperSync responses that were compared with the fallback",
+        &["method"]
+    )
+    .unwrap()
+});
+
+pub static SHADOW_MISMATCHES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "shadow_mismatches_total",
+        "Number of HyperSync responses that differ from the fallback",
+        &["method"]
+    )
+    .unwrap()
+});
+
+pub static SHADOW_FIELD_MISMATCHES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "shadow_field_mismatches_total",
+        "Number of mismatched responses in which a field differs from the fallback",
+        &["method", "field"]
+    )
+    .unwrap()
+});
+
+pub static SHADOW_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "shadow_errors_total",
+        "Number of shadow comparisons that failed because the fallback didn't respond",
+        &["method"]
+    )
+    .unwrap()
+});
+
+pub static VERIFIED_RESPONSES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "verified_responses_total",
+        "Number of HyperSync responses checked against the fallback block headers by result",
+        &["method", "result"]
+    )
+    .unwrap()
+});
+
+pub static UNLINKED_BLOCKS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "unlinked_blocks_total",
+        "Number of blocks from HyperSync that don't chain to the blocks next to them"
+    )
+    .unwrap()
+});
+
+pub static CACHE_HITS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "cache_hits_total",
+        "Number of blocks and log ranges that were served from the cache",
+        &["kind", "layer"]
+    )
+    .unwrap()
+});
+
+pub static CACHE_MISSES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "cache_misses_total",
+        "Number of blocks and log ranges that weren't in the cache",
+        &["kind"]
+    )
+    .unwrap()
+});
+
+pub static CACHE_SIZE_BYTES: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "cache_size_bytes",
+        "Approximate memory, or disk space, used by the data in the cache",
+        &["layer"]
+    )
+    .unwrap()
+});
+
+pub static COALESCED_CALLS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "coalesced_calls_total",
+        "Number of HyperSync queries and upstream calls that shared the result of an identical call in flight",
+        &["layer"]
+    )
+    .unwrap()
+});
+
+pub static MICRO_BATCH_REQUESTS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "micro_batch_requests",
+        "Number of http requests whose blocks were fetched together in a micro-batch",
+        &["kind"],
+        vec![1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0]
+    )
+    .unwrap()
+});
+
+pub static REORGS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "reorgs_total",
+        "Number of reorgs detected at the head of HyperSync"
+    )
+    .unwrap()
+});
+
+/// Records the count, duration and failures of a HyperSync query and traces it in a span
+pub async fn time_hypersync_query<T, E>(
+    query: &str,
+    fut: impl Future<Output = StdResult<T, E>>,
+) -> StdResult<T, E> {
+    HYPERSYNC_QUERIES.with_label_values(&[query]).inc();
+    let timer = HYPERSYNC_QUERY_DURATION
+        .with_label_values(&[query])
+        .start_timer();
+
+    let res = fut
+        .instrument(tracing::info_span!("hypersync_query", query))
+        .await;
+
+    timer.observe_duration();
+    if res.is_err() {
+        HYPERSYNC_QUERY_ERRORS.with_label_values(&[query]).inc();
+    }
+
+    res
+}
+
+/// Encodes all registered metrics in the prometheus text format
+pub fn encode() -> Result<String> {
+    let mut buf = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buf)
+        .context("encode metrics")?;
+
+    String::from_utf8(buf).context("metrics are not utf8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_time_hypersync_query() {
+        let ok: StdResult<(), ()> = time_hypersync_query("test_query", async { Ok(()) }).await;
+        assert!(ok.is_ok());
+        let err: StdResult<(), ()> = time_hypersync_query("test_query", async { Err(()) }).await;
+        assert!(err.is_err());
+
+        assert_eq!(
+            HYPERSYNC_QUERIES.with_label_values(&["test_query"]).get(),
+            2
+        );
+        assert_eq!(
+            HYPERSYNC_QUERY_ERRORS
+                .with_label_values(&["test_query"])
+                .get(),
+            1
+        );
+        assert!(encode()
+            .unwrap()
+            .contains(r#"hypersync_queries_total{query="test_query"} 2"#));
+    }
+}
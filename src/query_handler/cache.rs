@@ -0,0 +1,464 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash as StdHash;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use skar_format::{Block, Hash, Log, Transaction, TransactionReceipt};
+
+use crate::config::CacheConfig;
+use crate::metrics;
+
+use super::disk_cache::DiskCache;
+
+/// Data of a block, or logs of a block range, that is kept in the cache
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CacheKey {
+    Block(u64),
+    BlockWithTxs(u64),
+    Receipts(u64),
+    /// Logs matching the selection in a segment of blocks, the block range is inclusive
+    Logs {
+        selection: String,
+        from_block: u64,
+        to_block: u64,
+    },
+}
+
+impl CacheKey {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Block(_) => "block",
+            Self::BlockWithTxs(_) => "block_with_txs",
+            Self::Receipts(_) => "receipts",
+            Self::Logs { .. } => "logs",
+        }
+    }
+
+    /// Last block the data of the entry comes from
+    pub fn last_block(&self) -> u64 {
+        match self {
+            Self::Block(block_number)
+            | Self::BlockWithTxs(block_number)
+            | Self::Receipts(block_number) => *block_number,
+            Self::Logs { to_block, .. } => *to_block,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum CacheValue {
+    Block(Arc<Block<Hash>>),
+    BlockWithTxs(Arc<Block<Transaction>>),
+    Receipts(Arc<Vec<TransactionReceipt>>),
+    Logs(Arc<Vec<Log>>),
+}
+
+impl CacheValue {
+    /// Approximate memory used by the value
+    fn size(&self) -> usize {
+        match self {
+            Self::Block(block) => header_size(block) + block.transactions.len() * 32,
+            Self::BlockWithTxs(block) => {
+                header_size(block)
+                    + block
+                        .transactions
+                        .iter()
+                        .map(|tx| TX_SIZE + tx.input.as_ref().len())
+                        .sum::<usize>()
+            }
+            Self::Receipts(receipts) => receipts
+                .iter()
+                .map(|receipt| RECEIPT_SIZE + logs_size(&receipt.logs))
+                .sum(),
+            Self::Logs(logs) => logs_size(logs),
+        }
+    }
+}
+
+const HEADER_SIZE: usize = 800;
+const TX_SIZE: usize = 400;
+const RECEIPT_SIZE: usize = 500;
+const LOG_SIZE: usize = 250;
+
+fn header_size<Tx>(block: &Block<Tx>) -> usize {
+    HEADER_SIZE + block.header.extra_data.as_ref().len()
+}
+
+fn logs_size(logs: &[Log]) -> usize {
+    logs.iter()
+        .map(|log| LOG_SIZE + log.data.as_ref().len())
+        .sum()
+}
+
+/// Least recently used cache of finalized data with a limit on the approximate memory it uses.
+///
+/// Only data of blocks that are at least `confirmations` blocks below the HyperSync height is
+/// stored, so a cached entry never has to be invalidated. If a disk cache is configured, entries
+/// are written to it as well and entries that were evicted from memory are read back from it.
+pub struct Cache {
+    lru: Mutex<Lru<CacheKey, CacheValue>>,
+    max_bytes: u64,
+    confirmations: u64,
+    log_segment_size: u64,
+    disk: Option<Arc<DiskCache>>,
+    /// HyperSync height seen in the last query response
+    archive_height: Mutex<Option<u64>>,
+}
+
+impl Cache {
+    /// The disk cache, if there is one, is opened in the directory of the chain
+    pub fn new(cfg: CacheConfig, chain_id: u64) -> Result<Self> {
+        let disk = match cfg.disk {
+            Some(disk_cfg) => Some(Arc::new(
+                DiskCache::open(disk_cfg, chain_id).context("open disk cache")?,
+            )),
+            None => None,
+        };
+
+        Ok(Self {
+            lru: Mutex::new(Lru::default()),
+            max_bytes: (cfg.memory_size_mb as u64).saturating_mul(1024 * 1024),
+            confirmations: cfg.confirmations,
+            log_segment_size: cfg.log_segment_size.max(1),
+            disk,
+            archive_height: Mutex::new(None),
+        })
+    }
+
+    /// Inclusive ranges of the log segments that the inclusive block range overlaps with.
+    ///
+    /// Segments are aligned to multiples of the segment size so overlapping ranges share them.
+    pub fn log_segments(&self, from_block: u64, to_block: u64) -> Vec<(u64, u64)> {
+        let size = self.log_segment_size;
+        (from_block / size..=to_block / size)
+            .map(|segment| (segment * size, segment * size + size - 1))
+            .collect()
+    }
+
+    pub fn archive_height(&self) -> Option<u64> {
+        *self.archive_height.lock().unwrap()
+    }
+
+    pub fn set_archive_height(&self, height: u64) {
+        *self.archive_height.lock().unwrap() = Some(height);
+    }
+
+    /// Returns true if the data of the block can be cached
+    pub fn is_final(&self, block_number: u64, archive_height: Option<u64>) -> bool {
+        archive_height
+            .is_some_and(|height| block_number.saturating_add(self.confirmations) <= height)
+    }
+
+    pub async fn get(&self, key: &CacheKey) -> Option<CacheValue> {
+        let value = self.lru.lock().unwrap().get(key);
+        if value.is_some() {
+            count_hits(key.kind(), "memory", 1);
+            return value;
+        }
+
+        let value = self
+            .read_disk(vec![key.clone()])
+            .await
+            .pop()
+            .and_then(|(_, value)| value);
+        match value {
+            Some(_) => count_hits(key.kind(), "disk", 1),
+            None => count_misses(key.kind(), 1),
+        }
+        value
+    }
+
+    /// Looks up the entries of all blocks in the range, returns None if any of them is missing
+    pub async fn get_range<T>(
+        &self,
+        from_block: u64,
+        to_block: u64,
+        key: impl Fn(u64) -> CacheKey,
+        value: impl Fn(CacheValue) -> Option<T>,
+    ) -> Option<Vec<(u64, T)>> {
+        let kind = key(from_block).kind();
+
+        let mut cached = {
+            let mut lru = self.lru.lock().unwrap();
+            (from_block..to_block)
+                .map(|block_number| (block_number, lru.get(&key(block_number))))
+                .collect::<Vec<_>>()
+        };
+
+        let not_in_memory = cached
+            .iter()
+            .filter(|(_, data)| data.is_none())
+            .map(|(block_number, _)| key(*block_number))
+            .collect::<Vec<_>>();
+        let num_in_memory = cached.len() - not_in_memory.len();
+
+        let mut num_on_disk = 0;
+        if !not_in_memory.is_empty() {
+            let mut from_disk = self.read_disk(not_in_memory).await.into_iter();
+            for (_, data) in cached.iter_mut().filter(|(_, data)| data.is_none()) {
+                *data = from_disk.next().and_then(|(_, value)| value);
+                num_on_disk += usize::from(data.is_some());
+            }
+        }
+
+        let num_missing = cached.len() - num_in_memory - num_on_disk;
+        if num_missing > 0 {
+            count_misses(kind, num_missing as u64);
+            return None;
+        }
+
+        count_hits(kind, "memory", num_in_memory as u64);
+        count_hits(kind, "disk", num_on_disk as u64);
+        cached
+            .into_iter()
+            .map(|(block_number, data)| data.and_then(&value).map(|data| (block_number, data)))
+            .collect()
+    }
+
+    /// Adds the entry to the memory cache, and writes it to the disk cache in the background
+    pub fn insert(&self, key: CacheKey, value: CacheValue) {
+        if let Some(disk) = &self.disk {
+            let disk = disk.clone();
+            let (key, value) = (key.clone(), value.clone());
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = disk.write(&key, &value) {
+                    log::warn!("failed to write {} to disk cache: {:?}", key.kind(), e);
+                }
+            });
+        }
+
+        self.insert_memory(key, value);
+    }
+
+    /// Drops the entries of the block and the blocks after it, used when a reorg replaced them
+    pub fn invalidate_from(&self, block_number: u64) {
+        {
+            let mut lru = self.lru.lock().unwrap();
+            let removed = lru.remove_where(|key| key.last_block() >= block_number);
+            if !removed.is_empty() {
+                log::info!(
+                    "dropped {} cache entries of blocks from {}",
+                    removed.len(),
+                    block_number
+                );
+            }
+            metrics::CACHE_SIZE_BYTES
+                .with_label_values(&["memory"])
+                .set(lru.bytes as i64);
+        }
+
+        if let Some(disk) = &self.disk {
+            let disk = disk.clone();
+            tokio::task::spawn_blocking(move || disk.invalidate_from(block_number));
+        }
+    }
+
+    fn insert_memory(&self, key: CacheKey, value: CacheValue) {
+        let size = value.size() as u64;
+        if size > self.max_bytes {
+            return;
+        }
+
+        let mut lru = self.lru.lock().unwrap();
+        lru.insert(key, value, size, self.max_bytes);
+        metrics::CACHE_SIZE_BYTES
+            .with_label_values(&["memory"])
+            .set(lru.bytes as i64);
+    }
+
+    /// Reads the entries from the disk cache and moves the ones that were found to memory
+    async fn read_disk(&self, keys: Vec<CacheKey>) -> Vec<(CacheKey, Option<CacheValue>)> {
+        let disk = match &self.disk {
+            Some(disk) => disk.clone(),
+            None => return keys.into_iter().map(|key| (key, None)).collect(),
+        };
+
+        let entries = tokio::task::spawn_blocking(move || {
+            keys.into_iter()
+                .map(|key| {
+                    let value = disk.read(&key);
+                    (key, value)
+                })
+                .collect::<Vec<_>>()
+        })
+        .await
+        .unwrap();
+
+        for (key, value) in entries.iter() {
+            if let Some(value) = value {
+                self.insert_memory(key.clone(), value.clone());
+            }
+        }
+
+        entries
+    }
+}
+
+fn count_hits(kind: &str, layer: &str, count: u64) {
+    if count > 0 {
+        metrics::CACHE_HITS
+            .with_label_values(&[kind, layer])
+            .inc_by(count);
+    }
+}
+
+fn count_misses(kind: &str, count: u64) {
+    metrics::CACHE_MISSES
+        .with_label_values(&[kind])
+        .inc_by(count);
+}
+
+/// Entries ordered by the time they were last used, with a limit on their total size
+pub(super) struct Lru<K, V> {
+    entries: HashMap<K, Entry<V>>,
+    /// Keys by the time they were last used, oldest first
+    order: BTreeMap<u64, K>,
+    next_tick: u64,
+    pub bytes: u64,
+}
+
+impl<K, V> Default for Lru<K, V> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            next_tick: 0,
+            bytes: 0,
+        }
+    }
+}
+
+struct Entry<V> {
+    value: V,
+    size: u64,
+    tick: u64,
+}
+
+impl<K: Clone + Eq + StdHash, V: Clone> Lru<K, V> {
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        let entry = self.entries.get_mut(key)?;
+
+        self.order.remove(&entry.tick);
+        entry.tick = self.next_tick;
+        self.next_tick += 1;
+        self.order.insert(entry.tick, key.clone());
+
+        Some(entry.value.clone())
+    }
+
+    /// Inserts the entry and returns the keys that were evicted to make room for it
+    pub fn insert(&mut self, key: K, value: V, size: u64, max_bytes: u64) -> Vec<K> {
+        self.remove(&key);
+
+        let mut evicted = Vec::new();
+        while self.bytes + size > max_bytes {
+            let oldest = match self.order.first_key_value() {
+                Some((_, key)) => key.clone(),
+                None => break,
+            };
+            self.remove(&oldest);
+            evicted.push(oldest);
+        }
+
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        self.order.insert(tick, key.clone());
+        self.entries.insert(key, Entry { value, size, tick });
+        self.bytes += size;
+
+        evicted
+    }
+
+    pub fn remove(&mut self, key: &K) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.tick);
+            self.bytes -= entry.size;
+        }
+    }
+
+    /// Removes the entries whose key matches and returns their keys
+    pub fn remove_where(&mut self, f: impl Fn(&K) -> bool) -> Vec<K> {
+        let keys = self
+            .entries
+            .keys()
+            .filter(|key| f(key))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for key in keys.iter() {
+            self.remove(key);
+        }
+
+        keys
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logs(num_logs: usize) -> CacheValue {
+        CacheValue::Logs(Arc::new(vec![Log::default(); num_logs]))
+    }
+
+    fn logs_key(from_block: u64) -> CacheKey {
+        CacheKey::Logs {
+            selection: String::new(),
+            from_block,
+            to_block: from_block,
+        }
+    }
+
+    #[test]
+    fn test_lru() {
+        let mut lru = Lru::default();
+        let size = LOG_SIZE as u64;
+        let max_bytes = 3 * size;
+
+        lru.insert(logs_key(1), logs(1), size, max_bytes);
+        lru.insert(logs_key(2), logs(1), size, max_bytes);
+        lru.insert(logs_key(3), logs(1), size, max_bytes);
+        assert_eq!(lru.bytes, 3 * size);
+
+        // 1 becomes the most recently used so 2 is evicted
+        assert!(lru.get(&logs_key(1)).is_some());
+        let evicted = lru.insert(logs_key(4), logs(1), size, max_bytes);
+        assert_eq!(evicted, vec![logs_key(2)]);
+        assert!(lru.get(&logs_key(2)).is_none());
+        assert!(lru.get(&logs_key(3)).is_some());
+
+        // evicts as many entries as needed to make room
+        let evicted = lru.insert(logs_key(5), logs(2), 2 * size, max_bytes);
+        assert_eq!(evicted, vec![logs_key(1), logs_key(4)]);
+        assert_eq!(lru.len(), 2);
+        assert!(lru.get(&logs_key(3)).is_some());
+        assert!(lru.get(&logs_key(5)).is_some());
+        assert_eq!(lru.bytes, 3 * size);
+        assert_eq!(lru.order.len(), 2);
+    }
+
+    #[test]
+    fn test_is_final() {
+        let cache = Cache::new(
+            CacheConfig {
+                memory_size_mb: 1,
+                confirmations: 10,
+                log_segment_size: 100,
+                disk: None,
+            },
+            1,
+        )
+        .unwrap();
+
+        assert!(cache.is_final(90, Some(100)));
+        assert!(!cache.is_final(91, Some(100)));
+        assert!(!cache.is_final(0, None));
+
+        assert_eq!(cache.log_segments(0, 99), vec![(0, 99)]);
+        assert_eq!(cache.log_segments(150, 200), vec![(100, 199), (200, 299)]);
+    }
+}
@@ -0,0 +1,302 @@
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+
+use crate::config::DiskCacheConfig;
+use crate::metrics;
+use crate::verify::keccak256;
+
+use super::cache::{CacheKey, CacheValue, Lru};
+
+/// Number of blocks whose files are kept in the same directory
+const BLOCKS_PER_DIR: u64 = 10_000;
+
+/// Cache entries stored as JSON files, one file per entry.
+///
+/// The files that are in the directory are indexed when the cache is opened, ordered by the time
+/// they were last read, so the least recently used entries are deleted first when the size limit
+/// is reached, also across restarts.
+pub struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    index: Mutex<Lru<PathBuf, ()>>,
+}
+
+impl DiskCache {
+    pub fn open(cfg: DiskCacheConfig, chain_id: u64) -> Result<Self> {
+        let dir = cfg.path.join(chain_id.to_string());
+        fs::create_dir_all(&dir).context("create cache directory")?;
+
+        let mut files = Vec::new();
+        scan_dir(&dir, &mut files).context("scan cache directory")?;
+        files.sort_by_key(|(_, _, used)| *used);
+
+        let cache = Self {
+            dir,
+            max_bytes: cfg.max_size_mb.saturating_mul(1024 * 1024),
+            index: Mutex::new(Lru::default()),
+        };
+
+        for (path, size, _) in files {
+            cache.add_to_index(path, size);
+        }
+
+        let index = cache.index.lock().unwrap();
+        log::info!(
+            "opened disk cache at {} with {} entries ({} bytes)",
+            cache.dir.display(),
+            index.len(),
+            index.bytes
+        );
+        drop(index);
+
+        Ok(cache)
+    }
+
+    /// Returns None if the entry isn't cached or its file can't be read
+    pub fn read(&self, key: &CacheKey) -> Option<CacheValue> {
+        let path = self.dir.join(relative_path(key));
+        self.index.lock().unwrap().get(&path)?;
+
+        let value = fs::read(&path)
+            .context("read file")
+            .and_then(|data| decode(key, &data));
+
+        match value {
+            Ok(value) => {
+                // the modification time is the last use of the entry when the cache is reopened
+                if let Err(e) = File::options()
+                    .write(true)
+                    .open(&path)
+                    .and_then(|file| file.set_modified(SystemTime::now()))
+                {
+                    log::debug!("failed to touch {}: {}", path.display(), e);
+                }
+                Some(value)
+            }
+            Err(e) => {
+                log::warn!("dropping cache entry {}: {:?}", path.display(), e);
+                self.index.lock().unwrap().remove(&path);
+                let _ = fs::remove_file(&path);
+                None
+            }
+        }
+    }
+
+    pub fn write(&self, key: &CacheKey, value: &CacheValue) -> Result<()> {
+        let data = encode(value).context("encode entry")?;
+        let size = data.len() as u64;
+        if size > self.max_bytes {
+            return Ok(());
+        }
+
+        let path = self.dir.join(relative_path(key));
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context("create directory")?;
+        }
+
+        // written to a temporary file first so a crash never leaves a partial entry behind
+        let tmp_path = path.with_extension(format!("tmp{}", rand::random::<u32>()));
+        fs::write(&tmp_path, &data).context("write temporary file")?;
+        fs::rename(&tmp_path, &path).context("rename temporary file")?;
+
+        self.add_to_index(path, size);
+
+        Ok(())
+    }
+
+    /// Deletes the entries of the block and the blocks after it
+    pub fn invalidate_from(&self, block_number: u64) {
+        let removed = {
+            let mut index = self.index.lock().unwrap();
+            let removed = index.remove_where(|path| {
+                last_block(path).is_some_and(|last_block| last_block >= block_number)
+            });
+            metrics::CACHE_SIZE_BYTES
+                .with_label_values(&["disk"])
+                .set(index.bytes as i64);
+            removed
+        };
+
+        for path in removed {
+            if let Err(e) = fs::remove_file(&path) {
+                log::debug!("failed to remove {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    fn add_to_index(&self, path: PathBuf, size: u64) {
+        let evicted = {
+            let mut index = self.index.lock().unwrap();
+            let evicted = index.insert(path, (), size, self.max_bytes);
+            metrics::CACHE_SIZE_BYTES
+                .with_label_values(&["disk"])
+                .set(index.bytes as i64);
+            evicted
+        };
+
+        for path in evicted {
+            if let Err(e) = fs::remove_file(&path) {
+                log::debug!("failed to remove {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Collects the entries in the directory with their size and modification time, temporary files
+/// that were left behind are deleted
+fn scan_dir(dir: &Path, files: &mut Vec<(PathBuf, u64, SystemTime)>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let meta = entry.metadata()?;
+
+        if meta.is_dir() {
+            scan_dir(&path, files)?;
+        } else if path.extension().is_some_and(|ext| ext == "json") {
+            files.push((path, meta.len(), meta.modified()?));
+        } else {
+            fs::remove_file(&path).with_context(|| format!("remove {}", path.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Path of the file of the entry, relative to the directory of the chain
+fn relative_path(key: &CacheKey) -> PathBuf {
+    let (block_number, name) = match key {
+        CacheKey::Block(block_number)
+        | CacheKey::BlockWithTxs(block_number)
+        | CacheKey::Receipts(block_number) => (*block_number, block_number.to_string()),
+        CacheKey::Logs {
+            selection,
+            from_block,
+            to_block,
+        } => {
+            let selection_hash = keccak256(selection.as_bytes());
+            let selection_id = u64::from_be_bytes(selection_hash[..8].try_into().unwrap());
+            (
+                *from_block,
+                format!("{}-{}-{:016x}", from_block, to_block, selection_id),
+            )
+        }
+    };
+
+    Path::new(key.kind())
+        .join((block_number / BLOCKS_PER_DIR).to_string())
+        .join(format!("{}.json", name))
+}
+
+/// Last block of the entry in the file, parsed from the name that `relative_path` gave it
+fn last_block(path: &Path) -> Option<u64> {
+    let name = path.file_stem()?.to_str()?;
+    let mut parts = name.split('-');
+    let first = parts.next()?;
+
+    // logs are named by the first and the last block of their range
+    parts.next().unwrap_or(first).parse().ok()
+}
+
+fn encode(value: &CacheValue) -> Result<Vec<u8>> {
+    let data = match value {
+        CacheValue::Block(block) => serde_json::to_vec(block.as_ref()),
+        CacheValue::BlockWithTxs(block) => serde_json::to_vec(block.as_ref()),
+        CacheValue::Receipts(receipts) => serde_json::to_vec(receipts.as_ref()),
+        CacheValue::Logs(logs) => serde_json::to_vec(logs.as_ref()),
+    }?;
+
+    Ok(data)
+}
+
+fn decode(key: &CacheKey, data: &[u8]) -> Result<CacheValue> {
+    let value = match key {
+        CacheKey::Block(_) => CacheValue::Block(Arc::new(serde_json::from_slice(data)?)),
+        CacheKey::BlockWithTxs(_) => {
+            CacheValue::BlockWithTxs(Arc::new(serde_json::from_slice(data)?))
+        }
+        CacheKey::Receipts(_) => CacheValue::Receipts(Arc::new(serde_json::from_slice(data)?)),
+        CacheKey::Logs { .. } => CacheValue::Logs(Arc::new(serde_json::from_slice(data)?)),
+    };
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use skar_format::{Block, BlockHeader, Hash};
+
+    use super::*;
+
+    fn block(number: u64) -> CacheValue {
+        CacheValue::Block(Arc::new(Block {
+            header: BlockHeader {
+                number: number.into(),
+                hash: Hash::from([number as u8; 32]),
+                ..Default::default()
+            },
+            transactions: vec![Hash::from([0xaa; 32])],
+        }))
+    }
+
+    #[test]
+    fn test_disk_cache() {
+        let path = std::env::temp_dir().join(format!("disk-cache-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+
+        let entry_size = encode(&block(1)).unwrap().len() as u64;
+        let open = || {
+            let cache = DiskCache::open(
+                DiskCacheConfig {
+                    path: path.clone(),
+                    max_size_mb: 1,
+                },
+                1,
+            )
+            .unwrap();
+            // room for two blocks
+            DiskCache {
+                max_bytes: entry_size * 2,
+                ..cache
+            }
+        };
+
+        let cache = open();
+        cache.write(&CacheKey::Block(1), &block(1)).unwrap();
+        cache.write(&CacheKey::Block(2), &block(2)).unwrap();
+        match cache.read(&CacheKey::Block(1)) {
+            Some(CacheValue::Block(cached)) => assert_eq!(u64::from(cached.header.number), 1),
+            _ => panic!("block 1 should be cached"),
+        }
+
+        // 2 is the least recently used one
+        cache.write(&CacheKey::Block(3), &block(3)).unwrap();
+        assert!(cache.read(&CacheKey::Block(2)).is_none());
+        assert!(!path.join("1/block/0/2.json").exists());
+        assert!(cache.read(&CacheKey::BlockWithTxs(1)).is_none());
+        drop(cache);
+
+        // entries are found again after reopening
+        let cache = open();
+        assert!(cache.read(&CacheKey::Block(1)).is_some());
+        assert!(cache.read(&CacheKey::Block(3)).is_some());
+
+        cache.invalidate_from(3);
+        assert!(cache.read(&CacheKey::Block(1)).is_some());
+        assert!(cache.read(&CacheKey::Block(3)).is_none());
+        assert!(!path.join("1/block/0/3.json").exists());
+        assert_eq!(
+            last_block(&relative_path(&CacheKey::Logs {
+                selection: String::new(),
+                from_block: 10,
+                to_block: 19
+            })),
+            Some(19)
+        );
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
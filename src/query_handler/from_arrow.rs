@@ -1,6 +1,6 @@
 use std::collections::BTreeMap;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 
 use arrayvec::ArrayVec;
 use arrow2::array::{BinaryArray, BooleanArray, UInt64Array, UInt8Array};
@@ -345,3 +345,23 @@ pub fn batch_to_transactions(
 
     Ok(())
 }
+
+/// Returns the first chain id found in a batch of transactions
+pub fn batch_to_chain_id(batch: ArrowBatch) -> Result<Option<u64>> {
+    let chain_id = batch
+        .column::<BinaryArray<i32>>("chain_id")
+        .context("get column")?;
+
+    chain_id
+        .iter()
+        .flatten()
+        .next()
+        .map(|b| {
+            if b.len() > 8 {
+                return Err(anyhow!("chain id doesn't fit into u64"));
+            }
+            Ok(b.iter()
+                .fold(0u64, |acc, &byte| (acc << 8) | u64::from(byte)))
+        })
+        .transpose()
+}
@@ -1,91 +1,213 @@
 use std::collections::BTreeMap;
+use std::result::Result as StdResult;
+use std::sync::Arc;
+use std::time::Instant;
 
 use anyhow::{anyhow, Context, Result};
 
-use skar_format::{Block, Hash, Transaction, TransactionReceipt};
-use skar_net_types::{FieldSelection, Query, TransactionSelection};
+use skar_format::{Block, Hash, Log, Transaction, TransactionReceipt};
+use skar_net_types::{FieldSelection, LogSelection, Query, TransactionSelection};
 
 use crate::{
+    metrics,
     query_handler::from_arrow::{batch_to_block_headers, batch_to_transactions},
+    single_flight::SingleFlight,
+    types::{elapsed, QueryMetrics},
     BlockRange,
 };
 
-use self::from_arrow::{batch_to_logs, batch_to_receipts};
+use self::cache::{Cache, CacheKey, CacheValue};
+use self::from_arrow::{batch_to_chain_id, batch_to_logs, batch_to_receipts};
 
 pub mod from_arrow;
 
+pub mod cache;
+
+pub mod disk_cache;
+
+/// Returned when HyperSync couldn't reach the end of the requested range in a single query
+#[derive(Debug, Clone, Copy, PartialEq, thiserror::Error)]
+#[error(
+    "HyperSync query timed out, it stopped at block {next_block} before reaching block {to_block}"
+)]
+pub struct QueryTimeout {
+    pub to_block: u64,
+    pub next_block: u64,
+}
+
+type QueryResult = StdResult<skar_client::QueryResponse, Arc<anyhow::Error>>;
+
+/// Part of a log request that isn't covered by cached segments
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UncoveredLogs {
+    /// Inclusive range of the request that isn't covered
+    pub range: BlockRange,
+    /// `range` widened to the bounds of the final segments at its ends, so they can be cached
+    pub fetch_range: BlockRange,
+}
+
 #[derive(Clone)]
 pub struct QueryHandler {
     client: skar_client::Client,
+    cache: Option<Arc<Cache>>,
+    in_flight: Arc<SingleFlight<String, QueryResult>>,
 }
 
 impl QueryHandler {
-    pub fn new(client: skar_client::Client) -> Self {
-        Self { client }
+    pub fn new(client: skar_client::Client, cache: Option<Arc<Cache>>) -> Self {
+        Self {
+            client,
+            cache,
+            in_flight: Arc::new(SingleFlight::new("hypersync")),
+        }
     }
 
-    pub async fn get_blocks(&self, block_range: BlockRange) -> Result<BTreeMap<u64, Block<Hash>>> {
+    /// Sends the query to HyperSync, identical queries that are in flight share a single call
+    async fn send_query(&self, name: &str, query: &Query) -> Result<skar_client::QueryResponse> {
+        let key = serde_json::to_string(query).context("serialize query")?;
+
         let res = self
-            .client
-            .send::<skar_client::ArrowIpc>(&Query {
-                from_block: block_range.0,
-                to_block: Some(block_range.1),
-                include_all_blocks: true,
-                field_selection: FieldSelection {
-                    block: skar_schema::block_header()
-                        .fields
-                        .iter()
-                        .map(|f| f.name.clone())
-                        .collect(),
+            .in_flight
+            .run(key, async {
+                metrics::time_hypersync_query(
+                    name,
+                    self.client.send::<skar_client::ArrowIpc>(query),
+                )
+                .await
+                .map_err(Arc::new)
+            })
+            .await
+            .map_err(|e| anyhow!("{:#}", e))?;
+
+        if let (Some(cache), Some(height)) = (&self.cache, res.archive_height) {
+            cache.set_archive_height(height);
+        }
+
+        Ok(res)
+    }
+
+    pub async fn get_blocks(
+        &self,
+        block_range: BlockRange,
+    ) -> Result<(BTreeMap<u64, Block<Hash>>, QueryMetrics)> {
+        if let Some(blocks) = self
+            .cached_blocks(block_range, CacheKey::Block, |value| match value {
+                CacheValue::Block(block) => Some(block),
+                _ => None,
+            })
+            .await
+        {
+            return Ok((blocks, QueryMetrics::default()));
+        }
+
+        let start = Instant::now();
+        let res = self
+            .send_query(
+                "get_blocks",
+                &Query {
+                    from_block: block_range.0,
+                    to_block: Some(block_range.1),
+                    include_all_blocks: true,
+                    field_selection: FieldSelection {
+                        block: skar_schema::block_header()
+                            .fields
+                            .iter()
+                            .map(|f| f.name.clone())
+                            .collect(),
+                        ..Default::default()
+                    },
                     ..Default::default()
                 },
-                ..Default::default()
-            })
+            )
             .await
             .context("run skar query")?;
 
+        let mut query_metrics = QueryMetrics {
+            skar_wait_time: elapsed(&start),
+            rows_fetched: count_rows(&res.data),
+            ..Default::default()
+        };
+
         if res.next_block != block_range.1 {
-            return Err(anyhow!("Request took too long to handle"));
+            return Err(QueryTimeout {
+                to_block: block_range.1,
+                next_block: res.next_block,
+            }
+            .into());
         }
 
+        let start = Instant::now();
         let mut blocks = BTreeMap::new();
 
         for batch in res.data.blocks {
             batch_to_block_headers(batch, &mut blocks).context("batch to blocks")?;
         }
 
-        Ok(blocks)
+        query_metrics.arrow_decode_time += elapsed(&start);
+
+        self.cache_blocks(
+            res.archive_height,
+            &blocks,
+            CacheKey::Block,
+            CacheValue::Block,
+        );
+
+        Ok((blocks, query_metrics))
     }
 
     pub async fn get_blocks_with_transactions(
         &self,
         block_range: BlockRange,
-    ) -> Result<BTreeMap<u64, Block<Transaction>>> {
+    ) -> Result<(BTreeMap<u64, Block<Transaction>>, QueryMetrics)> {
+        if let Some(blocks) = self
+            .cached_blocks(block_range, CacheKey::BlockWithTxs, |value| match value {
+                CacheValue::BlockWithTxs(block) => Some(block),
+                _ => None,
+            })
+            .await
+        {
+            return Ok((blocks, QueryMetrics::default()));
+        }
+
+        let start = Instant::now();
         let res = self
-            .client
-            .send::<skar_client::ArrowIpc>(&Query {
-                from_block: block_range.0,
-                to_block: Some(block_range.1),
-                include_all_blocks: true,
-                transactions: vec![TransactionSelection::default()],
-                field_selection: FieldSelection {
-                    block: skar_schema::block_header()
-                        .fields
-                        .iter()
-                        .map(|f| f.name.clone())
-                        .collect(),
-                    transaction: TX_FIELDS.iter().map(|&f| f.to_owned()).collect(),
+            .send_query(
+                "get_blocks_with_transactions",
+                &Query {
+                    from_block: block_range.0,
+                    to_block: Some(block_range.1),
+                    include_all_blocks: true,
+                    transactions: vec![TransactionSelection::default()],
+                    field_selection: FieldSelection {
+                        block: skar_schema::block_header()
+                            .fields
+                            .iter()
+                            .map(|f| f.name.clone())
+                            .collect(),
+                        transaction: TX_FIELDS.iter().map(|&f| f.to_owned()).collect(),
+                        ..Default::default()
+                    },
                     ..Default::default()
                 },
-                ..Default::default()
-            })
+            )
             .await
             .context("run skar query")?;
 
+        let mut query_metrics = QueryMetrics {
+            skar_wait_time: elapsed(&start),
+            rows_fetched: count_rows(&res.data),
+            ..Default::default()
+        };
+
         if res.next_block != block_range.1 {
-            return Err(anyhow!("Request took too long to handle"));
+            return Err(QueryTimeout {
+                to_block: block_range.1,
+                next_block: res.next_block,
+            }
+            .into());
         }
 
+        let start = Instant::now();
         let mut blocks = BTreeMap::new();
 
         for batch in res.data.blocks {
@@ -96,38 +218,83 @@ impl QueryHandler {
             batch_to_transactions(batch, &mut blocks).context("batch to transactions")?;
         }
 
-        Ok(blocks)
+        query_metrics.arrow_decode_time += elapsed(&start);
+
+        self.cache_blocks(
+            res.archive_height,
+            &blocks,
+            CacheKey::BlockWithTxs,
+            CacheValue::BlockWithTxs,
+        );
+
+        Ok((blocks, query_metrics))
     }
 
+    /// Receipts of the blocks in the range keyed by block number and transaction index
     pub async fn get_block_receipts(
         &self,
         block_range: BlockRange,
-    ) -> Result<BTreeMap<(u64, u64), TransactionReceipt>> {
+    ) -> Result<(BTreeMap<(u64, u64), TransactionReceipt>, QueryMetrics)> {
+        if let Some(receipts_by_block) = self
+            .cached_blocks(block_range, CacheKey::Receipts, |value| match value {
+                CacheValue::Receipts(receipts) => Some(receipts),
+                _ => None,
+            })
+            .await
+        {
+            let receipts = receipts_by_block
+                .into_values()
+                .flatten()
+                .map(|receipt| {
+                    let key = (
+                        receipt.block_number.into(),
+                        receipt.transaction_index.into(),
+                    );
+                    (key, receipt)
+                })
+                .collect();
+            return Ok((receipts, QueryMetrics::default()));
+        }
+
+        let start = Instant::now();
         let res = self
-            .client
-            .send::<skar_client::ArrowIpc>(&Query {
-                from_block: block_range.0,
-                to_block: Some(block_range.1),
-                include_all_blocks: true,
-                transactions: vec![TransactionSelection::default()],
-                field_selection: FieldSelection {
-                    transaction: RECEIPT_FIELDS.iter().map(|&f| f.to_owned()).collect(),
-                    log: skar_schema::log()
-                        .fields
-                        .iter()
-                        .map(|f| f.name.clone())
-                        .collect(),
+            .send_query(
+                "get_block_receipts",
+                &Query {
+                    from_block: block_range.0,
+                    to_block: Some(block_range.1),
+                    include_all_blocks: true,
+                    transactions: vec![TransactionSelection::default()],
+                    field_selection: FieldSelection {
+                        transaction: RECEIPT_FIELDS.iter().map(|&f| f.to_owned()).collect(),
+                        log: skar_schema::log()
+                            .fields
+                            .iter()
+                            .map(|f| f.name.clone())
+                            .collect(),
+                        ..Default::default()
+                    },
                     ..Default::default()
                 },
-                ..Default::default()
-            })
+            )
             .await
             .context("run skar query")?;
 
+        let mut query_metrics = QueryMetrics {
+            skar_wait_time: elapsed(&start),
+            rows_fetched: count_rows(&res.data),
+            ..Default::default()
+        };
+
         if res.next_block != block_range.1 {
-            return Err(anyhow!("Request took too long to handle"));
+            return Err(QueryTimeout {
+                to_block: block_range.1,
+                next_block: res.next_block,
+            }
+            .into());
         }
 
+        let start = Instant::now();
         let mut receipts = BTreeMap::new();
 
         for batch in res.data.transactions {
@@ -144,10 +311,331 @@ impl QueryHandler {
             }
         }
 
-        Ok(receipts)
+        query_metrics.arrow_decode_time += elapsed(&start);
+
+        if self.cache.is_some() {
+            // blocks without transactions are cached too so a range of them is a hit
+            let mut receipts_by_block = (block_range.0..block_range.1)
+                .map(|block_number| (block_number, Vec::new()))
+                .collect::<BTreeMap<_, _>>();
+            for (&(block_number, _), receipt) in receipts.iter() {
+                if let Some(block_receipts) = receipts_by_block.get_mut(&block_number) {
+                    block_receipts.push(receipt.clone());
+                }
+            }
+            self.cache_blocks(
+                res.archive_height,
+                &receipts_by_block,
+                CacheKey::Receipts,
+                CacheValue::Receipts,
+            );
+        }
+
+        Ok((receipts, query_metrics))
+    }
+
+    /// Logs matching the selection in the inclusive block range that are in cached segments, along
+    /// with the parts of the range that aren't covered by them and have to be fetched with
+    /// `get_uncovered_logs`
+    pub async fn get_cached_logs(
+        &self,
+        selection: &LogSelection,
+        block_range: BlockRange,
+    ) -> (Vec<Log>, Vec<UncoveredLogs>) {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => {
+                let uncovered = UncoveredLogs {
+                    range: block_range,
+                    fetch_range: block_range,
+                };
+                return (Vec::new(), vec![uncovered]);
+            }
+        };
+
+        let selection = selection_key(selection);
+        let archive_height = cache.archive_height();
+        let mut logs = Vec::new();
+        let mut uncovered: Vec<UncoveredLogs> = Vec::new();
+
+        for (segment_start, segment_end) in cache.log_segments(block_range.0, block_range.1) {
+            let from_block = segment_start.max(block_range.0);
+            let to_block = segment_end.min(block_range.1);
+
+            // segments that are only partly in the range are fetched whole if they can be cached
+            let (fetch_from, fetch_to) = if cache.is_final(segment_end, archive_height) {
+                (segment_start, segment_end)
+            } else {
+                (from_block, to_block)
+            };
+
+            let key = CacheKey::Logs {
+                selection: selection.clone(),
+                from_block: segment_start,
+                to_block: segment_end,
+            };
+            match cache.get(&key).await {
+                Some(CacheValue::Logs(cached)) => logs.extend(
+                    cached
+                        .iter()
+                        .filter(|log| (from_block..=to_block).contains(&*log.block_number))
+                        .cloned(),
+                ),
+                _ => match uncovered.last_mut() {
+                    Some(last) if last.range.1 + 1 == from_block => {
+                        last.range.1 = to_block;
+                        last.fetch_range.1 = fetch_to;
+                    }
+                    _ => uncovered.push(UncoveredLogs {
+                        range: BlockRange(from_block, to_block),
+                        fetch_range: BlockRange(fetch_from, fetch_to),
+                    }),
+                },
+            }
+        }
+
+        (logs, uncovered)
+    }
+
+    /// Logs in the uncovered part of a request, fetched over the widened range so its segments are
+    /// cached. Falls back to the requested range if the widened one fails, e.g. because it has
+    /// more than `max_logs` logs.
+    pub async fn get_uncovered_logs(
+        &self,
+        selection: LogSelection,
+        uncovered: UncoveredLogs,
+        max_logs: usize,
+    ) -> Result<(Vec<Log>, QueryMetrics)> {
+        let range = uncovered.range;
+
+        if uncovered.fetch_range != range {
+            match self
+                .get_logs(selection.clone(), uncovered.fetch_range, max_logs)
+                .await
+            {
+                Ok((logs, query_metrics)) => {
+                    return Ok((logs_in_range(logs, range), query_metrics))
+                }
+                Err(e) => log::debug!(
+                    "failed to get logs of widened range {:?}, getting {:?} instead: {:#}",
+                    uncovered.fetch_range,
+                    range,
+                    e
+                ),
+            }
+        }
+
+        self.get_logs(selection, range, max_logs).await
     }
+
+    /// Logs matching the selection in the inclusive block range.
+    ///
+    /// Fails if there are more than `max_logs` logs. The segments that are entirely in the range
+    /// are cached once they are final.
+    pub async fn get_logs(
+        &self,
+        selection: LogSelection,
+        block_range: BlockRange,
+        max_logs: usize,
+    ) -> Result<(Vec<Log>, QueryMetrics)> {
+        let cache_selection = self.cache.as_ref().map(|_| selection_key(&selection));
+
+        let start = Instant::now();
+
+        let query = Query {
+            from_block: block_range.0,
+            // +1 since skar query is exclusive
+            to_block: Some(block_range.1 + 1),
+            logs: vec![selection],
+            field_selection: FieldSelection {
+                log: skar_schema::log()
+                    .fields
+                    .iter()
+                    .map(|field| field.name.clone())
+                    .collect(),
+                ..Default::default()
+            },
+            max_num_logs: Some(max_logs),
+            ..Default::default()
+        };
+
+        let res = self
+            .send_query("get_logs", &query)
+            .await
+            .context("send skar query")?;
+
+        let mut query_metrics = QueryMetrics {
+            skar_wait_time: elapsed(&start),
+            rows_fetched: count_rows(&res.data),
+            ..Default::default()
+        };
+
+        let start = Instant::now();
+
+        let mut logs = Vec::new();
+        for batch in res.data.logs {
+            for log in batch_to_logs(&batch).context("arrow data to logs")? {
+                if logs.len() >= max_logs {
+                    return Err(anyhow!(format!("More than {} logs returned", max_logs)));
+                }
+                logs.push(log);
+            }
+        }
+
+        // the query is exclusive so the last block was only scanned if next_block is past it,
+        // otherwise the skar query timed out
+        if res.next_block <= block_range.1 {
+            return Err(QueryTimeout {
+                to_block: block_range.1 + 1,
+                next_block: res.next_block,
+            }
+            .into());
+        }
+
+        query_metrics.arrow_decode_time += elapsed(&start);
+
+        if let (Some(cache), Some(selection)) = (&self.cache, cache_selection) {
+            for (segment_start, segment_end) in cache.log_segments(block_range.0, block_range.1) {
+                if segment_start < block_range.0
+                    || segment_end > block_range.1
+                    || !cache.is_final(segment_end, res.archive_height)
+                {
+                    continue;
+                }
+
+                let segment_logs = logs
+                    .iter()
+                    .filter(|log| (segment_start..=segment_end).contains(&*log.block_number))
+                    .cloned()
+                    .collect();
+                cache.insert(
+                    CacheKey::Logs {
+                        selection: selection.clone(),
+                        from_block: segment_start,
+                        to_block: segment_end,
+                    },
+                    CacheValue::Logs(Arc::new(segment_logs)),
+                );
+            }
+        }
+
+        Ok((logs, query_metrics))
+    }
+
+    /// Returns the data of all blocks in the range if all of them are cached
+    async fn cached_blocks<T: Clone>(
+        &self,
+        block_range: BlockRange,
+        key: fn(u64) -> CacheKey,
+        value: fn(CacheValue) -> Option<Arc<T>>,
+    ) -> Option<BTreeMap<u64, T>> {
+        let cached = self
+            .cache
+            .as_ref()?
+            .get_range(block_range.0, block_range.1, key, value)
+            .await?;
+
+        Some(
+            cached
+                .into_iter()
+                .map(|(block_number, data)| (block_number, T::clone(&data)))
+                .collect(),
+        )
+    }
+
+    /// Caches the data of the blocks that are final
+    fn cache_blocks<T: Clone>(
+        &self,
+        archive_height: Option<u64>,
+        blocks: &BTreeMap<u64, T>,
+        key: fn(u64) -> CacheKey,
+        value: fn(Arc<T>) -> CacheValue,
+    ) {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return,
+        };
+
+        for (&block_number, data) in blocks.iter() {
+            if cache.is_final(block_number, archive_height) {
+                cache.insert(key(block_number), value(Arc::new(data.clone())));
+            }
+        }
+    }
+
+    pub async fn get_height(&self) -> Result<u64> {
+        self.client.get_height().await
+    }
+
+    /// Reads the chain id from the transactions of the most recent blocks.
+    ///
+    /// Returns None if none of the transactions in these blocks have a chain id (e.g. pre EIP-155
+    /// transactions only).
+    pub async fn get_chain_id(&self) -> Result<Option<u64>> {
+        let height = self.client.get_height().await.context("get height")?;
+
+        let res = self
+            .send_query(
+                "get_chain_id",
+                &Query {
+                    from_block: height.saturating_sub(CHAIN_ID_LOOKBACK),
+                    to_block: Some(height + 1),
+                    transactions: vec![TransactionSelection::default()],
+                    field_selection: FieldSelection {
+                        transaction: ["chain_id".to_owned()].into_iter().collect(),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )
+            .await
+            .context("run skar query")?;
+
+        for batch in res.data.transactions {
+            if let Some(chain_id) = batch_to_chain_id(batch).context("batch to chain id")? {
+                return Ok(Some(chain_id));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Logs of the blocks in the inclusive range
+fn logs_in_range(logs: Vec<Log>, range: BlockRange) -> Vec<Log> {
+    logs.into_iter()
+        .filter(|log| (range.0..=range.1).contains(&*log.block_number))
+        .collect()
+}
+
+/// Cache key of a log selection, the same for selections that only differ in the order of the
+/// addresses or topics
+fn selection_key(selection: &LogSelection) -> String {
+    let mut selection = selection.clone();
+    selection.address.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+    selection.address.dedup();
+    for topics in selection.topics.iter_mut() {
+        topics.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+        topics.dedup();
+    }
+
+    serde_json::to_string(&selection).unwrap()
 }
 
+/// Number of rows in all tables of a HyperSync response
+pub fn count_rows(data: &skar_client::QueryResponseData) -> u64 {
+    data.blocks
+        .iter()
+        .chain(data.transactions.iter())
+        .chain(data.logs.iter())
+        .chain(data.traces.iter())
+        .map(|batch| batch.chunk.len() as u64)
+        .sum()
+}
+
+/// Number of blocks below the height that are searched for a transaction with a chain id
+const CHAIN_ID_LOOKBACK: u64 = 100;
+
 const TX_FIELDS: &[&str] = &[
     "block_hash",
     "block_number",
@@ -184,3 +672,113 @@ const RECEIPT_FIELDS: &[&str] = &[
     "root",
     "status",
 ];
+
+#[cfg(test)]
+mod tests {
+    use crate::config::CacheConfig;
+
+    use super::*;
+
+    fn log(block_number: u64) -> Log {
+        Log {
+            block_number: block_number.into(),
+            ..Default::default()
+        }
+    }
+
+    fn uncovered(range: (u64, u64), fetch_range: (u64, u64)) -> UncoveredLogs {
+        UncoveredLogs {
+            range: BlockRange(range.0, range.1),
+            fetch_range: BlockRange(fetch_range.0, fetch_range.1),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_cached_logs() {
+        let cache = Arc::new(
+            Cache::new(
+                CacheConfig {
+                    memory_size_mb: 1,
+                    confirmations: 0,
+                    log_segment_size: 10,
+                    disk: None,
+                },
+                1,
+            )
+            .unwrap(),
+        );
+        let client = skar_client::Client::new(skar_client::Config {
+            url: "http://localhost:1".parse().unwrap(),
+            bearer_token: None,
+            http_req_timeout_millis: std::num::NonZeroU64::new(1000).unwrap(),
+        })
+        .unwrap();
+        let query_handler = QueryHandler::new(client, Some(cache.clone()));
+
+        let selection = LogSelection::default();
+        cache.insert(
+            CacheKey::Logs {
+                selection: selection_key(&selection),
+                from_block: 30,
+                to_block: 39,
+            },
+            CacheValue::Logs(Arc::new(vec![log(30), log(35), log(39)])),
+        );
+
+        // without a known height no segment is final, so nothing is widened
+        let (logs, uncovered_logs) = query_handler
+            .get_cached_logs(&selection, BlockRange(25, 57))
+            .await;
+        assert_eq!(logs.len(), 3);
+        assert_eq!(
+            uncovered_logs,
+            vec![uncovered((25, 29), (25, 29)), uncovered((40, 57), (40, 57))]
+        );
+
+        // adjacent uncovered segments are merged, final ones are fetched whole
+        cache.set_archive_height(50);
+        let (_, uncovered_logs) = query_handler
+            .get_cached_logs(&selection, BlockRange(25, 57))
+            .await;
+        assert_eq!(
+            uncovered_logs,
+            vec![uncovered((25, 29), (20, 29)), uncovered((40, 57), (40, 57))]
+        );
+
+        cache.set_archive_height(100);
+        let (mut logs, uncovered_logs) = query_handler
+            .get_cached_logs(&selection, BlockRange(25, 57))
+            .await;
+        assert_eq!(
+            uncovered_logs,
+            vec![uncovered((25, 29), (20, 29)), uncovered((40, 57), (40, 59))]
+        );
+
+        // logs fetched over the widened ranges are trimmed before they join the cached ones
+        for uncovered in uncovered_logs {
+            let fetched = (uncovered.fetch_range.0..=uncovered.fetch_range.1)
+                .map(log)
+                .collect();
+            logs.extend(logs_in_range(fetched, uncovered.range));
+        }
+        let mut block_numbers = logs
+            .iter()
+            .map(|log| u64::from(log.block_number))
+            .collect::<Vec<_>>();
+        block_numbers.sort();
+        let expected = (25..=29).chain([30, 35, 39]).chain(40..=57);
+        assert_eq!(block_numbers, expected.collect::<Vec<_>>());
+
+        // logs of a partly requested segment are filtered to the range
+        let (logs, uncovered_logs) = query_handler
+            .get_cached_logs(&selection, BlockRange(32, 36))
+            .await;
+        assert_eq!(
+            logs.iter()
+                .map(|log| u64::from(log.block_number))
+                .collect::<Vec<_>>(),
+            vec![35]
+        );
+        assert!(uncovered_logs.is_empty());
+    }
+}
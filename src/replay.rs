@@ -0,0 +1,513 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use url::Url;
+
+use crate::args::ReplayArgs;
+use crate::types::elapsed;
+
+/// Upper bounds of the latency histogram buckets, in milliseconds
+const HISTOGRAM_BUCKETS_MS: &[u64] = &[1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000];
+
+/// A call as it appears in the log, either a json-rpc request or an access log entry
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LoggedCall {
+    method: String,
+    #[serde(default)]
+    params: serde_json::Value,
+    /// Only set in access log entries, calls of a batch share it
+    #[serde(default)]
+    http_request: Option<u64>,
+    #[serde(default)]
+    batch: bool,
+    #[serde(default)]
+    redacted: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call {
+    pub method: String,
+    pub params: serde_json::Value,
+}
+
+/// An http request to replay, ids are assigned by position in the batch
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayRequest {
+    pub calls: Vec<Call>,
+    pub batch: bool,
+}
+
+impl ReplayRequest {
+    fn body(&self) -> serde_json::Value {
+        let calls = self
+            .calls
+            .iter()
+            .enumerate()
+            .map(|(id, call)| {
+                let mut req = serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "method": call.method,
+                });
+                if !call.params.is_null() {
+                    req["params"] = call.params.clone();
+                }
+                req
+            })
+            .collect::<Vec<_>>();
+
+        if self.batch {
+            serde_json::Value::Array(calls)
+        } else {
+            calls.into_iter().next().unwrap_or_default()
+        }
+    }
+}
+
+/// Reads the http requests of the log.
+///
+/// Lines can be a json-rpc request, a batch of them or an access log entry. Consecutive access log
+/// entries of the same batch are sent as one batch again. Returns the number of calls that were
+/// skipped because their params were redacted.
+pub fn read_log(reader: impl BufRead) -> Result<(Vec<ReplayRequest>, usize)> {
+    let mut requests: Vec<ReplayRequest> = Vec::new();
+    let mut last_http_request = None;
+    let mut skipped = 0;
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.context("read line")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let value: serde_json::Value =
+            serde_json::from_str(&line).with_context(|| format!("parse line {}", i + 1))?;
+
+        if value.is_array() {
+            let calls: Vec<LoggedCall> = serde_json::from_value(value)
+                .with_context(|| format!("parse batch on line {}", i + 1))?;
+            requests.push(ReplayRequest {
+                calls: calls
+                    .into_iter()
+                    .map(|call| Call {
+                        method: call.method,
+                        params: call.params,
+                    })
+                    .collect(),
+                batch: true,
+            });
+            last_http_request = None;
+            continue;
+        }
+
+        let logged: LoggedCall = serde_json::from_value(value)
+            .with_context(|| format!("parse call on line {}", i + 1))?;
+
+        // calls rejected before parsing have no method
+        if logged.redacted || logged.method.is_empty() {
+            skipped += 1;
+            continue;
+        }
+
+        let call = Call {
+            method: logged.method,
+            params: logged.params,
+        };
+
+        match requests.last_mut() {
+            Some(last)
+                if logged.batch
+                    && last.batch
+                    && logged.http_request.is_some()
+                    && logged.http_request == last_http_request =>
+            {
+                last.calls.push(call)
+            }
+            _ => requests.push(ReplayRequest {
+                calls: vec![call],
+                batch: logged.batch,
+            }),
+        }
+        last_http_request = logged.http_request;
+    }
+
+    Ok((requests, skipped))
+}
+
+/// Latencies and errors of the calls of a method on an endpoint
+#[derive(Debug, Default)]
+struct MethodStats {
+    latencies_us: Vec<u64>,
+    errors: BTreeMap<String, u64>,
+}
+
+impl MethodStats {
+    fn num_errors(&self) -> u64 {
+        self.errors.values().sum()
+    }
+
+    /// Nearest rank percentile of the latencies in milliseconds, latencies must be sorted
+    fn percentile_ms(&self, p: f64) -> f64 {
+        if self.latencies_us.is_empty() {
+            return 0.0;
+        }
+        let rank = ((p / 100.0) * self.latencies_us.len() as f64).ceil() as usize;
+        let idx = rank.clamp(1, self.latencies_us.len()) - 1;
+        self.latencies_us[idx] as f64 / 1000.0
+    }
+
+    /// Number of calls in each bucket of HISTOGRAM_BUCKETS_MS, plus the calls slower than the
+    /// last bucket
+    fn histogram(&self) -> Vec<u64> {
+        let mut counts = vec![0; HISTOGRAM_BUCKETS_MS.len() + 1];
+        for latency in self.latencies_us.iter() {
+            let idx = HISTOGRAM_BUCKETS_MS
+                .iter()
+                .position(|bound| *latency <= bound * 1000)
+                .unwrap_or(HISTOGRAM_BUCKETS_MS.len());
+            counts[idx] += 1;
+        }
+        counts
+    }
+}
+
+/// Response object of each call of a request, None if the body has no response for the call
+pub fn split_responses(
+    num_calls: usize,
+    body: &serde_json::Value,
+) -> Vec<Option<&serde_json::Value>> {
+    let responses = match body {
+        serde_json::Value::Array(responses) => responses.iter().collect::<Vec<_>>(),
+        res => vec![res],
+    };
+
+    let mut by_id = HashMap::new();
+    for res in responses.iter() {
+        if let Some(id) = res.get("id").and_then(|id| id.as_u64()) {
+            by_id.insert(id as usize, *res);
+        }
+    }
+
+    (0..num_calls)
+        .map(|id| match by_id.get(&id) {
+            Some(res) => Some(*res),
+            // an error without id is the answer to the whole request
+            None if responses.len() == 1
+                && responses[0].get("id").is_none_or(|id| id.is_null()) =>
+            {
+                Some(responses[0])
+            }
+            None => None,
+        })
+        .collect()
+}
+
+/// Outcome of each call of a request given the http response, an error kind if the call failed
+fn call_outcomes(
+    req: &ReplayRequest,
+    res: std::result::Result<serde_json::Value, String>,
+) -> Vec<Option<String>> {
+    let body = match res {
+        Ok(body) => body,
+        Err(e) => return vec![Some(e); req.calls.len()],
+    };
+
+    split_responses(req.calls.len(), &body)
+        .into_iter()
+        .map(|res| {
+            let res = match res {
+                Some(res) => res,
+                None => return Some("missing_response".to_owned()),
+            };
+
+            match res.get("error") {
+                Some(err) => Some(match err.get("code").and_then(|code| code.as_i64()) {
+                    Some(code) => code.to_string(),
+                    None => "error".to_owned(),
+                }),
+                None if res.get("result").is_some() => None,
+                None => Some("invalid_response".to_owned()),
+            }
+        })
+        .collect()
+}
+
+pub async fn send(
+    http_client: &reqwest::Client,
+    url: &Url,
+    req: &ReplayRequest,
+) -> std::result::Result<serde_json::Value, String> {
+    let res = http_client
+        .post(url.clone())
+        .json(&req.body())
+        .send()
+        .await
+        .map_err(|e| if e.is_timeout() { "timeout" } else { "http" }.to_owned())?;
+
+    let status = res.status();
+    let body = res.bytes().await.map_err(|_| "http".to_owned())?;
+
+    match serde_json::from_slice(&body) {
+        Ok(body) => Ok(body),
+        Err(_) if !status.is_success() => Err(format!("http_{}", status.as_u16())),
+        Err(_) => Err("invalid_response".to_owned()),
+    }
+}
+
+struct EndpointReport {
+    num_requests: usize,
+    num_calls: usize,
+    elapsed: Duration,
+    methods: BTreeMap<String, MethodStats>,
+}
+
+async fn replay_endpoint(
+    http_client: reqwest::Client,
+    url: Url,
+    requests: Arc<Vec<ReplayRequest>>,
+    args: &ReplayArgs,
+) -> EndpointReport {
+    let semaphore = Arc::new(Semaphore::new(args.concurrency.max(1)));
+    let mut interval = args
+        .rate
+        .filter(|rate| *rate > 0.0)
+        .map(|rate| tokio::time::interval(Duration::from_secs_f64(1.0 / rate)));
+    let mut tasks = JoinSet::new();
+    let start = Instant::now();
+
+    for idx in 0..requests.len() {
+        if let Some(interval) = interval.as_mut() {
+            interval.tick().await;
+        }
+        let permit = semaphore.clone().acquire_owned().await.unwrap();
+
+        let http_client = http_client.clone();
+        let url = url.clone();
+        let requests = requests.clone();
+        tasks.spawn(async move {
+            let req = &requests[idx];
+            let start = Instant::now();
+            let res = send(&http_client, &url, req).await;
+            let latency = elapsed(&start);
+            drop(permit);
+
+            (idx, latency, call_outcomes(req, res))
+        });
+    }
+
+    let mut methods: BTreeMap<String, MethodStats> = BTreeMap::new();
+    while let Some(res) = tasks.join_next().await {
+        let (idx, latency, outcomes) = res.unwrap();
+        for (call, outcome) in requests[idx].calls.iter().zip(outcomes) {
+            let stats = methods.entry(call.method.clone()).or_default();
+            stats.latencies_us.push(latency);
+            if let Some(kind) = outcome {
+                *stats.errors.entry(kind).or_default() += 1;
+            }
+        }
+    }
+
+    for stats in methods.values_mut() {
+        stats.latencies_us.sort_unstable();
+    }
+
+    EndpointReport {
+        num_requests: requests.len(),
+        num_calls: requests.iter().map(|req| req.calls.len()).sum(),
+        elapsed: start.elapsed(),
+        methods,
+    }
+}
+
+fn print_report(url: &Url, report: &EndpointReport) {
+    let secs = report.elapsed.as_secs_f64().max(f64::EPSILON);
+    let num_errors: u64 = report.methods.values().map(|s| s.num_errors()).sum();
+
+    println!(
+        "\n{}: {} http requests, {} calls in {:.2}s, {:.1} req/s, {:.1} calls/s, {} errors",
+        url,
+        report.num_requests,
+        report.num_calls,
+        secs,
+        report.num_requests as f64 / secs,
+        report.num_calls as f64 / secs,
+        num_errors,
+    );
+    println!(
+        "{:<45} {:>8} {:>8} {:>10} {:>10} {:>10} {:>10}",
+        "method", "calls", "errors", "p50 ms", "p90 ms", "p99 ms", "max ms"
+    );
+
+    for (method, stats) in report.methods.iter() {
+        println!(
+            "{:<45} {:>8} {:>8} {:>10.2} {:>10.2} {:>10.2} {:>10.2}",
+            method,
+            stats.latencies_us.len(),
+            stats.num_errors(),
+            stats.percentile_ms(50.0),
+            stats.percentile_ms(90.0),
+            stats.percentile_ms(99.0),
+            stats.percentile_ms(100.0),
+        );
+
+        let histogram = stats
+            .histogram()
+            .into_iter()
+            .enumerate()
+            .filter(|(_, count)| *count > 0)
+            .map(|(idx, count)| match HISTOGRAM_BUCKETS_MS.get(idx) {
+                Some(bound) => format!("<={}ms:{}", bound, count),
+                None => format!(">{}ms:{}", HISTOGRAM_BUCKETS_MS.last().unwrap(), count),
+            })
+            .collect::<Vec<_>>();
+        println!("    latency {}", histogram.join(" "));
+
+        if !stats.errors.is_empty() {
+            let errors = stats
+                .errors
+                .iter()
+                .map(|(kind, count)| format!("{}:{}", kind, count))
+                .collect::<Vec<_>>();
+            println!("    errors {}", errors.join(" "));
+        }
+    }
+}
+
+/// Replays the log against each endpoint in turn and prints a report per endpoint
+pub async fn run(args: ReplayArgs) -> Result<()> {
+    let file = std::fs::File::open(&args.log_path)
+        .with_context(|| format!("open {}", args.log_path.display()))?;
+    let (mut requests, skipped) = read_log(BufReader::new(file)).context("read request log")?;
+
+    if let Some(limit) = args.limit {
+        requests.truncate(limit);
+    }
+    if skipped > 0 {
+        println!("skipped {} calls with redacted params", skipped);
+    }
+
+    let requests = Arc::new(requests);
+    let http_client = reqwest::Client::builder()
+        .gzip(true)
+        .timeout(Duration::from_secs(args.timeout_secs))
+        .build()
+        .context("build http client")?;
+
+    for url in args.endpoints.iter() {
+        let report =
+            replay_endpoint(http_client.clone(), url.clone(), requests.clone(), &args).await;
+        print_report(url, &report);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(method: &str) -> Call {
+        Call {
+            method: method.to_owned(),
+            params: serde_json::json!([]),
+        }
+    }
+
+    #[test]
+    fn test_read_log() {
+        let log = r#"
+{"jsonrpc":"2.0","id":1,"method":"eth_blockNumber","params":[]}
+[{"jsonrpc":"2.0","id":1,"method":"eth_chainId","params":[]},{"jsonrpc":"2.0","id":2,"method":"eth_blockNumber","params":[]}]
+{"timestampMs":1,"httpRequest":3,"batch":true,"client":null,"id":1,"method":"eth_getLogs","params":[],"backend":"hypersync","latencyUs":10,"status":"ok","responseBytes":10}
+{"timestampMs":1,"httpRequest":3,"batch":true,"client":null,"id":2,"method":"eth_sendRawTransaction","params":null,"redacted":true,"backend":"broadcast","latencyUs":10,"status":"ok","responseBytes":10}
+{"timestampMs":1,"httpRequest":3,"batch":true,"client":null,"id":3,"method":"eth_call","params":[],"backend":"fallback","latencyUs":10,"status":"ok","responseBytes":10}
+{"timestampMs":1,"httpRequest":4,"batch":false,"client":null,"id":1,"method":"eth_call","params":[],"backend":"fallback","latencyUs":10,"status":"ok","responseBytes":10}
+"#;
+
+        let (requests, skipped) = read_log(log.as_bytes()).unwrap();
+
+        assert_eq!(skipped, 1);
+        assert_eq!(
+            requests,
+            vec![
+                ReplayRequest {
+                    calls: vec![call("eth_blockNumber")],
+                    batch: false
+                },
+                ReplayRequest {
+                    calls: vec![call("eth_chainId"), call("eth_blockNumber")],
+                    batch: true
+                },
+                ReplayRequest {
+                    calls: vec![call("eth_getLogs"), call("eth_call")],
+                    batch: true
+                },
+                ReplayRequest {
+                    calls: vec![call("eth_call")],
+                    batch: false
+                },
+            ]
+        );
+        assert_eq!(
+            requests[0].body(),
+            serde_json::json!({"jsonrpc":"2.0","id":0,"method":"eth_blockNumber","params":[]})
+        );
+    }
+
+    #[test]
+    fn test_call_outcomes() {
+        let req = ReplayRequest {
+            calls: vec![call("eth_call"), call("eth_call"), call("eth_getLogs")],
+            batch: true,
+        };
+
+        let body = serde_json::json!([
+            {"jsonrpc":"2.0","id":1,"error":{"code":3,"message":"execution reverted"}},
+            {"jsonrpc":"2.0","id":0,"result":"0x"},
+        ]);
+        assert_eq!(
+            call_outcomes(&req, Ok(body)),
+            vec![
+                None,
+                Some("3".to_owned()),
+                Some("missing_response".to_owned())
+            ]
+        );
+
+        let body = serde_json::json!(
+            {"jsonrpc":"2.0","id":null,"error":{"code":-32005,"message":"limit exceeded"}}
+        );
+        assert_eq!(
+            call_outcomes(&req, Ok(body)),
+            vec![Some("-32005".to_owned()); 3]
+        );
+
+        assert_eq!(
+            call_outcomes(&req, Err("timeout".to_owned())),
+            vec![Some("timeout".to_owned()); 3]
+        );
+    }
+
+    #[test]
+    fn test_method_stats() {
+        let stats = MethodStats {
+            latencies_us: (1..=100).map(|ms| ms * 1000).collect(),
+            errors: BTreeMap::new(),
+        };
+
+        assert_eq!(stats.percentile_ms(50.0), 50.0);
+        assert_eq!(stats.percentile_ms(99.0), 99.0);
+        assert_eq!(stats.percentile_ms(100.0), 100.0);
+        assert_eq!(
+            stats.histogram(),
+            vec![1, 1, 3, 5, 10, 30, 50, 0, 0, 0, 0, 0, 0]
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use super::{
-    EndpointConfig, Error, GetBlockNumber, LimitConfig, Result, RpcRequest, RpcRequestImpl,
-    RpcResponse,
+    EndpointConfig, Error, GetBlockNumber, LimitConfig, Result, RpcErrorObject, RpcRequest,
+    RpcRequestImpl, RpcResponse,
 };
 use reqwest::Method;
 use skar_format::BlockNumber;
@@ -12,10 +12,15 @@ use std::{
 };
 use tokio::sync::mpsc;
 use tokio::sync::RwLock;
+use tracing::Instrument;
 use url::Url;
 
+use crate::{metrics, telemetry};
+
 pub struct Endpoint {
     url: Arc<Url>,
+    name: Arc<str>,
+    limit: LimitConfig,
     last_block: Arc<RwLock<Option<BlockNumber>>>,
     job_tx: mpsc::Sender<Job>,
 }
@@ -23,11 +28,19 @@ pub struct Endpoint {
 impl Endpoint {
     pub fn new(http_client: reqwest::Client, config: EndpointConfig) -> Self {
         let last_block = Arc::new(RwLock::new(None));
+        // urls can contain api keys so only the host is used if there is no label
+        let name = config
+            .label
+            .clone()
+            .or_else(|| config.url.host_str().map(str::to_owned))
+            .unwrap_or_default();
         let url = Arc::new(config.url);
+        let name: Arc<str> = name.into();
         let bearer_token = config.bearer_token.map(Arc::new);
 
         tokio::spawn(
             WatchHealth {
+                name: name.clone(),
                 http_client: http_client.clone(),
                 last_block: last_block.clone(),
                 status_refresh_interval_secs: config.status_refresh_interval_secs,
@@ -43,10 +56,11 @@ impl Endpoint {
             Listen {
                 http_client,
                 job_rx,
-                limit_config: config.limit,
+                limit_config: config.limit.clone(),
                 window_num_reqs: 0,
                 last_limit_refresh: Instant::now(),
                 url: url.clone(),
+                name: name.clone(),
                 bearer_token,
             }
             .listen(),
@@ -54,6 +68,8 @@ impl Endpoint {
 
         Self {
             url,
+            name,
+            limit: config.limit,
             last_block,
             job_tx,
         }
@@ -67,6 +83,15 @@ impl Endpoint {
         &self.url
     }
 
+    /// Label of the endpoint, safe to show to clients unlike the url
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn limit(&self) -> &LimitConfig {
+        &self.limit
+    }
+
     pub async fn send(&self, req: Arc<RpcRequest>) -> Result<RpcResponse> {
         self.send_impl(req).await
     }
@@ -85,7 +110,13 @@ impl Endpoint {
 
         let (res_tx, mut res_rx) = mpsc::channel(1);
 
-        self.job_tx.send(Job { res_tx, req }).await.ok().unwrap();
+        let job = Job {
+            req,
+            res_tx,
+            span: tracing::Span::current(),
+        };
+
+        self.job_tx.send(job).await.ok().unwrap();
 
         res_rx.recv().await.unwrap()
     }
@@ -106,6 +137,7 @@ impl Endpoint {
     fn calculate_required_last_block_impl(req: &RpcRequestImpl) -> Option<BlockNumber> {
         match req {
             RpcRequestImpl::GetBlockNumber => None,
+            RpcRequestImpl::GetChainId => None,
             RpcRequestImpl::GetBlockByNumber(block_number) => Some(*block_number),
             RpcRequestImpl::GetTransactionReceipt(block_number, _) => Some(*block_number),
             RpcRequestImpl::GetBlockReceipts(block_number) => Some(*block_number),
@@ -117,6 +149,7 @@ impl Endpoint {
 
 struct WatchHealth {
     url: Arc<Url>,
+    name: Arc<str>,
     bearer_token: Option<Arc<String>>,
     http_client: reqwest::Client,
     last_block: Arc<RwLock<Option<BlockNumber>>>,
@@ -133,9 +166,15 @@ impl WatchHealth {
             tokio::spawn(
                 SendRpcRequest {
                     url: self.url.clone(),
+                    name: self.name.clone(),
                     bearer_token: self.bearer_token.clone(),
                     http_client: self.http_client.clone(),
-                    job: Job { res_tx, req },
+                    // health checks aren't part of any request so they aren't traced
+                    job: Job {
+                        req,
+                        res_tx,
+                        span: tracing::Span::none(),
+                    },
                 }
                 .send(),
             );
@@ -163,10 +202,13 @@ impl WatchHealth {
 struct Job {
     req: Arc<RpcRequest>,
     res_tx: mpsc::Sender<Result<RpcResponse>>,
+    /// Span of the request that created the job, jobs are sent from other tasks
+    span: tracing::Span,
 }
 
 struct Listen {
     url: Arc<Url>,
+    name: Arc<str>,
     bearer_token: Option<Arc<String>>,
     http_client: reqwest::Client,
     job_rx: mpsc::Receiver<Job>,
@@ -179,6 +221,9 @@ impl Listen {
     async fn listen(mut self) {
         while let Some(job) = self.job_rx.recv().await {
             if let Err(e) = self.update_limit(&job.req) {
+                metrics::UPSTREAM_ERRORS
+                    .with_label_values(&[&self.name, e.kind()])
+                    .inc();
                 tokio::spawn(async move {
                     job.res_tx.send(Err(e)).await.ok();
                 });
@@ -190,6 +235,7 @@ impl Listen {
                     http_client: self.http_client.clone(),
                     job,
                     url: self.url.clone(),
+                    name: self.name.clone(),
                     bearer_token: self.bearer_token.clone(),
                 }
                 .send(),
@@ -243,6 +289,7 @@ impl Listen {
 
 struct SendRpcRequest {
     url: Arc<Url>,
+    name: Arc<str>,
     bearer_token: Option<Arc<String>>,
     http_client: reqwest::Client,
     job: Job,
@@ -251,9 +298,26 @@ struct SendRpcRequest {
 impl SendRpcRequest {
     async fn send(self) {
         let res_tx = self.job.res_tx.clone();
-        let res = self.send_impl().await;
+
+        metrics::UPSTREAM_REQUESTS
+            .with_label_values(&[&self.name])
+            .inc();
+        let timer = metrics::UPSTREAM_REQUEST_DURATION
+            .with_label_values(&[&self.name])
+            .start_timer();
+        let span = if self.job.span.is_none() {
+            tracing::Span::none()
+        } else {
+            tracing::info_span!(parent: &self.job.span, "upstream_request", endpoint = &*self.name)
+        };
+        let res = self.send_impl(&span).instrument(span.clone()).await;
+        timer.observe_duration();
 
         if let Err(e) = res.as_ref() {
+            metrics::UPSTREAM_ERRORS
+                .with_label_values(&[&self.name, e.kind()])
+                .inc();
+
             let req: serde_json::Value = self.job.req.as_ref().into();
             let req_str = serde_json::to_string(&req)
                 .unwrap_or_else(|_| "Failed to serialize request".to_string());
@@ -268,13 +332,17 @@ impl SendRpcRequest {
         res_tx.send(res).await.ok();
     }
 
-    async fn send_impl(&self) -> Result<RpcResponse> {
+    async fn send_impl(&self, span: &tracing::Span) -> Result<RpcResponse> {
         let json: serde_json::Value = self.job.req.as_ref().into();
 
         let mut req = self
             .http_client
             .request(Method::POST, Url::clone(&self.url));
 
+        for (name, value) in telemetry::trace_headers(span) {
+            req = req.header(name, value);
+        }
+
         if let Some(bearer_token) = &self.bearer_token {
             req = req.bearer_auth(bearer_token);
         }
@@ -284,16 +352,22 @@ impl SendRpcRequest {
             .send()
             .await
             .map_err(Error::HttpRequest)?
-            .text()
+            .bytes()
             .await
             .map_err(Error::HttpRequest)?;
 
-        let json = tokio::task::block_in_place(|| self.job.req.resp_from_json(res.clone()));
+        let resp = tokio::task::block_in_place(|| self.job.req.resp_from_bytes(res.clone()));
 
-        match json {
-            Ok(json) => Ok(json),
+        match resp {
+            Ok(resp) => Ok(resp),
+            Err(e) if e.is::<RpcErrorObject>() => Err(Error::RpcErrorResponse(
+                e.downcast().expect("checked the error type"),
+            )),
             Err(e) => {
-                log::warn!("failed to parse rpc response: {res}");
+                log::warn!(
+                    "failed to parse rpc response: {}",
+                    String::from_utf8_lossy(&res)
+                );
                 Err(Error::InvalidRPCResponse(e))
             }
         }
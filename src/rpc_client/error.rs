@@ -1,6 +1,8 @@
 use std::result::Result as StdResult;
 use thiserror::Error as ThisError;
 
+use super::types::RpcErrorObject;
+
 #[derive(Debug, ThisError)]
 pub enum Error {
     #[error("Failed to execute http request:\n{0}")]
@@ -13,10 +15,41 @@ pub enum Error {
     EndpointTooBehind,
     #[error("Endpoint is unavailable. Client failed to get height of it.")]
     EndpointUnavailable,
+    #[error("Upstream returned an error response. {0}")]
+    RpcErrorResponse(RpcErrorObject),
     #[error("Invalid RPC response.\n{0:?}")]
     InvalidRPCResponse(anyhow::Error),
     #[error("All retries failed when trying to execute RPC request.")]
     RetriesFailed,
+    #[error("Upstream batch response didn't include a response for this request.")]
+    MissingResponse,
 }
 
 pub type Result<T> = StdResult<T, Error>;
+
+impl Error {
+    /// Short name of the error, used as a metric label
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::HttpRequest(_) => "http_request",
+            Self::NoHealthyEndpoints(_) => "no_healthy_endpoints",
+            Self::EndpointLimitTooLow => "endpoint_limit_too_low",
+            Self::EndpointTooBehind => "endpoint_too_behind",
+            Self::EndpointUnavailable => "endpoint_unavailable",
+            Self::RpcErrorResponse(_) => "rpc_error_response",
+            Self::InvalidRPCResponse(_) => "invalid_rpc_response",
+            Self::RetriesFailed => "retries_failed",
+            Self::MissingResponse => "missing_response",
+        }
+    }
+
+    /// Returns true if the endpoint refused the request because of a limit, so it is worth
+    /// retrying it later
+    pub fn is_limit(&self) -> bool {
+        match self {
+            Self::EndpointLimitTooLow => true,
+            Self::RpcErrorResponse(err) => err.code == -32005,
+            _ => false,
+        }
+    }
+}
@@ -1,10 +1,13 @@
 use anyhow::Context;
 use tokio::time::sleep;
 
-use super::LimitConfig;
+use super::config::default_batch_size_limit;
 use super::{endpoint::Endpoint, EndpointConfig, Error, Result, RpcRequest, RpcResponse};
+use super::{GetChainId, LimitConfig, RpcClientConfig, RpcRequestImpl};
+use futures::{FutureExt, StreamExt};
 use std::cmp;
 use std::num::{NonZeroU64, NonZeroUsize};
+use std::ops::Range;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -14,30 +17,38 @@ pub struct RpcClient {
 
 impl RpcClient {
     pub fn new(name: String, url: String) -> anyhow::Result<Self> {
-        let http_client = reqwest::Client::builder()
-            .gzip(true)
-            .http1_only()
-            .timeout(Duration::from_secs(20))
-            .tcp_keepalive(Duration::from_secs(7200))
-            .build()
-            .unwrap();
-
-        let endpoints = vec![Endpoint::new(
-            http_client,
-            EndpointConfig {
+        Ok(Self::from_config(RpcClientConfig {
+            http_req_timeout_millis: NonZeroU64::new(20000).unwrap(),
+            endpoints: vec![EndpointConfig {
                 url: url.parse().context("parse url")?,
                 bearer_token: None,
                 status_refresh_interval_secs: NonZeroU64::new(1).unwrap(),
                 limit: LimitConfig {
                     req_limit: NonZeroUsize::new(123123123).unwrap(),
                     req_limit_window_ms: NonZeroU64::new(1000).unwrap(),
-                    batch_size_limit: NonZeroUsize::new(123123).unwrap(),
+                    batch_size_limit: default_batch_size_limit(),
                 },
                 label: Some(name),
-            },
-        )];
+            }],
+        }))
+    }
+
+    pub fn from_config(config: RpcClientConfig) -> Self {
+        let http_client = reqwest::Client::builder()
+            .gzip(true)
+            .http1_only()
+            .timeout(Duration::from_millis(config.http_req_timeout_millis.get()))
+            .tcp_keepalive(Duration::from_secs(7200))
+            .build()
+            .unwrap();
 
-        Ok(Self { endpoints })
+        let endpoints = config
+            .endpoints
+            .into_iter()
+            .map(|cfg| Endpoint::new(http_client.clone(), cfg))
+            .collect();
+
+        Self { endpoints }
     }
 
     pub async fn last_block(&self) -> u64 {
@@ -52,6 +63,37 @@ impl RpcClient {
         last_block
     }
 
+    /// Returns the name and last known block of each endpoint, block is None if the endpoint is
+    /// unhealthy
+    pub async fn endpoint_heights(&self) -> Vec<(String, Option<u64>)> {
+        let mut heights = Vec::with_capacity(self.endpoints.len());
+
+        for e in self.endpoints.iter() {
+            heights.push((e.name().to_owned(), e.last_block().await.map(u64::from)));
+        }
+
+        heights
+    }
+
+    /// Queries eth_chainId from each endpoint, results are labeled with the name of the endpoint
+    pub async fn get_chain_ids(&self) -> Vec<(String, Result<u64>)> {
+        let req: Arc<RpcRequest> = Arc::new(GetChainId.into());
+
+        let futs = self.endpoints.iter().map(|endpoint| {
+            let req = req.clone();
+            async move {
+                let res = endpoint.send(req).await.and_then(|res| {
+                    res.try_into_single().ok_or_else(|| {
+                        Error::InvalidRPCResponse(anyhow::anyhow!("unexpected response type"))
+                    })
+                });
+                (endpoint.name().to_owned(), res)
+            }
+        });
+
+        futures::future::join_all(futs).await
+    }
+
     /// Executes the given rpc request without retries
     pub async fn send_once(&self, req: RpcRequest) -> Result<RpcResponse> {
         let req = Arc::new(req);
@@ -89,10 +131,7 @@ impl RpcClient {
                         _ => return Err(e),
                     };
 
-                    if errs
-                        .iter()
-                        .all(|e| !matches!(e, Error::EndpointLimitTooLow))
-                    {
+                    if !errs.iter().any(Error::is_limit) {
                         return Err(Error::NoHealthyEndpoints(errs));
                     }
                 }
@@ -108,4 +147,87 @@ impl RpcClient {
 
         Err(Error::RetriesFailed)
     }
+
+    /// Executes a batch of requests by splitting it into chunks.
+    ///
+    /// Each chunk is sized to the batch size limit of the endpoint it is assigned to and chunks are
+    /// assigned to healthy endpoints in turns. Chunks are executed concurrently, bounded by the total
+    /// request limit of the endpoints. If the assigned endpoint fails to execute a chunk, it is retried
+    /// on all endpoints using `send`.
+    ///
+    /// Returns the result for each chunk along with the range of requests it covers, in order.
+    pub async fn send_chunked(
+        &self,
+        reqs: Vec<RpcRequestImpl>,
+    ) -> Vec<(Range<usize>, Result<RpcResponse>)> {
+        if reqs.is_empty() {
+            return Vec::new();
+        }
+        if self.endpoints.is_empty() {
+            return vec![(0..reqs.len(), Err(Error::NoHealthyEndpoints(Vec::new())))];
+        }
+
+        let mut healthy = Vec::new();
+        for (idx, endpoint) in self.endpoints.iter().enumerate() {
+            if endpoint.last_block().await.is_some() {
+                healthy.push(idx);
+            }
+        }
+        if healthy.is_empty() {
+            healthy = (0..self.endpoints.len()).collect();
+        }
+
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        for &idx in healthy.iter().cycle() {
+            if start >= reqs.len() {
+                break;
+            }
+            let batch_size_limit = self.endpoints[idx].limit().batch_size_limit.get();
+            let end = cmp::min(start + batch_size_limit, reqs.len());
+            chunks.push((idx, start..end));
+            start = end;
+        }
+
+        let concurrency = healthy
+            .iter()
+            .map(|&idx| self.endpoints[idx].limit().req_limit.get())
+            .fold(0usize, |acc, limit| acc.saturating_add(limit))
+            .clamp(1, MAX_CHUNK_CONCURRENCY);
+
+        let futs = chunks
+            .into_iter()
+            .map(|(idx, range)| {
+                let req = RpcRequest::Batch(reqs[range.clone()].to_vec());
+                self.send_chunk(idx, req).map(move |res| (range, res))
+            })
+            .collect::<Vec<_>>();
+
+        let mut res = futures::stream::iter(futs)
+            .buffer_unordered(concurrency)
+            .collect::<Vec<_>>()
+            .await;
+
+        res.sort_by_key(|(range, _)| range.start);
+
+        res
+    }
+
+    async fn send_chunk(&self, endpoint_idx: usize, req: RpcRequest) -> Result<RpcResponse> {
+        let endpoint = &self.endpoints[endpoint_idx];
+
+        match endpoint.send(Arc::new(req.clone())).await {
+            Ok(res) => Ok(res),
+            Err(e) => {
+                log::debug!(
+                    "failed to execute chunk on endpoint {}, retrying on all endpoints.\nCaused by: {}",
+                    endpoint.url(),
+                    e
+                );
+                self.send(req).await
+            }
+        }
+    }
 }
+
+const MAX_CHUNK_CONCURRENCY: usize = 64;
@@ -2,9 +2,14 @@ pub mod config;
 mod endpoint;
 mod error;
 pub mod inner;
+pub mod raw;
 mod types;
 
-pub use config::{EndpointConfig, LimitConfig};
+pub use config::{EndpointConfig, LimitConfig, RpcClientConfig};
 pub use error::{Error, Result};
 pub use inner::RpcClient;
-pub use types::{GetBlockNumber, RpcRequest, RpcRequestImpl, RpcResponse};
+pub use raw::RawResponse;
+pub use types::{
+    GetBlockNumber, GetChainId, RpcErrorObject, RpcRequest, RpcRequestImpl, RpcResponse,
+    RpcResponseImpl,
+};
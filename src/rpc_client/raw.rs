@@ -0,0 +1,294 @@
+use std::ops::Range;
+
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+
+use crate::bytes_builder::BytesBuilder;
+
+/// A single json-rpc response object taken out of an upstream response body without parsing it.
+///
+/// Only the structure of the json is scanned so the object can be split out of a batch and the `id`
+/// member can be located. Everything else is written to the client as it was received.
+#[derive(Debug, Clone)]
+pub struct RawResponse {
+    body: Bytes,
+    /// Span of the whole `"id": <value>` member inside body
+    id_member: Option<Range<usize>>,
+    /// Span of the value of the `id` member inside body
+    id_value: Option<Range<usize>>,
+}
+
+impl RawResponse {
+    /// Parses the id of the response if it is an unsigned integer
+    pub fn id(&self) -> Option<u64> {
+        let span = self.id_value.clone()?;
+        std::str::from_utf8(&self.body[span]).ok()?.parse().ok()
+    }
+
+    /// Json text of the response object
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Writes the response object into builder, replacing the upstream id with given id
+    pub fn write_with_id(&self, builder: &mut BytesBuilder, id: i64) {
+        match &self.id_member {
+            Some(span) => {
+                builder.push(self.body.slice(..span.start));
+                builder.push(Bytes::from(format!(r#""id":{}"#, id)));
+                builder.push(self.body.slice(span.end..));
+            }
+            None => {
+                builder.push(Bytes::from(format!(r#"{{"id":{}"#, id)));
+                // body is at least "{}" so skipping the opening brace is fine
+                let rest = self.body.slice(1..);
+                if !rest.iter().all(|b| b.is_ascii_whitespace() || *b == b'}') {
+                    builder.push_static(",");
+                }
+                builder.push(rest);
+            }
+        }
+    }
+}
+
+/// Splits a json array of response objects into raw responses
+pub fn split_batch(body: Bytes) -> Result<Vec<RawResponse>> {
+    let mut pos = skip_ws(&body, 0);
+    if body.get(pos) != Some(&b'[') {
+        return Err(anyhow!("expected a json array"));
+    }
+    pos += 1;
+
+    let mut items = Vec::new();
+
+    pos = skip_ws(&body, pos);
+    if body.get(pos) == Some(&b']') {
+        return Ok(items);
+    }
+
+    loop {
+        pos = skip_ws(&body, pos);
+        let start = pos;
+        let scanned = scan_object(&body, start).context("scan array item")?;
+        items.push(RawResponse {
+            body: body.slice(start..scanned.end),
+            id_member: scanned.id_member.map(|r| r.start - start..r.end - start),
+            id_value: scanned.id_value.map(|r| r.start - start..r.end - start),
+        });
+
+        pos = skip_ws(&body, scanned.end);
+        match body.get(pos) {
+            Some(b',') => pos += 1,
+            Some(b']') => return Ok(items),
+            _ => return Err(anyhow!("expected ',' or ']' at {}", pos)),
+        }
+    }
+}
+
+/// Parses a single response object into a raw response
+pub fn parse_object(body: Bytes) -> Result<RawResponse> {
+    let start = skip_ws(&body, 0);
+    let scanned = scan_object(&body, start)?;
+
+    if skip_ws(&body, scanned.end) != body.len() {
+        return Err(anyhow!("trailing characters after json object"));
+    }
+
+    Ok(RawResponse {
+        body: body.slice(start..scanned.end),
+        id_member: scanned.id_member.map(|r| r.start - start..r.end - start),
+        id_value: scanned.id_value.map(|r| r.start - start..r.end - start),
+    })
+}
+
+/// Returns true if the body is a json array, without checking the rest of it
+pub fn is_array(body: &[u8]) -> bool {
+    body.get(skip_ws(body, 0)) == Some(&b'[')
+}
+
+struct ScannedObject {
+    end: usize,
+    id_member: Option<Range<usize>>,
+    id_value: Option<Range<usize>>,
+}
+
+fn scan_object(buf: &[u8], mut pos: usize) -> Result<ScannedObject> {
+    if buf.get(pos) != Some(&b'{') {
+        return Err(anyhow!("expected a json object at {}", pos));
+    }
+    pos += 1;
+
+    let mut id_member = None;
+    let mut id_value = None;
+
+    pos = skip_ws(buf, pos);
+    if buf.get(pos) == Some(&b'}') {
+        return Ok(ScannedObject {
+            end: pos + 1,
+            id_member,
+            id_value,
+        });
+    }
+
+    loop {
+        pos = skip_ws(buf, pos);
+        let key_start = pos;
+        pos = skip_string(buf, pos)?;
+        let key = &buf[key_start..pos];
+
+        pos = skip_ws(buf, pos);
+        if buf.get(pos) != Some(&b':') {
+            return Err(anyhow!("expected ':' at {}", pos));
+        }
+        pos = skip_ws(buf, pos + 1);
+
+        let value_start = pos;
+        pos = skip_value(buf, pos)?;
+
+        if key == br#""id""# {
+            id_member = Some(key_start..pos);
+            id_value = Some(value_start..pos);
+        }
+
+        pos = skip_ws(buf, pos);
+        match buf.get(pos) {
+            Some(b',') => pos += 1,
+            Some(b'}') => {
+                return Ok(ScannedObject {
+                    end: pos + 1,
+                    id_member,
+                    id_value,
+                })
+            }
+            _ => return Err(anyhow!("expected ',' or '}}' at {}", pos)),
+        }
+    }
+}
+
+fn skip_value(buf: &[u8], pos: usize) -> Result<usize> {
+    match buf.get(pos) {
+        Some(b'"') => skip_string(buf, pos),
+        Some(b'{') | Some(b'[') => skip_nested(buf, pos),
+        Some(_) => {
+            // number, bool or null
+            let len = buf[pos..]
+                .iter()
+                .position(|b| matches!(b, b',' | b'}' | b']') || b.is_ascii_whitespace())
+                .unwrap_or(buf.len() - pos);
+            if len == 0 {
+                Err(anyhow!("expected a json value at {}", pos))
+            } else {
+                Ok(pos + len)
+            }
+        }
+        None => Err(anyhow!("unexpected end of json")),
+    }
+}
+
+fn skip_nested(buf: &[u8], mut pos: usize) -> Result<usize> {
+    let mut depth = 0usize;
+
+    while let Some(b) = buf.get(pos) {
+        match b {
+            b'"' => {
+                pos = skip_string(buf, pos)?;
+                continue;
+            }
+            b'{' | b'[' => depth += 1,
+            b'}' | b']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(pos + 1);
+                }
+            }
+            _ => (),
+        }
+        pos += 1;
+    }
+
+    Err(anyhow!("unexpected end of json"))
+}
+
+fn skip_string(buf: &[u8], mut pos: usize) -> Result<usize> {
+    if buf.get(pos) != Some(&b'"') {
+        return Err(anyhow!("expected a json string at {}", pos));
+    }
+    pos += 1;
+
+    while let Some(b) = buf.get(pos) {
+        match b {
+            b'\\' => pos += 2,
+            b'"' => return Ok(pos + 1),
+            _ => pos += 1,
+        }
+    }
+
+    Err(anyhow!("unterminated json string"))
+}
+
+fn skip_ws(buf: &[u8], pos: usize) -> usize {
+    pos + buf[pos.min(buf.len())..]
+        .iter()
+        .take_while(|b| b.is_ascii_whitespace())
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewritten(raw: &RawResponse, id: i64) -> serde_json::Value {
+        let mut builder = BytesBuilder::new();
+        raw.write_with_id(&mut builder, id);
+        serde_json::from_slice(&builder.build()).unwrap()
+    }
+
+    #[test]
+    fn test_split_batch() {
+        let body = Bytes::from_static(
+            br#" [ {"jsonrpc":"2.0","id":0,"result":{"a":["}",{"b":"\"]"}]}},
+                {"id" : 1 , "jsonrpc":"2.0","error":{"code":3,"message":"execution reverted","data":"0x08"}},
+                {"jsonrpc":"2.0","result":null,"id":2} ] "#,
+        );
+
+        let items = split_batch(body).unwrap();
+
+        assert_eq!(items.len(), 3);
+        assert_eq!(
+            items.iter().map(RawResponse::id).collect::<Vec<_>>(),
+            vec![Some(0), Some(1), Some(2)]
+        );
+        assert_eq!(
+            rewritten(&items[0], 10),
+            serde_json::json!({"jsonrpc":"2.0","id":10,"result":{"a":["}",{"b":"\"]"}]}})
+        );
+        assert_eq!(
+            rewritten(&items[1], 11),
+            serde_json::json!({"jsonrpc":"2.0","id":11,"error":{"code":3,"message":"execution reverted","data":"0x08"}})
+        );
+        assert_eq!(
+            rewritten(&items[2], 12),
+            serde_json::json!({"jsonrpc":"2.0","id":12,"result":null})
+        );
+    }
+
+    #[test]
+    fn test_object_without_id() {
+        let raw = parse_object(Bytes::from_static(br#"{"jsonrpc":"2.0","result":"0x1"}"#)).unwrap();
+        assert_eq!(raw.id(), None);
+        assert_eq!(
+            rewritten(&raw, 5),
+            serde_json::json!({"jsonrpc":"2.0","id":5,"result":"0x1"})
+        );
+
+        let raw = parse_object(Bytes::from_static(b"{ }")).unwrap();
+        assert_eq!(rewritten(&raw, 5), serde_json::json!({"id":5}));
+    }
+
+    #[test]
+    fn test_invalid_json() {
+        assert!(split_batch(Bytes::from_static(b"<html>")).is_err());
+        assert!(split_batch(Bytes::from_static(br#"[{"id":1"#)).is_err());
+        assert!(parse_object(Bytes::from_static(br#"{"id":1} x"#)).is_err());
+    }
+}
@@ -1,10 +1,16 @@
 use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use serde::Deserialize;
 use skar_format::{Block, BlockNumber, Hash, Trace, Transaction, TransactionReceipt};
 use std::result::Result as StdResult;
+use thiserror::Error as ThisError;
+
+use super::raw::{self, RawResponse};
 
 #[derive(Clone)]
 pub enum RpcRequestImpl {
     GetBlockNumber,
+    GetChainId,
     GetBlockByNumber(BlockNumber),
     GetTransactionReceipt(BlockNumber, Hash),
     GetBlockReceipts(BlockNumber),
@@ -17,11 +23,26 @@ pub enum RpcRequestImpl {
 
 pub enum RpcResponseImpl {
     GetBlockNumber(BlockNumber),
+    GetChainId(u64),
     GetBlockByNumber(Block<Transaction>),
     GetTransactionReceipt(TransactionReceipt),
     GetBlockReceipts(Vec<TransactionReceipt>),
     TraceBlock(Vec<Trace>),
-    Proxy(serde_json::Value),
+    Proxy(RawResponse),
+    /// Upstream didn't return a response for this item of the batch
+    Missing,
+    /// Upstream returned an error or an invalid response for this item of the batch
+    Failed(anyhow::Error),
+}
+
+/// Error object returned by an upstream endpoint
+#[derive(Debug, Clone, Deserialize, PartialEq, ThisError)]
+#[error("rpc error response. code: {code}, message: {message}")]
+pub struct RpcErrorObject {
+    pub code: i64,
+    pub message: String,
+    #[serde(default)]
+    pub data: Option<serde_json::Value>,
 }
 
 #[derive(Clone)]
@@ -41,6 +62,25 @@ impl From<GetBlockNumber> for RpcRequest {
     }
 }
 
+pub struct GetChainId;
+
+impl From<GetChainId> for RpcRequest {
+    fn from(_: GetChainId) -> Self {
+        Self::Single(RpcRequestImpl::GetChainId)
+    }
+}
+
+impl TryInto<u64> for RpcResponseImpl {
+    type Error = ();
+
+    fn try_into(self) -> StdResult<u64, Self::Error> {
+        match self {
+            RpcResponseImpl::GetChainId(chain_id) => Ok(chain_id),
+            _ => Err(()),
+        }
+    }
+}
+
 impl From<GetBlockReceipts> for RpcRequest {
     fn from(req: GetBlockReceipts) -> Self {
         Self::Single(RpcRequestImpl::GetBlockReceipts(req.0))
@@ -160,23 +200,6 @@ impl TryInto<Vec<Trace>> for MaybeBatch<RpcResponseImpl> {
     }
 }
 
-impl TryInto<Vec<serde_json::Value>> for MaybeBatch<RpcResponseImpl> {
-    type Error = ();
-
-    fn try_into(self) -> StdResult<Vec<serde_json::Value>, Self::Error> {
-        match self {
-            MaybeBatch::Batch(resps) => resps
-                .into_iter()
-                .map(|r| match r {
-                    RpcResponseImpl::Proxy(r) => Ok(r),
-                    _ => Err(()),
-                })
-                .collect(),
-            _ => Err(()),
-        }
-    }
-}
-
 impl RpcResponse {
     pub fn try_into_single<T>(self) -> Option<T>
     where
@@ -215,6 +238,12 @@ impl RpcRequestImpl {
                 "id": idx,
                 "jsonrpc": "2.0",
             }),
+            RpcRequestImpl::GetChainId => serde_json::json!({
+                "method": "eth_chainId",
+                "params": [],
+                "id": idx,
+                "jsonrpc": "2.0",
+            }),
             RpcRequestImpl::GetBlockByNumber(block_number) => serde_json::json!({
                 "method": "eth_getBlockByNumber",
                 "params": [
@@ -253,28 +282,119 @@ impl RpcRequestImpl {
 }
 
 impl RpcRequest {
+    pub(crate) fn resp_from_bytes(&self, body: Bytes) -> Result<RpcResponse> {
+        if self.is_proxy() {
+            self.resp_from_raw(body)
+        } else {
+            let json = String::from_utf8(body.into()).context("response is not utf8")?;
+            self.resp_from_json(json)
+        }
+    }
+
+    fn is_proxy(&self) -> bool {
+        match self {
+            Self::Single(req) => matches!(req, RpcRequestImpl::Proxy { .. }),
+            Self::Batch(reqs) => reqs
+                .iter()
+                .all(|req| matches!(req, RpcRequestImpl::Proxy { .. })),
+        }
+    }
+
+    /// Splits the response into raw objects so proxied responses can be written to the client
+    /// without parsing and serializing them again.
+    fn resp_from_raw(&self, body: Bytes) -> Result<RpcResponse> {
+        match self {
+            Self::Batch(reqs) => {
+                let items = if raw::is_array(&body) {
+                    raw::split_batch(body).context("split batch response")?
+                } else {
+                    // some providers answer a whole batch with a single error object (e.g. when
+                    // rate limited)
+                    let obj = raw::parse_object(body).context("parse response object")?;
+
+                    let mut json = serde_json::from_slice::<JsonObject>(obj.body())
+                        .context("parse response object")?;
+                    if let Some(err) = parse_error_object(&mut json)? {
+                        return Err(error_response(err));
+                    }
+
+                    vec![obj]
+                };
+
+                Ok(RpcResponse::Batch(
+                    order_by_id(reqs.len(), items, RawResponse::id)
+                        .into_iter()
+                        .map(|item| match item {
+                            Some(item) => RpcResponseImpl::Proxy(item),
+                            None => RpcResponseImpl::Missing,
+                        })
+                        .collect(),
+                ))
+            }
+            Self::Single(_) => Ok(RpcResponse::Single(RpcResponseImpl::Proxy(
+                raw::parse_object(body).context("parse response object")?,
+            ))),
+        }
+    }
+
     pub(crate) fn resp_from_json(&self, json: String) -> Result<RpcResponse> {
         let mut json = json.into_bytes();
         let json = simd_json::serde::from_slice(&mut json).context("parse response json")?;
 
         match (self, json) {
             (Self::Batch(reqs), serde_json::Value::Array(arr)) => {
-                let mut vals = Vec::new();
-
-                for (idx, (val, req)) in arr.into_iter().zip(reqs.iter()).enumerate() {
-                    match val {
-                        serde_json::Value::Object(obj) => {
-                            vals.push(req.resp_from_json(idx, obj)?);
-                        }
-                        _ => return Err(anyhow!("non object item in array response")),
+                let objs = arr
+                    .into_iter()
+                    .map(|val| match val {
+                        serde_json::Value::Object(obj) => Ok(obj),
+                        _ => Err(anyhow!("non object item in array response")),
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+
+                let mut vals = Vec::with_capacity(reqs.len());
+
+                let objs = order_by_id(reqs.len(), objs, |obj| {
+                    obj.get("id").and_then(serde_json::Value::as_u64)
+                });
+                for (idx, (obj, req)) in objs.into_iter().zip(reqs.iter()).enumerate() {
+                    match obj {
+                        Some(obj) => vals.push(
+                            req.resp_from_json(idx, obj)
+                                .unwrap_or_else(RpcResponseImpl::Failed),
+                        ),
+                        None => vals.push(RpcResponseImpl::Missing),
                     }
                 }
 
+                // nothing to keep if no item succeeded, the error lets the client retry the batch
+                if vals
+                    .iter()
+                    .all(|val| matches!(val, RpcResponseImpl::Failed(_) | RpcResponseImpl::Missing))
+                {
+                    let failed = vals.into_iter().find_map(|val| match val {
+                        RpcResponseImpl::Failed(err) => Some(err),
+                        _ => None,
+                    });
+                    return match failed {
+                        Some(err) => Err(err),
+                        None => Ok(RpcResponse::Batch(
+                            reqs.iter().map(|_| RpcResponseImpl::Missing).collect(),
+                        )),
+                    };
+                }
+
                 Ok(RpcResponse::Batch(vals))
             }
             (Self::Single(req), serde_json::Value::Object(obj)) => {
                 Ok(RpcResponse::Single(req.resp_from_json(0, obj)?))
             }
+            // some providers answer a whole batch with a single error object (e.g. when rate limited)
+            (Self::Batch(_), serde_json::Value::Object(mut obj)) => {
+                let err =
+                    parse_error_object(&mut obj)?.context("non error object as batch response")?;
+
+                Err(error_response(err))
+            }
             _ => Err(anyhow!("invalid rpc response")),
         }
     }
@@ -292,22 +412,30 @@ impl RpcRequestImpl {
             return Err(anyhow!("invalid jsonrpc field in response"));
         }
 
-        if json
-            .remove("id")
-            .context("get id field")?
-            .as_u64()
-            .context("id field is u64")?
-            != u64::try_from(idx).unwrap()
+        let id = json.remove("id").context("get id field")?;
+        let err = parse_error_object(&mut json)?;
+
+        // error responses can have a null id if the upstream couldn't read the request
+        if !(id.is_null() && err.is_some())
+            && id.as_u64().context("id field is u64")? != u64::try_from(idx).unwrap()
         {
             return Err(anyhow!("invalid id field in response"));
         }
 
+        if let Some(err) = err {
+            return Err(error_response(err));
+        }
+
         let res = json.remove("result").context("get result field")?;
 
         match self {
             Self::GetBlockNumber => Ok(RpcResponseImpl::GetBlockNumber(
                 serde_json::from_value(res).context("deserialize")?,
             )),
+            // chain id is a quantity like the block number
+            Self::GetChainId => serde_json::from_value::<BlockNumber>(res)
+                .map(|chain_id| RpcResponseImpl::GetChainId(chain_id.into()))
+                .context("deserialize"),
             Self::GetBlockByNumber(_) => serde_json::from_value(res)
                 .map(RpcResponseImpl::GetBlockByNumber)
                 .context("deserialize"),
@@ -320,11 +448,62 @@ impl RpcRequestImpl {
             Self::TraceBlock(_) => serde_json::from_value(res)
                 .context("deserialize")
                 .map(RpcResponseImpl::TraceBlock),
-            Self::Proxy { .. } => Ok(RpcResponseImpl::Proxy(res)),
+            Self::Proxy { .. } => Err(anyhow!("proxied responses are not parsed")),
         }
     }
 }
 
+/// Puts the items of a batch response in the order of the requests, matching them by id.
+///
+/// The json-rpc spec allows the items to be in any order. An item without a numeric id (e.g. an
+/// error response with a null id) only takes a slot if it is the only such item and exactly one
+/// slot is left, otherwise it can't be told which request it belongs to. Slots that don't get an
+/// item are left empty.
+fn order_by_id<T>(
+    num_reqs: usize,
+    items: Vec<T>,
+    id: impl Fn(&T) -> Option<u64>,
+) -> Vec<Option<T>> {
+    let mut slots = (0..num_reqs).map(|_| None).collect::<Vec<Option<T>>>();
+    let mut without_id = Vec::new();
+
+    for item in items {
+        match id(&item) {
+            Some(id) => match usize::try_from(id).ok().and_then(|idx| slots.get_mut(idx)) {
+                Some(slot @ None) => *slot = Some(item),
+                _ => log::warn!("unexpected or duplicate id {} in batch response", id),
+            },
+            None => without_id.push(item),
+        }
+    }
+
+    let mut free = slots.iter_mut().filter(|slot| slot.is_none());
+    match (free.next(), free.next()) {
+        (Some(slot), None) if without_id.len() == 1 => *slot = without_id.pop(),
+        _ if !without_id.is_empty() => log::warn!(
+            "dropping {} batch response items without an id",
+            without_id.len()
+        ),
+        _ => (),
+    }
+
+    slots
+}
+
+/// Wraps the error object so it can be recovered with `downcast` and passed on to the client
+fn error_response(err: RpcErrorObject) -> anyhow::Error {
+    anyhow::Error::new(err)
+}
+
+fn parse_error_object(json: &mut JsonObject) -> Result<Option<RpcErrorObject>> {
+    match json.remove("error") {
+        Some(serde_json::Value::Null) | None => Ok(None),
+        Some(err) => serde_json::from_value(err)
+            .context("deserialize error object")
+            .map(Some),
+    }
+}
+
 type JsonObject = serde_json::Map<String, serde_json::Value>;
 
 #[cfg(test)]
@@ -347,6 +526,19 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    fn test_get_chain_id() {
+        let req = RpcRequest::Single(RpcRequestImpl::GetChainId);
+
+        let chain_id: u64 = req
+            .resp_from_json(read_json_file("eth_chainId.json"))
+            .unwrap()
+            .try_into_single()
+            .unwrap();
+
+        assert_eq!(chain_id, 42161);
+    }
+
     #[test]
     fn test_get_block_by_number() {
         let req = RpcRequest::Batch(vec![
@@ -379,4 +571,179 @@ mod tests {
             .try_into()
             .unwrap();
     }
+
+    #[test]
+    fn test_proxy_error_passthrough() {
+        let proxy = || RpcRequestImpl::Proxy {
+            params: serde_json::json!([]),
+            method: "eth_call".to_owned(),
+        };
+        let req = RpcRequest::Batch(vec![proxy(), proxy()]);
+
+        let resps = match req
+            .resp_from_bytes(read_json_file("eth_call_error_batch.json").into())
+            .unwrap()
+        {
+            RpcResponse::Batch(resps) => resps,
+            RpcResponse::Single(_) => panic!("expected batch response"),
+        };
+
+        let upstream: serde_json::Value =
+            serde_json::from_str(&read_json_file("eth_call_error_batch.json")).unwrap();
+
+        for (idx, resp) in resps.iter().enumerate() {
+            let raw = match resp {
+                RpcResponseImpl::Proxy(raw) => raw,
+                _ => panic!("expected proxy response"),
+            };
+
+            let mut builder = crate::bytes_builder::BytesBuilder::new();
+            raw.write_with_id(&mut builder, 42);
+            let mut written: serde_json::Value = serde_json::from_slice(&builder.build()).unwrap();
+
+            assert_eq!(written["id"], 42);
+            written["id"] = idx.into();
+            assert_eq!(written, upstream[idx]);
+        }
+    }
+
+    #[test]
+    fn test_proxy_batch_answered_with_error_object() {
+        let req = RpcRequest::Batch(vec![
+            RpcRequestImpl::Proxy {
+                params: serde_json::json!([]),
+                method: "eth_call".to_owned(),
+            };
+            3
+        ]);
+
+        // the error object fails the whole batch and is kept so it can be passed on to the client
+        let err = match req.resp_from_bytes(Bytes::from_static(
+            br#"{"jsonrpc":"2.0","id":null,"error":{"code":-32005,"message":"rate limited"}}"#,
+        )) {
+            Err(err) => err,
+            Ok(_) => panic!("expected error"),
+        };
+        assert_eq!(
+            err.downcast::<RpcErrorObject>().unwrap(),
+            RpcErrorObject {
+                code: -32005,
+                message: "rate limited".to_owned(),
+                data: None,
+            }
+        );
+
+        // a single non error object only answers the request with its id
+        let resps = match req
+            .resp_from_bytes(Bytes::from_static(
+                br#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#,
+            ))
+            .unwrap()
+        {
+            RpcResponse::Batch(resps) => resps,
+            _ => panic!("expected batch response"),
+        };
+        assert_eq!(resps.len(), 3);
+        assert!(matches!(resps[0], RpcResponseImpl::Missing));
+        assert!(matches!(resps[1], RpcResponseImpl::Proxy(_)));
+        assert!(matches!(resps[2], RpcResponseImpl::Missing));
+    }
+
+    #[test]
+    fn test_reordered_batch_response() {
+        let req = RpcRequest::Batch(vec![
+            RpcRequestImpl::GetBlockNumber,
+            RpcRequestImpl::GetBlockNumber,
+            RpcRequestImpl::GetBlockNumber,
+        ]);
+
+        let resps: Vec<BlockNumber> = req
+            .resp_from_json(
+                r#"[{"jsonrpc":"2.0","id":2,"result":"0x3"},{"jsonrpc":"2.0","id":0,"result":"0x1"},{"jsonrpc":"2.0","id":1,"result":"0x2"}]"#.to_owned(),
+            )
+            .unwrap()
+            .try_into()
+            .unwrap();
+
+        assert_eq!(resps, vec![1.into(), 2.into(), 3.into()]);
+    }
+
+    #[test]
+    fn test_batch_response_with_failed_items() {
+        let req = RpcRequest::Batch(vec![
+            RpcRequestImpl::GetBlockNumber,
+            RpcRequestImpl::GetBlockNumber,
+            RpcRequestImpl::GetBlockNumber,
+        ]);
+
+        let resps = match req
+            .resp_from_json(
+                r#"[{"jsonrpc":"2.0","id":0,"result":"0x1"},{"jsonrpc":"2.0","id":null,"error":{"code":-32000,"message":"failed"}}]"#.to_owned(),
+            )
+            .unwrap()
+        {
+            RpcResponse::Batch(resps) => resps,
+            RpcResponse::Single(_) => panic!("expected batch response"),
+        };
+
+        // the error can't be matched to one of the two requests without a response
+        assert!(matches!(resps[0], RpcResponseImpl::GetBlockNumber(_)));
+        assert!(matches!(resps[1], RpcResponseImpl::Missing));
+        assert!(matches!(resps[2], RpcResponseImpl::Missing));
+
+        let resps = match req
+            .resp_from_json(
+                r#"[{"jsonrpc":"2.0","id":2,"result":"0x3"},{"jsonrpc":"2.0","id":1,"result":"bad"},{"jsonrpc":"2.0","id":null,"error":{"code":-32000,"message":"failed"}}]"#.to_owned(),
+            )
+            .unwrap()
+        {
+            RpcResponse::Batch(resps) => resps,
+            RpcResponse::Single(_) => panic!("expected batch response"),
+        };
+
+        assert!(matches!(resps[0], RpcResponseImpl::Failed(_)));
+        assert!(matches!(resps[1], RpcResponseImpl::Failed(_)));
+        assert!(matches!(resps[2], RpcResponseImpl::GetBlockNumber(_)));
+
+        // a batch where no item succeeded is an error
+        assert!(req
+            .resp_from_json(
+                r#"[{"jsonrpc":"2.0","id":0,"error":{"code":-32000,"message":"failed"}}]"#
+                    .to_owned(),
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_proxy_batch_with_missing_items() {
+        let req = RpcRequest::Batch(vec![
+            RpcRequestImpl::Proxy {
+                params: serde_json::json!([]),
+                method: "eth_call".to_owned(),
+            };
+            4
+        ]);
+
+        let resps = match req
+            .resp_from_bytes(Bytes::from_static(
+                br#"[{"jsonrpc":"2.0","id":3,"result":"0x3"},{"jsonrpc":"2.0","id":7,"result":"0x7"},{"jsonrpc":"2.0","id":null,"error":{"code":-32600,"message":"invalid request"}},{"jsonrpc":"2.0","id":0,"result":"0x0"}]"#,
+            ))
+            .unwrap()
+        {
+            RpcResponse::Batch(resps) => resps,
+            RpcResponse::Single(_) => panic!("expected batch response"),
+        };
+
+        let ids = resps
+            .iter()
+            .map(|resp| match resp {
+                RpcResponseImpl::Proxy(raw) => Some(raw.id()),
+                RpcResponseImpl::Missing => None,
+                _ => panic!("unexpected response type"),
+            })
+            .collect::<Vec<_>>();
+
+        // two slots are free so the item without an id is dropped, so is the unknown id
+        assert_eq!(ids, vec![Some(Some(0)), None, None, Some(Some(3))]);
+    }
 }
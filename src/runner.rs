@@ -1,30 +1,57 @@
 use std::sync::Arc;
 
-use crate::{args::Args, config::Config, eth_rpc::RpcHandler, http_server::HttpServer};
+use crate::{
+    args::{Args, Command},
+    config::Config,
+    diff,
+    eth_rpc::RpcHandler,
+    http_server::HttpServer,
+    replay, telemetry,
+};
 use anyhow::Context;
 
 pub struct Runner;
 
 impl Runner {
     pub async fn run(args: Args) -> Result<(), anyhow::Error> {
+        match args.command {
+            Some(Command::Replay(replay_args)) => {
+                return replay::run(replay_args).await.context("replay")
+            }
+            Some(Command::Diff(diff_args)) => return diff::run(diff_args).await.context("diff"),
+            None => (),
+        }
+
         let cfg = tokio::fs::read_to_string(&args.config_path)
             .await
             .context("read config file")?;
 
         let cfg: Config = toml::de::from_str(&cfg).context("parse config")?;
 
+        let tracing_enabled = cfg.tracing.is_some();
+        if let Some(tracing_cfg) = cfg.tracing {
+            telemetry::init(tracing_cfg).context("init tracing")?;
+        }
+
         let skar_client =
             skar_client::Client::new(cfg.hypersync).context("couldn't create skar client")?;
 
-        let rpc_handler =
-            RpcHandler::new(skar_client, cfg.eth_rpc).context("create rpc handler")?;
+        let rpc_handler = RpcHandler::new(skar_client, cfg.eth_rpc, cfg.routing)
+            .await
+            .context("create rpc handler")?;
 
         let rpc_handler = Arc::new(rpc_handler);
 
-        HttpServer::run(rpc_handler, cfg.http_server)
+        let res = HttpServer::run(rpc_handler, cfg.http_server)
             .await
-            .context("create http server")?;
+            .context("create http server");
+
+        if tracing_enabled {
+            tokio::task::spawn_blocking(telemetry::shutdown)
+                .await
+                .context("shutdown tracing")?;
+        }
 
-        Ok(())
+        res
     }
 }
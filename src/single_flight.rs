@@ -0,0 +1,144 @@
+//! Lets concurrent callers that make the same call share a single execution of it
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::Mutex;
+
+use futures::channel::oneshot;
+use futures::future::{FutureExt, Shared};
+
+use crate::metrics;
+
+pub struct SingleFlight<K, V> {
+    /// Label of the coalesced calls metric
+    name: &'static str,
+    calls: Mutex<HashMap<K, Shared<oneshot::Receiver<V>>>>,
+}
+
+pub enum Flight<'a, K: Eq + Hash, V> {
+    /// No call for the key is in flight, the caller has to make it and complete the leader
+    Leader(Leader<'a, K, V>),
+    /// Another caller is making the call
+    Follower(Follower<V>),
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> SingleFlight<K, V> {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            calls: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Joins the call that is in flight for the key, or makes the caller the leader of a new one
+    pub fn join(&self, key: K) -> Flight<'_, K, V> {
+        let mut calls = self.calls.lock().unwrap();
+
+        if let Some(call) = calls.get(&key) {
+            metrics::COALESCED_CALLS
+                .with_label_values(&[self.name])
+                .inc();
+            return Flight::Follower(Follower(call.clone()));
+        }
+
+        let (tx, rx) = oneshot::channel();
+        calls.insert(key.clone(), rx.shared());
+
+        Flight::Leader(Leader {
+            flight: self,
+            key,
+            tx: Some(tx),
+        })
+    }
+
+    /// Runs the future, or waits for the result of the call that is in flight for the key instead.
+    ///
+    /// The future is run anyway if the leader of the call is dropped before it completes.
+    pub async fn run(&self, key: K, fut: impl Future<Output = V>) -> V {
+        match self.join(key) {
+            Flight::Leader(leader) => {
+                let value = fut.await;
+                leader.complete(value.clone());
+                value
+            }
+            Flight::Follower(follower) => match follower.wait().await {
+                Some(value) => value,
+                None => fut.await,
+            },
+        }
+    }
+}
+
+pub struct Leader<'a, K: Eq + Hash, V> {
+    flight: &'a SingleFlight<K, V>,
+    key: K,
+    tx: Option<oneshot::Sender<V>>,
+}
+
+impl<K: Eq + Hash, V> Leader<'_, K, V> {
+    /// Hands the result to the followers, callers that join after this start a new call
+    pub fn complete(mut self, value: V) {
+        self.flight.calls.lock().unwrap().remove(&self.key);
+        if let Some(tx) = self.tx.take() {
+            let _ = tx.send(value);
+        }
+    }
+}
+
+impl<K: Eq + Hash, V> Drop for Leader<'_, K, V> {
+    fn drop(&mut self) {
+        // the followers see the call as cancelled when the sender is dropped
+        if self.tx.is_some() {
+            self.flight.calls.lock().unwrap().remove(&self.key);
+        }
+    }
+}
+
+pub struct Follower<V>(Shared<oneshot::Receiver<V>>);
+
+impl<V: Clone> Follower<V> {
+    /// Returns None if the leader was dropped without completing the call
+    pub async fn wait(self) -> Option<V> {
+        self.0.await.ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_single_flight() {
+        let flight = SingleFlight::<u64, u64>::new("test");
+        let calls = &AtomicUsize::new(0);
+        let call = |value| async move {
+            calls.fetch_add(1, Ordering::SeqCst);
+            tokio::task::yield_now().await;
+            value
+        };
+
+        let (a, b, c) = tokio::join!(
+            flight.run(1, call(10)),
+            flight.run(1, call(11)),
+            flight.run(2, call(20))
+        );
+        assert_eq!((a, b, c), (10, 10, 20));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // the follower of a dropped leader is told to make the call itself
+        let leader = match flight.join(1) {
+            Flight::Leader(leader) => leader,
+            Flight::Follower(_) => panic!("no call should be in flight"),
+        };
+        let follower = match flight.join(1) {
+            Flight::Follower(follower) => follower,
+            Flight::Leader(_) => panic!("a call should be in flight"),
+        };
+        drop(leader);
+        assert_eq!(follower.wait().await, None);
+        assert!(matches!(flight.join(1), Flight::Leader(_)));
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use axum::http::HeaderMap;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::{global, Context as OtelContext, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{self, Sampler, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+
+use crate::config::TracingConfig;
+
+/// Installs a global tracing subscriber that exports spans to the configured OTLP collector.
+///
+/// Logs still go through env_logger, only spans are exported.
+pub fn init(cfg: TracingConfig) -> Result<()> {
+    let provider = build_provider(&cfg).context("build tracer provider")?;
+    let tracer = provider.tracer(cfg.service_name);
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    global::set_tracer_provider(provider);
+
+    let subscriber =
+        tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+    tracing::subscriber::set_global_default(subscriber).context("set tracing subscriber")?;
+
+    Ok(())
+}
+
+/// Exports the spans that are still buffered. Blocks until the export is done.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+fn build_provider(cfg: &TracingConfig) -> Result<TracerProvider> {
+    let exporter = opentelemetry_otlp::new_exporter()
+        .http()
+        .with_endpoint(&cfg.otlp_endpoint)
+        .build_span_exporter()
+        .context("build otlp exporter")?;
+
+    let config = trace::config()
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            cfg.sample_ratio,
+        ))))
+        .with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            cfg.service_name.clone(),
+        )]));
+
+    Ok(TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_config(config)
+        .build())
+}
+
+/// Trace context sent by the client in `traceparent` and `tracestate` headers, so the spans of a
+/// request become part of the client's trace
+pub fn parent_context(headers: &HeaderMap) -> OtelContext {
+    let headers = headers
+        .iter()
+        .filter_map(|(name, value)| {
+            Some((name.as_str().to_owned(), value.to_str().ok()?.to_owned()))
+        })
+        .collect::<HashMap<_, _>>();
+
+    global::get_text_map_propagator(|propagator| propagator.extract(&headers))
+}
+
+/// W3C trace context headers (`traceparent`, `tracestate`) for a request made inside the span.
+///
+/// Empty if tracing is disabled or the span isn't sampled.
+pub fn trace_headers(span: &tracing::Span) -> HashMap<String, String> {
+    let cx = span.context();
+    let mut headers = HashMap::new();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&cx, &mut headers));
+    headers
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_export_and_propagate() {
+        // stands in for an otlp collector, answers the first export request
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let collector = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 4096];
+            let n = stream.read(&mut buf).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&buf[..n]).into_owned()
+        });
+
+        let cfg = TracingConfig {
+            otlp_endpoint: format!("http://{}", addr),
+            service_name: "test".to_owned(),
+            sample_ratio: 1.0,
+        };
+        let provider = build_provider(&cfg).unwrap();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let headers = tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("upstream_request");
+            trace_headers(&span)
+        });
+
+        let traceparent = &headers["traceparent"];
+        let parts = traceparent.split('-').collect::<Vec<_>>();
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts[0], "00");
+        assert_eq!(parts[1].len(), 32);
+        assert_eq!(parts[2].len(), 16);
+        assert_eq!(parts[3], "01");
+
+        tokio::task::spawn_blocking(move || provider.force_flush())
+            .await
+            .unwrap();
+
+        let req = tokio::time::timeout(Duration::from_secs(10), collector)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(req.starts_with("POST /v1/traces "));
+    }
+}
@@ -0,0 +1,86 @@
+use std::ops::AddAssign;
+use std::time::Instant;
+
+/// Where the time of a request was spent. Times are in microseconds.
+///
+/// Times of queries that run concurrently are added up, so they can be larger than the total time
+/// of the request.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QueryMetrics {
+    /// Deserializing and validating the http request body
+    pub parse_time: u64,
+    /// Parsing params and planning the HyperSync queries
+    pub query_prepare_time: u64,
+    /// Waiting for HyperSync to respond
+    pub skar_wait_time: u64,
+    /// Decoding arrow data returned by HyperSync
+    pub arrow_decode_time: u64,
+    /// Building the responses and serializing them
+    pub response_encode_time: u64,
+    /// Waiting for upstream rpc endpoints
+    pub proxy_time: u64,
+    /// Number of rows returned by HyperSync
+    pub rows_fetched: u64,
+}
+
+impl AddAssign for QueryMetrics {
+    fn add_assign(&mut self, other: Self) {
+        self.parse_time += other.parse_time;
+        self.query_prepare_time += other.query_prepare_time;
+        self.skar_wait_time += other.skar_wait_time;
+        self.arrow_decode_time += other.arrow_decode_time;
+        self.response_encode_time += other.response_encode_time;
+        self.proxy_time += other.proxy_time;
+        self.rows_fetched += other.rows_fetched;
+    }
+}
+
+impl QueryMetrics {
+    /// Formats the metrics as the value of a `Server-Timing` header
+    pub fn server_timing(&self, total_time: u64) -> String {
+        let dur = |name: &str, micros: u64| format!("{};dur={:.3}", name, micros as f64 / 1000.0);
+
+        [
+            dur("parse", self.parse_time),
+            dur("plan", self.query_prepare_time),
+            dur("hypersync", self.skar_wait_time),
+            dur("decode", self.arrow_decode_time),
+            dur("encode", self.response_encode_time),
+            dur("upstream", self.proxy_time),
+            format!("rows;desc={}", self.rows_fetched),
+            dur("total", total_time),
+        ]
+        .join(", ")
+    }
+}
+
+/// Microseconds since start
+pub fn elapsed(start: &Instant) -> u64 {
+    start.elapsed().as_micros().try_into().unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_timing() {
+        let mut metrics = QueryMetrics {
+            parse_time: 120,
+            skar_wait_time: 15_000,
+            rows_fetched: 3,
+            ..Default::default()
+        };
+        metrics += QueryMetrics {
+            skar_wait_time: 5_000,
+            rows_fetched: 2,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            metrics.server_timing(25_500),
+            "parse;dur=0.120, plan;dur=0.000, hypersync;dur=20.000, decode;dur=0.000, \
+             encode;dur=0.000, upstream;dur=0.000, rows;desc=5, total;dur=25.500"
+        );
+    }
+}
@@ -0,0 +1,297 @@
+//! Recomputes the commitments of a block header from the data that was returned for the block
+//!
+//! Used to check data served from HyperSync against the block headers of the fallback.
+
+use skar_format::{BlockHeader, Log, Transaction, TransactionReceipt};
+use tiny_keccak::{Hasher, Keccak};
+
+use self::rlp::RlpList;
+
+pub mod rlp;
+
+pub mod trie;
+
+pub const BLOOM_SIZE: usize = 256;
+
+pub fn keccak256(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Keccak::v256();
+    hasher.update(data);
+    let mut out = [0; 32];
+    hasher.finalize(&mut out);
+    out
+}
+
+/// Root of the receipts trie of a block, the receipts have to be in the order of the transactions
+pub fn receipts_root(receipts: &[TransactionReceipt]) -> [u8; 32] {
+    trie::ordered_trie_root(receipts.iter().map(encode_receipt))
+}
+
+/// Root of the transactions trie of a block.
+///
+/// The RPC transaction object doesn't have all fields of the signed transaction (e.g. the type and
+/// the access list), so the encoding is rebuilt from the fields that are there and checked against
+/// the transaction hash. Returns None if that fails for any transaction, e.g. for transactions with
+/// a non-empty access list or blob transactions.
+pub fn transactions_root(transactions: &[Transaction]) -> Option<[u8; 32]> {
+    let mut encoded = Vec::with_capacity(transactions.len());
+    for tx in transactions {
+        let tx_bytes = encode_transaction(tx);
+        if keccak256(&tx_bytes) != tx.hash.as_ref() {
+            return None;
+        }
+        encoded.push(tx_bytes);
+    }
+
+    Some(trie::ordered_trie_root(encoded))
+}
+
+/// Numbers of the blocks whose parent hash isn't the hash of the block before them, along with the
+/// number of that block. Only consecutive blocks are compared, headers have to be sorted by number.
+///
+/// This doesn't show which of the two blocks is wrong, only that they aren't from the same chain.
+/// The block hash itself isn't recomputed from the header fields because HyperSync doesn't return
+/// `mixHash` and the fields that were added after London (`withdrawalsRoot`, `blobGasUsed`,
+/// `excessBlobGas`, `parentBeaconBlockRoot`), so the RLP encoding of the header can't be rebuilt.
+pub fn unlinked_blocks<'a>(headers: impl IntoIterator<Item = &'a BlockHeader>) -> Vec<u64> {
+    let mut unlinked = Vec::new();
+    let mut prev: Option<&BlockHeader> = None;
+
+    for header in headers {
+        let number = u64::from(header.number);
+        if let Some(prev) = prev.filter(|prev| u64::from(prev.number) + 1 == number) {
+            if header.parent_hash != prev.hash {
+                unlinked.extend([number - 1, number]);
+            }
+        }
+        prev = Some(header);
+    }
+
+    unlinked.dedup();
+    unlinked
+}
+
+/// Consensus encoding of a receipt as it is stored in the receipts trie
+pub fn encode_receipt(receipt: &TransactionReceipt) -> Vec<u8> {
+    let mut list = RlpList::new();
+
+    // receipts before byzantium have the intermediate state root instead of the status
+    match (&receipt.status, &receipt.root) {
+        (Some(status), _) => list.u64(status.to_u8().into()),
+        (None, Some(root)) => list.bytes(root.as_ref()),
+        (None, None) => list.bytes(&[]),
+    };
+
+    let mut logs = RlpList::new();
+    for log in receipt.logs.iter() {
+        logs.raw(&encode_log(log));
+    }
+
+    list.uint(receipt.cumulative_gas_used.as_ref())
+        .bytes(receipt.logs_bloom.as_ref())
+        .raw(&logs.finish());
+
+    let mut out = Vec::new();
+    if let Some(kind) = receipt.kind.filter(|kind| **kind != 0) {
+        out.push(*kind);
+    }
+    out.extend_from_slice(&list.finish());
+    out
+}
+
+fn encode_log(log: &Log) -> Vec<u8> {
+    let mut topics = RlpList::new();
+    for topic in log.topics.iter() {
+        topics.bytes(topic.as_ref());
+    }
+
+    RlpList::new()
+        .bytes(log.address.as_ref())
+        .raw(&topics.finish())
+        .bytes(log.data.as_ref())
+        .finish()
+}
+
+/// Signed encoding of a transaction, assuming it has an empty access list
+pub fn encode_transaction(tx: &Transaction) -> Vec<u8> {
+    let uint = |val: &Option<skar_format::Quantity>| -> Vec<u8> {
+        val.as_ref()
+            .map(|v| v.as_ref().to_vec())
+            .unwrap_or_default()
+    };
+    let to = tx.to.as_ref().map(|to| to.as_ref()).unwrap_or_default();
+    let v = uint(&tx.v);
+    let y_parity = v.iter().all(|b| *b <= 1);
+
+    let mut list = RlpList::new();
+    let kind = if tx.max_fee_per_gas.is_some() {
+        list.uint(&uint(&tx.chain_id))
+            .uint(tx.nonce.as_ref())
+            .uint(&uint(&tx.max_priority_fee_per_gas))
+            .uint(&uint(&tx.max_fee_per_gas));
+        Some(2)
+    } else if y_parity && tx.chain_id.is_some() {
+        list.uint(&uint(&tx.chain_id))
+            .uint(tx.nonce.as_ref())
+            .uint(&uint(&tx.gas_price));
+        Some(1)
+    } else {
+        list.uint(tx.nonce.as_ref()).uint(&uint(&tx.gas_price));
+        None
+    };
+
+    list.uint(tx.gas.as_ref())
+        .bytes(to)
+        .uint(tx.value.as_ref())
+        .bytes(tx.input.as_ref());
+    if kind.is_some() {
+        // access list
+        list.raw(&RlpList::new().finish());
+    }
+    list.uint(&v).uint(&uint(&tx.r)).uint(&uint(&tx.s));
+
+    let mut out = Vec::new();
+    out.extend(kind);
+    out.extend_from_slice(&list.finish());
+    out
+}
+
+/// Adds the address and topics of the log to the bloom filter
+pub fn accrue_log(bloom: &mut [u8; BLOOM_SIZE], log: &Log) {
+    accrue(bloom, log.address.as_ref());
+    for topic in log.topics.iter() {
+        accrue(bloom, topic.as_ref());
+    }
+}
+
+/// Bloom filter of the logs, as it is in the receipt that has them
+pub fn logs_bloom<'a>(logs: impl IntoIterator<Item = &'a Log>) -> [u8; BLOOM_SIZE] {
+    let mut bloom = [0; BLOOM_SIZE];
+    for log in logs {
+        accrue_log(&mut bloom, log);
+    }
+    bloom
+}
+
+/// Returns true if the address and topics of the log might be in the bloom filter
+pub fn bloom_contains_log(bloom: &[u8], log: &Log) -> bool {
+    bloom.len() == BLOOM_SIZE
+        && bloom_contains(bloom, log.address.as_ref())
+        && log
+            .topics
+            .iter()
+            .all(|topic| bloom_contains(bloom, topic.as_ref()))
+}
+
+fn accrue(bloom: &mut [u8; BLOOM_SIZE], input: &[u8]) {
+    for (byte, mask) in bloom_bits(input) {
+        bloom[byte] |= mask;
+    }
+}
+
+fn bloom_contains(bloom: &[u8], input: &[u8]) -> bool {
+    bloom_bits(input)
+        .into_iter()
+        .all(|(byte, mask)| bloom[byte] & mask != 0)
+}
+
+/// The three bits an input sets in a bloom filter, as byte index and mask
+fn bloom_bits(input: &[u8]) -> [(usize, u8); 3] {
+    let hash = keccak256(input);
+    [0, 2, 4].map(|i| {
+        let bit = ((usize::from(hash[i]) << 8) | usize::from(hash[i + 1])) & 2047;
+        (BLOOM_SIZE - 1 - bit / 8, 1 << (bit % 8))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use hex_literal::hex;
+    use skar_format::{Address, Hash};
+
+    use super::*;
+
+    #[test]
+    fn test_transactions_root() {
+        // signed transaction from the EIP-155 example
+        let signed = hex!("f86c098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a76400008025a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276a067cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83");
+        let mut tx = Transaction {
+            nonce: hex!("09").into(),
+            gas_price: Some(hex!("04a817c800").into()),
+            gas: hex!("5208").into(),
+            to: Some(Address::from([0x35; 20])),
+            value: hex!("0de0b6b3a7640000").into(),
+            v: Some(hex!("25").into()),
+            r: Some(
+                hex!("28ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276").into(),
+            ),
+            s: Some(
+                hex!("67cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83").into(),
+            ),
+            chain_id: Some(hex!("01").into()),
+            hash: Hash::from(keccak256(&signed)),
+            ..Default::default()
+        };
+
+        assert_eq!(encode_transaction(&tx), signed);
+        assert_eq!(
+            transactions_root(std::slice::from_ref(&tx)),
+            Some(trie::trie_root(vec![(vec![0x80], signed.to_vec())]))
+        );
+
+        tx.value = hex!("01").into();
+        assert_eq!(transactions_root(&[tx]), None);
+    }
+
+    #[test]
+    fn test_unlinked_blocks() {
+        let headers = [(10, 1, 0), (11, 2, 1), (12, 3, 9), (14, 5, 4), (15, 6, 5)]
+            .into_iter()
+            .map(|(number, hash, parent_hash)| BlockHeader {
+                number: number.into(),
+                hash: Hash::from([hash; 32]),
+                parent_hash: Hash::from([parent_hash; 32]),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(unlinked_blocks(&headers), vec![11, 12]);
+        assert!(unlinked_blocks(&headers[3..]).is_empty());
+    }
+
+    #[test]
+    fn test_encode_receipt() {
+        let log = Log {
+            address: Address::from([0x11; 20]),
+            topics: [Hash::from([0x22; 32])].into_iter().collect(),
+            data: hex!("ff").into(),
+            ..Default::default()
+        };
+        let bloom = logs_bloom([&log]);
+        assert!(bloom_contains_log(&bloom, &log));
+        assert!(!bloom_contains_log(&[0; BLOOM_SIZE], &log));
+
+        let receipt = TransactionReceipt {
+            kind: Some(2.into()),
+            status: Some(skar_format::TransactionStatus::Success),
+            cumulative_gas_used: hex!("5208").into(),
+            logs_bloom: bloom.into(),
+            logs: vec![log],
+            ..Default::default()
+        };
+
+        let encoded = encode_receipt(&receipt);
+        assert_eq!(&encoded[..4], &[0x02, 0xf9, 0x01, 0x44]);
+        assert_eq!(&encoded[4..8], &[0x01, 0x82, 0x52, 0x08]);
+        assert_eq!(&encoded[8..11], &[0xb9, 0x01, 0x00]);
+        assert_eq!(&encoded[11..267], &bloom);
+        let encoded_log = [
+            &[0xf8, 0x39, 0x94][..],
+            &[0x11; 20],
+            &[0xe1, 0xa0],
+            &[0x22; 32],
+            &[0x81, 0xff],
+        ]
+        .concat();
+        assert_eq!(&encoded[267..], &[&[0xf8, 0x3b][..], &encoded_log].concat());
+    }
+}
@@ -0,0 +1,171 @@
+/// Builds the RLP encoding of a list item by item
+#[derive(Debug, Default)]
+pub struct RlpList {
+    payload: Vec<u8>,
+}
+
+impl RlpList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a byte string
+    pub fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        encode_bytes(&mut self.payload, bytes);
+        self
+    }
+
+    /// Appends a big endian integer, leading zeros are stripped so zero is the empty string
+    pub fn uint(&mut self, bytes: &[u8]) -> &mut Self {
+        let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+        self.bytes(&bytes[start..])
+    }
+
+    pub fn u64(&mut self, val: u64) -> &mut Self {
+        self.uint(&val.to_be_bytes())
+    }
+
+    /// Appends an item that is already encoded, e.g. a nested list
+    pub fn raw(&mut self, encoded: &[u8]) -> &mut Self {
+        self.payload.extend_from_slice(encoded);
+        self
+    }
+
+    pub fn finish(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.payload.len() + 9);
+        encode_length(&mut out, self.payload.len(), 0xc0);
+        out.extend_from_slice(&self.payload);
+        out
+    }
+}
+
+/// Encodes a single byte string
+pub fn encode_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    match bytes {
+        [b] if *b < 0x80 => out.push(*b),
+        _ => {
+            encode_length(out, bytes.len(), 0x80);
+            out.extend_from_slice(bytes);
+        }
+    }
+}
+
+/// Header of an encoded item
+#[derive(Debug, PartialEq)]
+pub struct Header {
+    pub list: bool,
+    /// Length of the header itself
+    pub header_len: usize,
+    pub payload_len: usize,
+}
+
+impl Header {
+    /// Reads the header of the item at the start of buf, None if the item is truncated
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        let (list, header_len, payload_len) = match *buf.first()? {
+            // a single byte is its own encoding
+            0x00..=0x7f => (false, 0, 1),
+            b @ 0x80..=0xb7 => (false, 1, (b - 0x80) as usize),
+            b @ 0xb8..=0xbf => {
+                let (header_len, payload_len) = long_length(buf, (b - 0xb7) as usize)?;
+                (false, header_len, payload_len)
+            }
+            b @ 0xc0..=0xf7 => (true, 1, (b - 0xc0) as usize),
+            b @ 0xf8..=0xff => {
+                let (header_len, payload_len) = long_length(buf, (b - 0xf7) as usize)?;
+                (true, header_len, payload_len)
+            }
+        };
+
+        if buf.len() < header_len.checked_add(payload_len)? {
+            return None;
+        }
+
+        Some(Self {
+            list,
+            header_len,
+            payload_len,
+        })
+    }
+
+    /// Length of the whole item
+    pub fn item_len(&self) -> usize {
+        self.header_len + self.payload_len
+    }
+}
+
+/// Header length and payload length of an item whose length is stored in `len_of_len` bytes
+fn long_length(buf: &[u8], len_of_len: usize) -> Option<(usize, usize)> {
+    let len_bytes = buf.get(1..1 + len_of_len)?;
+    if len_bytes.len() > 8 {
+        return None;
+    }
+
+    let len = len_bytes
+        .iter()
+        .fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+
+    Some((1 + len_of_len, usize::try_from(len).ok()?))
+}
+
+fn encode_length(out: &mut Vec<u8>, len: usize, offset: u8) {
+    if len < 56 {
+        out.push(offset + len as u8);
+    } else {
+        let len_bytes = (len as u64).to_be_bytes();
+        let start = len_bytes.iter().position(|b| *b != 0).unwrap_or(7);
+        out.push(offset + 55 + (8 - start) as u8);
+        out.extend_from_slice(&len_bytes[start..]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let mut out = Vec::new();
+        encode_bytes(&mut out, b"dog");
+        assert_eq!(out, b"\x83dog");
+
+        let long = [0xaa; 60];
+        let mut out = Vec::new();
+        encode_bytes(&mut out, &long);
+        assert_eq!(&out[..2], &[0xb8, 60]);
+        assert_eq!(&out[2..], &long);
+
+        assert_eq!(
+            RlpList::new().bytes(b"cat").bytes(b"dog").finish(),
+            b"\xc8\x83cat\x83dog"
+        );
+        assert_eq!(
+            RlpList::new().u64(0).u64(15).u64(1024).finish(),
+            [0xc5, 0x80, 0x0f, 0x82, 0x04, 0x00]
+        );
+        assert_eq!(RlpList::new().uint(&[0, 0, 1]).finish(), [0xc1, 0x01]);
+        assert_eq!(RlpList::new().finish(), [0xc0]);
+    }
+
+    #[test]
+    fn test_decode_header() {
+        let list = RlpList::new().bytes(b"cat").bytes(&[0xaa; 60]).finish();
+        let header = Header::decode(&list).unwrap();
+        assert_eq!(
+            header,
+            Header {
+                list: true,
+                header_len: 2,
+                payload_len: 66
+            }
+        );
+        assert_eq!(header.item_len(), list.len());
+
+        let cat = Header::decode(&list[2..]).unwrap();
+        assert_eq!((cat.list, cat.item_len()), (false, 4));
+        assert_eq!(Header::decode(&[0x0f]).unwrap().item_len(), 1);
+
+        // truncated
+        assert_eq!(Header::decode(&list[..10]), None);
+    }
+}
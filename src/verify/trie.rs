@@ -0,0 +1,155 @@
+use super::keccak256;
+use super::rlp::{self, RlpList};
+
+/// Root hash of a Merkle Patricia trie whose keys are the RLP encoded indices of the values, like
+/// the transactions and receipts tries of a block
+pub fn ordered_trie_root(values: impl IntoIterator<Item = Vec<u8>>) -> [u8; 32] {
+    let pairs = values
+        .into_iter()
+        .enumerate()
+        .map(|(idx, value)| {
+            let mut key = Vec::new();
+            rlp::encode_bytes(&mut key, &uint_bytes(idx as u64));
+            (key, value)
+        })
+        .collect();
+
+    trie_root(pairs)
+}
+
+/// Root hash of a Merkle Patricia trie with the given keys and values, keys have to be unique
+pub fn trie_root(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> [u8; 32] {
+    let mut pairs = pairs
+        .into_iter()
+        .map(|(key, value)| (nibbles(&key), value))
+        .collect::<Vec<_>>();
+    pairs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+    if pairs.is_empty() {
+        // hash of the empty string
+        return keccak256(&[0x80]);
+    }
+
+    keccak256(&encode_node(&pairs, 0))
+}
+
+/// Encodes the node that holds the pairs, all keys share the first `depth` nibbles
+fn encode_node(pairs: &[(Vec<u8>, Vec<u8>)], depth: usize) -> Vec<u8> {
+    if let [(key, value)] = pairs {
+        return RlpList::new()
+            .bytes(&hex_prefix(&key[depth..], true))
+            .bytes(value)
+            .finish();
+    }
+
+    // keys are sorted so the first and last key have the shortest common prefix
+    let first = &pairs[0].0[depth..];
+    let last = &pairs[pairs.len() - 1].0[depth..];
+    let common = first.iter().zip(last).take_while(|(a, b)| a == b).count();
+
+    if common > 0 {
+        let child = encode_node(pairs, depth + common);
+        return RlpList::new()
+            .bytes(&hex_prefix(&first[..common], false))
+            .raw(&node_ref(child))
+            .finish();
+    }
+
+    let mut branch = RlpList::new();
+    let mut rest = pairs;
+
+    // a key that ends here is the smallest one so it comes first
+    let value = match rest.first() {
+        Some((key, value)) if key.len() == depth => {
+            rest = &rest[1..];
+            Some(value)
+        }
+        _ => None,
+    };
+
+    for nibble in 0..16u8 {
+        let len = rest
+            .iter()
+            .take_while(|(key, _)| key[depth] == nibble)
+            .count();
+        if len == 0 {
+            branch.bytes(&[]);
+        } else {
+            branch.raw(&node_ref(encode_node(&rest[..len], depth + 1)));
+        }
+        rest = &rest[len..];
+    }
+
+    match value {
+        Some(value) => branch.bytes(value),
+        None => branch.bytes(&[]),
+    };
+
+    branch.finish()
+}
+
+/// Nodes shorter than a hash are embedded in their parent
+fn node_ref(node: Vec<u8>) -> Vec<u8> {
+    if node.len() < 32 {
+        node
+    } else {
+        let mut out = Vec::with_capacity(33);
+        rlp::encode_bytes(&mut out, &keccak256(&node));
+        out
+    }
+}
+
+fn hex_prefix(nibbles: &[u8], leaf: bool) -> Vec<u8> {
+    let flag = if leaf { 2 } else { 0 };
+    let mut out = Vec::with_capacity(nibbles.len() / 2 + 1);
+
+    let rest = if nibbles.len() % 2 == 1 {
+        out.push(((flag + 1) << 4) | nibbles[0]);
+        &nibbles[1..]
+    } else {
+        out.push(flag << 4);
+        nibbles
+    };
+
+    out.extend(rest.chunks(2).map(|pair| (pair[0] << 4) | pair[1]));
+    out
+}
+
+fn nibbles(key: &[u8]) -> Vec<u8> {
+    key.iter().flat_map(|b| [b >> 4, b & 0x0f]).collect()
+}
+
+fn uint_bytes(val: u64) -> Vec<u8> {
+    let bytes = val.to_be_bytes();
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+    bytes[start..].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use hex_literal::hex;
+
+    use super::*;
+
+    #[test]
+    fn test_trie_root() {
+        assert_eq!(
+            trie_root(Vec::new()),
+            hex!("56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421")
+        );
+
+        let pairs = [
+            ("do", "verb"),
+            ("horse", "stallion"),
+            ("doge", "coin"),
+            ("dog", "puppy"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.as_bytes().to_vec(), value.as_bytes().to_vec()))
+        .collect();
+        assert_eq!(
+            trie_root(pairs),
+            hex!("5991bb8c6514148a29db676a14ac506cd2cd5775ace63c30a4fe457715e9ac84")
+        );
+    }
+}